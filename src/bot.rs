@@ -0,0 +1,280 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{SocketAddr, TcpStream},
+    thread::JoinHandle,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use crate::{
+    comm::{self, CommError, CtsMessage, PlayerId, StcMessage, Transport, WitchAction},
+    log::{info, warn},
+};
+
+/// The decisions that a bot can be asked to make by picking one player from a list.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Pick {
+    /// Who to vote out during the day.
+    Vote,
+
+    /// Who the wolves should kill.
+    Kill,
+
+    /// Who a bomber should take down with them.
+    TakeDown,
+
+    /// Who a hunter should shoot as they die.
+    Shoot,
+
+    /// Who the village should investigate.
+    Investigate,
+
+    /// Who a seer should inspect.
+    Inspect,
+
+    /// Who a doctor should protect.
+    Protect,
+
+    /// Who a bodyguard should guard.
+    Guard,
+
+    /// Who cupid should make fall in love. Cupid picks twice, and the second time the first
+    /// lover isn't offered again.
+    Lover,
+}
+
+/// How a bot makes its decisions. Bots handle everything else themselves, so a strategy only
+/// has to decide who to pick and what to do with a witch's potions.
+pub trait Strategy: Send {
+    /// Picks one of the given players for the given decision. `opts` is never empty.
+    fn pick(&mut self, pick: Pick, opts: &[PlayerId]) -> PlayerId;
+
+    /// Decides what to do with a witch's potions, given the player who is about to die (if
+    /// anyone), which potions can be used, and who could be poisoned.
+    fn brew(
+        &mut self,
+        dying: Option<PlayerId>,
+        can_heal: bool,
+        can_poison: bool,
+        targets: &[PlayerId],
+    ) -> WitchAction;
+}
+
+/// A strategy that picks at random from whatever it's offered.
+pub struct RandomStrategy {
+    rng: StdRng,
+}
+
+impl RandomStrategy {
+    pub fn new() -> RandomStrategy {
+        RandomStrategy {
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl Default for RandomStrategy {
+    fn default() -> RandomStrategy {
+        RandomStrategy::new()
+    }
+}
+
+impl Strategy for RandomStrategy {
+    fn pick(&mut self, _pick: Pick, opts: &[PlayerId]) -> PlayerId {
+        *opts.choose(&mut self.rng).unwrap()
+    }
+
+    fn brew(
+        &mut self,
+        dying: Option<PlayerId>,
+        can_heal: bool,
+        can_poison: bool,
+        targets: &[PlayerId],
+    ) -> WitchAction {
+        // Potions only get used now and then, so that they last a few nights.
+        if can_heal && dying.is_some() && self.rng.gen_bool(0.5) {
+            WitchAction::Heal
+        } else if can_poison && self.rng.gen_bool(0.25) {
+            WitchAction::Poison(*targets.choose(&mut self.rng).unwrap())
+        } else {
+            WitchAction::Pass
+        }
+    }
+}
+
+/// A strategy that makes choices given to it in advance, so that a game plays out the same way
+/// every time. Each kind of decision has its own script, which is worked through in order.
+///
+/// If a script runs out, or the next player in it isn't one of the options, the first option is
+/// picked instead. A scripted witch never uses their potions.
+#[derive(Default)]
+pub struct ScriptedStrategy {
+    scripts: HashMap<Pick, VecDeque<PlayerId>>,
+}
+
+impl ScriptedStrategy {
+    pub fn new() -> ScriptedStrategy {
+        ScriptedStrategy::default()
+    }
+
+    /// Adds the given players to the end of the script for the given decision.
+    pub fn then(mut self, pick: Pick, ids: impl IntoIterator<Item = PlayerId>) -> ScriptedStrategy {
+        self.scripts.entry(pick).or_default().extend(ids);
+        self
+    }
+}
+
+impl Strategy for ScriptedStrategy {
+    fn pick(&mut self, pick: Pick, opts: &[PlayerId]) -> PlayerId {
+        let scripted = self.scripts.get_mut(&pick).and_then(VecDeque::pop_front);
+
+        match scripted {
+            Some(id) if opts.contains(&id) => id,
+            _ => opts[0],
+        }
+    }
+
+    fn brew(
+        &mut self,
+        _dying: Option<PlayerId>,
+        _can_heal: bool,
+        _can_poison: bool,
+        _targets: &[PlayerId],
+    ) -> WitchAction {
+        WitchAction::Pass
+    }
+}
+
+/// Starts the given number of bots, each on its own thread, which join the room with the given
+/// code on the server at the given address and play along with everyone else. The bots use
+/// `RandomStrategy` to make their decisions.
+pub fn spawn_all(addr: SocketAddr, room: &str, count: usize) -> Vec<JoinHandle<()>> {
+    (1..=count)
+        .map(|n| {
+            let room = room.to_string();
+            let name = format!("Bot {}", n);
+
+            std::thread::spawn(move || {
+                if let Err(err) = run(addr, &room, name.clone(), RandomStrategy::new()) {
+                    warn!("{} lost its connection: {}", name, err);
+                }
+            })
+        })
+        .collect()
+}
+
+/// Connects a bot with the given name to the room with the given code, and plays until the
+/// session is closed.
+pub fn run(
+    addr: SocketAddr,
+    room: &str,
+    name: String,
+    strategy: impl Strategy,
+) -> Result<(), CommError> {
+    let mut stream = TcpStream::connect(addr)?;
+
+    comm::send(&mut stream, &CtsMessage::Hello(comm::PROTOCOL_VERSION))?;
+    comm::send(&mut stream, &CtsMessage::JoinRoom(room.to_string()))?;
+
+    let reply: StcMessage = comm::receive(&mut stream)?;
+
+    if !matches!(reply, StcMessage::RoomJoined(_)) {
+        warn!("{} couldn't get into room {}: {:?}", name, room, reply);
+        return Ok(());
+    }
+
+    play(stream, name, strategy)
+}
+
+/// Joins a bot with the given name to the game at the other end of `stream`, and plays until the
+/// session is closed. The stream should already be in a room, or be connected to a game directly
+/// (as with `server::play_game`).
+pub fn play(
+    mut stream: impl Transport,
+    name: String,
+    mut strategy: impl Strategy,
+) -> Result<(), CommError> {
+    comm::send(&mut stream, &CtsMessage::Connect(name.clone(), None))?;
+
+    // If our name is taken, we keep trying it with a number on the end until we find one that
+    // isn't.
+    let mut attempts = 1;
+
+    loop {
+        let msg: StcMessage = comm::receive(&mut stream)?;
+
+        let reply = match msg {
+            StcMessage::NameRejected(reason) => {
+                attempts += 1;
+                warn!(
+                    "{} can't use its name ({}), so trying another",
+                    name, reason
+                );
+
+                CtsMessage::Connect(format!("{} ({})", name, attempts), None)
+            }
+
+            StcMessage::GameFull(max) => {
+                warn!("{} couldn't join, since the game is full at {}", name, max);
+                return Ok(());
+            }
+
+            StcMessage::IdAssigned(id, ..) => {
+                info!("{} joined as {:?}", name, id);
+                continue;
+            }
+
+            StcMessage::AskReady => CtsMessage::Ready(true),
+            StcMessage::Ping => CtsMessage::Pong,
+
+            // Bots only play one game. If the player who brought them plays again, they bring a
+            // fresh set of bots with them.
+            StcMessage::GameOver => CtsMessage::PlayAgain(false),
+
+            StcMessage::VoteOptions { candidates, .. } => {
+                CtsMessage::Vote(strategy.pick(Pick::Vote, &candidates))
+            }
+            StcMessage::KillOptions(opts) => CtsMessage::Kill(strategy.pick(Pick::Kill, &opts)),
+            StcMessage::BomberPrompt(opts) => {
+                CtsMessage::TakeDown(strategy.pick(Pick::TakeDown, &opts))
+            }
+            StcMessage::HunterShootOptions(opts) => {
+                CtsMessage::HunterShoot(strategy.pick(Pick::Shoot, &opts))
+            }
+            StcMessage::InvestigateOptions(opts) => {
+                CtsMessage::Investigate(strategy.pick(Pick::Investigate, &opts))
+            }
+            StcMessage::InspectOptions(opts) => {
+                CtsMessage::Inspect(strategy.pick(Pick::Inspect, &opts))
+            }
+            StcMessage::ProtectOptions(opts) => {
+                CtsMessage::Protect(strategy.pick(Pick::Protect, &opts))
+            }
+            StcMessage::GuardOptions(opts) => CtsMessage::Guard(strategy.pick(Pick::Guard, &opts)),
+            StcMessage::ChooseLovers(mut opts) => {
+                let first = strategy.pick(Pick::Lover, &opts);
+                opts.retain(|&id| id != first);
+
+                CtsMessage::ChooseLovers(first, strategy.pick(Pick::Lover, &opts))
+            }
+
+            StcMessage::WitchPrompt {
+                dying,
+                can_heal,
+                can_poison,
+                targets,
+            } => CtsMessage::WitchAction(strategy.brew(dying, can_heal, can_poison, &targets)),
+
+            StcMessage::SessionClosed => return Ok(()),
+
+            // Bots don't talk, and if one ends up as the host, it leaves starting the game to
+            // someone else.
+            msg if msg.is_prompt() => CtsMessage::Received,
+
+            // Everything else is just information, which bots don't need.
+            _ => continue,
+        };
+
+        comm::send(&mut stream, &reply)?;
+    }
+}
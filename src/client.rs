@@ -1,30 +1,442 @@
-use std::{collections::HashMap, io::Write, net::SocketAddr};
-
-use crate::comm::{CtsMessage, PlayerId, Role, StcMessage, Winner};
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{IsTerminal, Write},
+    net::{SocketAddr, TcpStream},
+    ops::DerefMut,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bot,
+    comm::{
+        self, Alignment, ClientState, Colour, CommError, CtsMessage, GameSnapshot, Phase, PlayerId,
+        PublicEvent, Role, Secret, Setup, StcMessage, Token, Transport, Winner, WitchAction,
+    },
+    log::{info, trace, warn},
+};
 use parking_lot::Mutex;
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-pub fn start(addr: SocketAddr) {
-    println!("Connecting to {}", addr);
-    Player::new(Session::new(addr)).play();
+/// Settings chosen by the user that change how the game is shown to them.
+pub struct Config {
+    /// The name to join the game with. If this is `None`, the user is asked for a name.
+    pub name: Option<String>,
+
+    /// The colour the user would like their name shown in. If this is `None`, or someone else
+    /// already has the colour, the host picks one for them.
+    pub colour: Option<Colour>,
+
+    /// Whether the end of the game should be typed out slowly for dramatic effect.
+    pub dramatic: bool,
+
+    /// Whether output should be suitable for reading with a screen reader. This turns off colour
+    /// and anything that updates text in place, and spells things out in full.
+    pub screen_reader: bool,
+
+    /// The order to list players in when asking the user to pick one.
+    pub menu_order: MenuOrder,
+
+    /// Whether output should be left uncoloured.
+    pub no_colour: bool,
+
+    /// Whether names and messages meant for the user should be marked with symbols, so that they
+    /// can be told apart without colour.
+    pub symbols: bool,
+
+    /// Whether to watch the game rather than playing in it.
+    pub spectate: bool,
+
+    /// The room to play in.
+    pub room: RoomChoice,
+
+    /// The ID and token of the player whose seat should be taken back, if the user is rejoining a
+    /// game after losing their connection.
+    pub rejoin: Option<(PlayerId, Token)>,
+
+    /// The number of bots to fill the room with once we're in it.
+    pub bots: usize,
+
+    /// Whether each line of output should start with how long it's been since we started.
+    pub timestamps: bool,
+
+    /// A file to add a plain copy of everything shown to the user to, if any.
+    pub transcript: Option<PathBuf>,
+}
+
+/// The room that the user wants to play in.
+#[derive(Clone, PartialEq, Debug)]
+pub enum RoomChoice {
+    /// A new room, which other players can then join.
+    Create,
+
+    /// The room with the given code. An empty code means the only room on the server.
+    Join(String),
+}
+
+/// The ways in which the players in a menu can be ordered.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MenuOrder {
+    /// The order in which the players joined the game.
+    Join,
+
+    /// Alphabetical order by name.
+    Alphabetical,
+}
+
+impl std::str::FromStr for MenuOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MenuOrder, String> {
+        match s.to_lowercase().as_str() {
+            "join" => Ok(MenuOrder::Join),
+            "alphabetical" | "name" => Ok(MenuOrder::Alphabetical),
+            _ => Err(format!("'{}' is not a menu order", s)),
+        }
+    }
+}
+
+pub fn start(addr: SocketAddr, config: Config) {
+    let mut output = Output::new(&config);
+
+    if let Some(path) = &config.transcript {
+        if let Err(err) = output.keep_transcript(path) {
+            output.write_user(format!(
+                "Couldn't open the transcript file {}: {}\n",
+                path.display(),
+                err
+            ));
+
+            std::process::exit(1);
+        }
+    }
+
+    output.writeln(format!("Connecting to {}", addr));
+
+    let mut session = Session::connect(addr).unwrap_or_else(|err| {
+        output.write_user(format!("Couldn't connect to {}: {}\n", addr, err));
+        std::process::exit(1);
+    });
+
+    // Everything after this happens within a room, so we have to be in one first.
+    let reply = session
+        .enter_room(&config.room)
+        .unwrap_or_else(|err| Player::<TcpStream>::lost_host(&output, err));
+
+    match reply {
+        StcMessage::RoomCreated(code) => {
+            output.write_log(format!(
+                "Opened room {}. Other players can join it with `--room {}`.\n",
+                code, code
+            ));
+
+            session.room = Some(code);
+        }
+
+        StcMessage::RoomJoined(code) => {
+            output.write_log(format!("Entered room {}.\n", code));
+            session.room = Some(code);
+        }
+
+        StcMessage::NoSuchRoom(code) => {
+            output.write_user(if code.is_empty() {
+                String::from("There isn't just one room to join, so pick one with `--room`.\n")
+            } else {
+                format!("There's no room with the code {}.\n", code)
+            });
+
+            std::process::exit(1);
+        }
+
+        StcMessage::VersionMismatch { server, client } => {
+            output.write_user(format!(
+                "The host is running a different version of the game (protocol version {}, but \
+                 you have {}), so you can't join. You'll both need the same version to play.\n",
+                server, client
+            ));
+
+            std::process::exit(1);
+        }
+
+        _ => unreachable!("only replies about rooms are returned"),
+    }
+
+    let mut player = Player::new(session, output, &config);
+
+    // Now that we've got a name (if we needed one), the user's input can be read as it comes,
+    // so that commands work even while we're waiting for the host.
+    player.session.read_input();
+
+    // The bots join once we have, so that we're the host rather than one of them.
+    player.bots = (config.bots > 0).then_some((addr, config.bots));
+    player.spawn_bots();
+
+    player.play();
+}
+
+/// Returns the terminal colour used to show a player's colour.
+fn term_colour(colour: Colour) -> Color {
+    match colour {
+        Colour::Blue => Color::Blue,
+        Colour::Cyan => Color::Cyan,
+        Colour::Magenta => Color::Magenta,
+        Colour::Yellow => Color::Yellow,
+        Colour::Green => Color::Green,
+        Colour::Red => Color::Red,
+        Colour::White => Color::White,
+    }
+}
+
+/// Returns the name of the given role and a description of what a player with that role should
+/// do.
+fn describe_role(role: Role) -> (&'static str, &'static str) {
+    match role {
+        Role::Wolf => ("werewolf", "Kill others and avoid detection."),
+        Role::WolfCub => (
+            "wolf cub",
+            "Kill others and avoid detection. If you die, the other werewolves get to kill twice \
+             the next night.",
+        ),
+        Role::Villager => (
+            "villager",
+            "Do villager things, avoid being killed, and capture the werewolves.",
+        ),
+        Role::Bomber => (
+            "bomber",
+            "Help the villagers. If you are voted out, you take someone down with you.",
+        ),
+        Role::Knight => (
+            "knight",
+            "Help the villagers. If a werewolf kills you, they will pay with their life.",
+        ),
+        Role::Saint => (
+            "saint",
+            "Help the villagers. If the village votes you out, they will pay for it.",
+        ),
+        Role::Prince => (
+            "prince",
+            "Help the villagers. The first time the village votes you out, you show them who you \
+             are and they let you go.",
+        ),
+        Role::Seer => (
+            "seer",
+            "Help the villagers. Each night, you can find out whether someone is a werewolf.",
+        ),
+        Role::ApprenticeSeer => (
+            "apprentice seer",
+            "Help the villagers. If the seer dies, you take their place.",
+        ),
+        Role::Doctor => (
+            "doctor",
+            "Help the villagers. Each night, you can protect someone from the werewolves.",
+        ),
+        Role::Hunter => (
+            "hunter",
+            "Help the villagers. However you die, you get to shoot someone as you go.",
+        ),
+        Role::Bodyguard => (
+            "bodyguard",
+            "Help the villagers. Each night, you can guard someone from the werewolves, but never \
+             the same person two nights in a row.",
+        ),
+        Role::Cupid => (
+            "cupid",
+            "Help the villagers. On the first night, you choose two players to fall in love. If \
+             one of them dies, so does the other.",
+        ),
+        Role::Jester => (
+            "jester",
+            "You're on nobody's side. Get the village to vote you out, and you win.",
+        ),
+        Role::Minion => (
+            "minion",
+            "Help the werewolves. You know who they are, but they don't know who you are, and \
+             you can't kill anyone yourself.",
+        ),
+        Role::Witch => (
+            "witch",
+            "Help the villagers. You have one potion that saves the werewolves' victim, and one \
+             that poisons someone.",
+        ),
+    }
+}
+
+/// Returns the IDs of the players sitting to the left and right of the given player, given the
+/// seating order around the table. If the player isn't seated, `None` is returned.
+fn neighbours(seating: &[PlayerId], id: PlayerId) -> Option<(PlayerId, PlayerId)> {
+    let seat = seating.iter().position(|&seated| seated == id)?;
+
+    // The table is a circle, so the players at either end of the list sit next to each other.
+    // Facing into the middle of the table, the next player clockwise is on the left.
+    let left = seating[(seat + 1) % seating.len()];
+    let right = seating[(seat + seating.len() - 1) % seating.len()];
+
+    Some((left, right))
+}
+
+/// Returns the ID of the player whose death the given message announces, if it announces one.
+fn announced_death(msg: &StcMessage) -> Option<PlayerId> {
+    match *msg {
+        StcMessage::Died(id)
+        | StcMessage::Poisoned(id)
+        | StcMessage::KnightRevenge(id)
+        | StcMessage::VotedOut(id)
+        | StcMessage::PlayerLeft(id)
+        | StcMessage::Heartbroken(id)
+        | StcMessage::Shot(_, id)
+        | StcMessage::TakenDown(_, id) => Some(id),
+        _ => None,
+    }
+}
+
+/// Returns the given number spelled out in words, as long as it's small enough to be spelled out
+/// sensibly. Larger numbers are written with digits.
+fn spell_out(n: usize) -> String {
+    const ONES: [&str; 20] = [
+        "zero",
+        "one",
+        "two",
+        "three",
+        "four",
+        "five",
+        "six",
+        "seven",
+        "eight",
+        "nine",
+        "ten",
+        "eleven",
+        "twelve",
+        "thirteen",
+        "fourteen",
+        "fifteen",
+        "sixteen",
+        "seventeen",
+        "eighteen",
+        "nineteen",
+    ];
+
+    const TENS: [&str; 10] = [
+        "", "", "twenty", "thirty", "forty", "fifty", "sixty", "seventy", "eighty", "ninety",
+    ];
+
+    match n {
+        0..=19 => ONES[n].to_string(),
+        20..=99 if n.is_multiple_of(10) => TENS[n / 10].to_string(),
+        20..=99 => format!("{}-{}", TENS[n / 10], ONES[n % 10]),
+        _ => n.to_string(),
+    }
 }
 
 /// A coloured output stream that abstracts semantic highlighting details.
 struct Output {
     stdout: Mutex<StandardStream>,
+
+    /// The file that a plain copy of the output is added to, if any. Styling is only ever applied
+    /// to `stdout`, so nothing needs stripping from what ends up here.
+    transcript: Option<Mutex<File>>,
+
+    /// Whether each line should start with a timestamp.
+    timestamps: bool,
+
+    /// When the output was created, which timestamps count from.
+    started: Instant,
+
+    /// Whether the next thing written starts a new line, and so needs a timestamp (or a symbol).
+    line_start: Mutex<bool>,
+
+    /// Whether names are marked with `@` and messages meant for the user with `*`, as well as
+    /// with colour.
+    symbols: bool,
+
+    /// Whether text for the end of the game should be typed out slowly.
+    dramatic: bool,
+
+    /// Whether output should be kept plain and linear for screen readers.
+    screen_reader: bool,
 }
 
 impl Output {
     /// Creates a new coloured stdout stream.
-    fn new() -> Output {
+    fn new(config: &Config) -> Output {
+        // Colour codes get read out (or just confuse things) with a screen reader, and they end
+        // up as junk in files and pipes. `Auto` still leaves colour out for dumb terminals.
+        let colour = if config.no_colour || config.screen_reader {
+            ColorChoice::Never
+        } else if std::io::stdout().is_terminal() {
+            ColorChoice::Auto
+        } else {
+            ColorChoice::Never
+        };
+
         Output {
-            stdout: Mutex::new(StandardStream::stdout(ColorChoice::Always)),
+            stdout: Mutex::new(StandardStream::stdout(colour)),
+            transcript: None,
+            timestamps: config.timestamps,
+            started: Instant::now(),
+            line_start: Mutex::new(true),
+            symbols: config.symbols,
+            dramatic: config.dramatic,
+            screen_reader: config.screen_reader,
         }
     }
 
-    /// Writes the given string to the stream.
+    /// Starts adding a copy of everything written to the file at the given path. The file is
+    /// added to rather than replaced, so a transcript can cover several sessions.
+    fn keep_transcript(&mut self, path: &Path) -> std::io::Result<()> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        self.transcript = Some(Mutex::new(file));
+
+        Ok(())
+    }
+
+    /// Writes the given string to the stream, and to the transcript if we're keeping one.
+    ///
+    /// The stream is flushed straight away so that text without a trailing newline (such as a
+    /// prompt) shows up immediately rather than whenever the next line is written.
     fn write(&self, name: impl AsRef<str>) {
-        write!(self.stdout.lock(), "{}", name.as_ref()).unwrap();
+        let text = self.stamp(name.as_ref());
+        let mut stdout = self.stdout.lock();
+
+        write!(stdout, "{}", text).unwrap();
+        stdout.flush().unwrap();
+
+        // Losing the transcript isn't worth interrupting the game for.
+        if let Some(transcript) = &self.transcript {
+            if let Err(err) = transcript.lock().write_all(text.as_bytes()) {
+                warn!("Couldn't add to the transcript: {}", err);
+            }
+        }
+    }
+
+    /// Returns the given text with a timestamp at the start of each line, if timestamps are
+    /// turned on.
+    fn stamp(&self, text: &str) -> String {
+        // We keep track of where lines start even without timestamps, since symbols only go at
+        // the start of a line.
+        let mut line_start = self.line_start.lock();
+        let mut stamped = String::with_capacity(text.len());
+
+        for c in text.chars() {
+            if *line_start && self.timestamps {
+                let secs = self.started.elapsed().as_secs();
+                stamped.push_str(&format!("[{:02}:{:02}] ", secs / 60, secs % 60));
+            }
+
+            stamped.push(c);
+            *line_start = c == '\n';
+        }
+
+        stamped
+    }
+
+    /// Writes the given string to the stream, followed by a newline.
+    fn writeln(&self, line: impl AsRef<str>) {
+        self.write(format!("{}\n", line.as_ref()));
     }
 
     /// Sets the stream's foreground colour and whether the text is bold.
@@ -40,16 +452,41 @@ impl Output {
         self.stdout.lock().reset().unwrap();
     }
 
-    /// Writes a player name to the stream.
-    fn write_name(&self, name: impl AsRef<str>) {
-        self.set_fg(Color::Blue, true);
-        self.write(name);
+    /// Writes a player name to the stream in the given colour.
+    fn write_name(&self, name: impl AsRef<str>, colour: Colour) {
+        self.set_fg(term_colour(colour), true);
+        self.write(self.mark_name(name.as_ref()));
+        self.reset();
+    }
+
+    /// Writes a player name to the stream dimmed, for players who are no longer in the game.
+    fn write_dimmed_name(&self, name: impl AsRef<str>) {
+        self.stdout
+            .lock()
+            .set_color(ColorSpec::new().set_dimmed(true))
+            .unwrap();
+        self.write(self.mark_name(name.as_ref()));
         self.reset();
     }
 
-    /// Writes information important to the user to the stream.
+    /// Returns the given name marked as a name, if we're using symbols.
+    fn mark_name(&self, name: &str) -> String {
+        if self.symbols {
+            format!("@{}", name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    /// Writes information important to the user to the stream. If we're using symbols, a line
+    /// that starts with this is marked with `*`.
     fn write_user(&self, msg: impl AsRef<str>) {
         self.set_fg(Color::Green, false);
+
+        if self.symbols && *self.line_start.lock() {
+            self.write("* ");
+        }
+
         self.write(msg);
         self.reset();
     }
@@ -58,47 +495,240 @@ impl Output {
     fn write_log(&self, msg: impl AsRef<str>) {
         self.write(msg);
     }
+
+    /// Writes the announcement of the given night (counting from one) to the stream.
+    fn write_nightfall(&self, night: usize) {
+        if self.screen_reader {
+            self.write_log(format!("Night {} begins.\n", spell_out(night)));
+        } else {
+            self.write_log("Night has fallen.\n");
+        }
+    }
+
+    /// Writes an option from a menu to the stream, given the number used to pick it.
+    fn write_option(&self, number: usize, name: impl AsRef<str>) {
+        // Screen readers do better with one option per line than with a row of bracketed numbers.
+        if self.screen_reader {
+            self.write(format!("\nOption {}: {}.", number, name.as_ref()));
+        } else {
+            self.write(format!("  [{}] {}", number, name.as_ref()));
+        }
+    }
+
+    /// Writes part of the game's finale to the stream. If dramatic output is enabled, the text is
+    /// typed out one character at a time.
+    fn write_finale(&self, msg: impl AsRef<str>) {
+        // Typing text out one character at a time would be read out one character at a time by
+        // a screen reader.
+        if !self.dramatic || self.screen_reader {
+            self.write(msg);
+            return;
+        }
+
+        for c in msg.as_ref().chars() {
+            self.write(c.to_string());
+            std::thread::sleep(Duration::from_millis(40));
+        }
+    }
+}
+
+/// Something that the client has to deal with, from either the host or the user.
+enum Event {
+    /// A message from the host, or the error that stopped us from reading one.
+    Host(Result<StcMessage, CommError>),
+
+    /// A line typed by the user.
+    Line(String),
+
+    /// The user's input has ended, so nothing more will be typed.
+    InputClosed,
 }
 
 /// A connection to a game room.
-struct Session {
-    /// The stream used to talk to room on the server.
-    stream: std::net::TcpStream,
+struct Session<T: Transport> {
+    /// The connection used to talk to room on the server. The thread that reads messages from
+    /// the room answers pings through it as well, so it's shared with that thread.
+    stream: Arc<Mutex<T>>,
+
+    /// Messages from the room and lines from the user, passed on by the threads that read them,
+    /// in the order they arrived.
+    events: mpsc::Receiver<Event>,
+
+    /// Used to pass events to `events`. We keep hold of it so that the thread reading the user's
+    /// input can be started later on.
+    sender: mpsc::Sender<Event>,
 
-    /// The names of the players in the session.
-    players: HashMap<PlayerId, String>,
+    /// The names and colours of the players in the session.
+    players: HashMap<PlayerId, (String, Colour)>,
+
+    /// The code of the room that the session is in, once we're in one.
+    room: Option<String>,
 }
 
-impl Session {
+impl Session<TcpStream> {
     /// Creates a new `Session` by connecting to the given address over TCP.
-    fn new(addr: SocketAddr) -> Session {
+    fn connect(addr: SocketAddr) -> std::io::Result<Session<TcpStream>> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = stream.try_clone()?;
+
+        Ok(Session::new(stream, reader))
+    }
+}
+
+impl<T: Transport + 'static> Session<T> {
+    /// Creates a new `Session` that sends messages to the room over `stream` and reads them from
+    /// `reader`, which should be the other end of the same connection.
+    ///
+    /// Messages are read on a thread of their own, so that pings can be answered even while the
+    /// user is taking their time over a decision.
+    fn new(stream: T, mut reader: T) -> Session<T> {
+        let stream = Arc::new(Mutex::new(stream));
+        let (sender, events) = mpsc::channel();
+
+        let pong_stream = Arc::clone(&stream);
+        let host_sender = sender.clone();
+
+        std::thread::spawn(move || loop {
+            let msg = comm::receive(&mut reader);
+
+            if let Ok(StcMessage::Ping) = msg {
+                trace!(target: "wolf::client::net", "Answering a ping");
+
+                match comm::send(pong_stream.lock().deref_mut(), &CtsMessage::Pong) {
+                    Ok(()) => continue,
+                    Err(err) => {
+                        let _ = host_sender.send(Event::Host(Err(err)));
+                        break;
+                    }
+                }
+            }
+
+            // There's nothing more to read once the connection has failed, and nobody to read
+            // for once the session has been dropped.
+            let failed = msg.is_err();
+
+            if host_sender.send(Event::Host(msg)).is_err() || failed {
+                break;
+            }
+        });
+
         Session {
-            stream: std::net::TcpStream::connect(addr).unwrap(),
+            stream,
+            events,
+            sender,
             players: HashMap::new(),
+            room: None,
         }
     }
+}
 
-    fn send(&mut self, msg: CtsMessage) {
-        bincode::serialize_into(&mut self.stream, &msg).unwrap();
+impl<T: Transport> Session<T> {
+    /// Sends the given message to the host. If it can't be sent, the error is returned so that
+    /// the caller can tell the user what went wrong.
+    fn send(&self, msg: CtsMessage) -> Result<(), CommError> {
+        trace!(target: "wolf::client::net", "Sending {:?}", msg);
+        comm::send(self.stream.lock().deref_mut(), &msg)
+    }
+
+    /// Starts reading lines typed by the user on a thread of their own, so that they come through
+    /// as events alongside messages from the host. Until this is called, nothing else may read
+    /// from stdin while the session waits for the host.
+    fn read_input(&self) {
+        let sender = self.sender.clone();
+
+        std::thread::spawn(move || loop {
+            let mut line = String::new();
+
+            let event = match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => Event::InputClosed,
+                Ok(_) => Event::Line(line),
+            };
+
+            let closed = matches!(event, Event::InputClosed);
+
+            if sender.send(event).is_err() || closed {
+                break;
+            }
+        });
+    }
+
+    /// Waits for the next thing to happen, whether that's a message from the host or a line from
+    /// the user.
+    fn next_event(&self) -> Event {
+        let event = self
+            .events
+            .recv()
+            .expect("the session keeps a sender, so the channel can't close");
+
+        if let Event::Host(Ok(msg)) = &event {
+            trace!(target: "wolf::client::net", "Received {:?}", msg);
+        }
+
+        event
+    }
+
+    /// Waits for the next message from the host. If the message can't be read, the error is
+    /// returned so that the caller can tell the user what went wrong.
+    ///
+    /// This is only used before we start reading the user's input, so there are no lines from
+    /// the user to get in the way.
+    fn receive(&mut self) -> Result<StcMessage, CommError> {
+        loop {
+            if let Event::Host(msg) = self.next_event() {
+                return msg;
+            }
+        }
+    }
+
+    /// Asks the server for the given room, and returns its answer. This has to happen before
+    /// anything else, since everything else happens within a room.
+    fn enter_room(&mut self, choice: &RoomChoice) -> Result<StcMessage, CommError> {
+        // The server only answers this if it speaks a different version, in which case it
+        // answers our request for a room with the mismatch instead.
+        self.send(CtsMessage::Hello(comm::PROTOCOL_VERSION))?;
+
+        self.send(match choice {
+            RoomChoice::Create => CtsMessage::CreateRoom,
+            RoomChoice::Join(code) => CtsMessage::JoinRoom(code.clone()),
+        })?;
+
+        loop {
+            let msg = self.receive()?;
+
+            if ClientState::ChoosingRoom.received(&msg).is_some() {
+                return Ok(msg);
+            }
+
+            warn!("Ignoring {:?} while {:?}", msg, ClientState::ChoosingRoom);
+        }
     }
 
-    fn receive(&mut self) -> StcMessage {
-        bincode::deserialize_from(&mut self.stream).unwrap()
+    /// Returns the name of the player with the given ID. The host only refers to players it's
+    /// told us about, but if it gets that wrong, we show the ID rather than give up on the game.
+    fn player_name(&self, id: PlayerId) -> Cow<'_, str> {
+        match self.players.get(&id) {
+            Some((name, _)) => Cow::Borrowed(name),
+            None => {
+                warn!("Don't know the name of {:?}", id);
+                Cow::Owned(format!("Unknown({})", id))
+            }
+        }
     }
 
-    fn player_name(&self, id: PlayerId) -> &str {
-        self.players.get(&id).expect("No player found for given ID")
+    /// Returns the colour of the player with the given ID, or white if we don't know who they
+    /// are.
+    fn player_colour(&self, id: PlayerId) -> Colour {
+        self.players
+            .get(&id)
+            .map_or(Colour::White, |&(_, colour)| colour)
     }
 }
 
 /// The user's player. Manages communication with the host.
-struct Player {
+struct Player<T: Transport> {
     /// The ID of this player.
     id: PlayerId,
 
-    /// The name chosen by the user.
-    name: String,
-
     /// The coloured output stream.
     output: Output,
 
@@ -108,77 +738,338 @@ struct Player {
     /// Whether the player has died.
     dead: bool,
 
+    /// The IDs of every player we've seen die, including us.
+    fallen: HashSet<PlayerId>,
+
+    /// What we've found out about other players as a seer, as the night we found out, the
+    /// player and their role.
+    findings: Vec<(usize, PlayerId, Role)>,
+
+    /// The number of nights that have begun so far.
+    nights: usize,
+
+    /// The order to show players in menus.
+    menu_order: MenuOrder,
+
+    /// The stage of the game that the player's connection is at.
+    state: ClientState,
+
     /// The session that the player is currently in.
-    session: Session,
+    session: Session<T>,
+
+    /// Messages from the host that arrived while the user was answering a prompt. We deal with
+    /// them once the user has answered, so that everything happens in the order it was sent.
+    held: RefCell<VecDeque<StcMessage>>,
+
+    /// Lines that the user typed before they were asked anything. These answer the next prompts
+    /// in order, just as they would if the prompt had been waiting for them.
+    typed: RefCell<VecDeque<String>>,
+
+    /// Whether the user's input has ended, in which case every prompt gets an empty answer.
+    input_closed: Cell<bool>,
+
+    /// Whether the host is counting down to the start of the game, which the user can call off.
+    counting_down: Cell<bool>,
+
+    /// The address of the server and the number of bots to bring into each game, if we're
+    /// bringing any.
+    bots: Option<(SocketAddr, usize)>,
 }
 
-impl Player {
-    /// Creates a new player connected to the given session.
-    fn new(mut session: Session) -> Player {
-        // Ask the user for a name to connect with.
-        let name = Self::input_name();
+impl<T: Transport> Player<T> {
+    /// Creates a new player connected to the given session. Depending on the config, the player
+    /// either joins the game, takes back a seat they lost, or just watches.
+    fn new(mut session: Session<T>, output: Output, config: &Config) -> Player<T> {
+        // Ask the user for a name to connect with, unless they gave us one already. Spectators
+        // and players who are rejoining don't need one, since the host already knows who they
+        // are (or doesn't care).
+        let mut name = (!config.spectate && config.rejoin.is_none()).then(|| {
+            config
+                .name
+                .clone()
+                .unwrap_or_else(|| Self::input_name(&output))
+        });
+
+        // Ask to connect to the session with the name the user entered, or to take a seat back,
+        // or just to watch.
+        let request = match (&name, config.rejoin) {
+            (_, Some((id, token))) => CtsMessage::Reconnect(id, token),
+            (Some(name), None) => CtsMessage::Connect(name.clone(), config.colour),
+            (None, None) => CtsMessage::Spectate,
+        };
 
-        // Ask to connect to the session with the name the user entered.
-        session.send(CtsMessage::Connect(name.clone()));
+        session
+            .send(request)
+            .unwrap_or_else(|err| Self::lost_host(&output, err));
 
         // The server should register the player with an ID and send it back so we can identify
         // ourselves by ID. (The server uses the ID to identify players in messages, so we need to
-        // have one as soon as we connect.)
-        let id = match session.receive() {
-            StcMessage::IdAssigned(id) => id,
-            msg => panic!("Expected to receive player ID, but got {:?} instead", msg),
+        // have one as soon as we connect.) Anything else is out of place, so we ignore it.
+        let joined = loop {
+            let msg = session
+                .receive()
+                .unwrap_or_else(|err| Self::lost_host(&output, err));
+
+            // If the host won't take our name, we have to answer with another one.
+            if let StcMessage::NameRejected(reason) = msg {
+                output.write_user(format!("You can't use that name: {}.\n", reason));
+
+                let new_name = Self::input_name(&output);
+
+                session
+                    .send(CtsMessage::Connect(new_name.clone(), config.colour))
+                    .unwrap_or_else(|err| Self::lost_host(&output, err));
+
+                name = Some(new_name);
+                continue;
+            }
+
+            match msg {
+                StcMessage::IdAssigned(..)
+                | StcMessage::Spectating(_)
+                | StcMessage::Reconnected(..)
+                | StcMessage::RejoinRejected
+                | StcMessage::GameFull(_)
+                | StcMessage::SpectatorLimitReached(_) => break msg,
+                msg => warn!("Ignoring {:?} while {:?}", msg, ClientState::Connecting),
+            }
         };
 
-        // Acknowledge receipt of the ID.
-        session.send(CtsMessage::Received);
+        let state = ClientState::Connecting
+            .received(&joined)
+            .expect("joining messages are always accepted while connecting");
 
-        Player {
-            id,
-            name,
-            output: Output::new(),
+        let mut player = Player {
+            id: PlayerId::new(),
+            output,
 
             // No role yet, since the server can only pick roles once all the players have
-            // joined and the game is about to start.
+            // joined and the game is about to start. Spectators never get one.
             role: None,
 
             dead: false,
+            fallen: HashSet::new(),
+            findings: vec![],
+            nights: 0,
+            menu_order: config.menu_order,
+            state,
             session,
+            held: RefCell::new(VecDeque::new()),
+            typed: RefCell::new(VecDeque::new()),
+            input_closed: Cell::new(false),
+            counting_down: Cell::new(false),
+            bots: None,
+        };
+
+        match joined {
+            StcMessage::IdAssigned(id, assigned, token) => {
+                player.id = id;
+
+                // The server won't announce our own join to us, so remember our own name so that
+                // we can show it alongside everyone else's.
+                if let Some(name) = name {
+                    player.output.write_log("You joined the game as ");
+                    player.output.write_name(&name, assigned);
+                    player.output.write_log(".\n");
+
+                    player.session.players.insert(id, (name, assigned));
+                }
+
+                if config.colour.is_some_and(|colour| colour != assigned) {
+                    player.output.write_log(
+                        "Someone else already had the colour you asked for, so you got a \
+                         different one.\n",
+                    );
+                }
+
+                let room = player.session.room.as_deref().unwrap_or_default();
+
+                player.output.write_log(format!(
+                    "If you lose your connection, you can rejoin room {} with the code {}.\n",
+                    room,
+                    comm::rejoin_code(id, token)
+                ));
+            }
+
+            // We're given an ID even as a spectator, but it's never one of the players' IDs, so
+            // nothing that happens to a player will be mistaken for something happening to us.
+            StcMessage::Spectating(id) => {
+                player.id = id;
+
+                player.output.write_log(if config.spectate {
+                    "You are spectating the game.\n"
+                } else {
+                    "The game has already started, so you are spectating.\n"
+                });
+            }
+
+            StcMessage::Reconnected(role, dead, phase) => {
+                // We only ask to rejoin with an ID, so we know what it is.
+                player.id = config.rejoin.unwrap().0;
+                player.role = role;
+                player.dead = dead;
+
+                player.output.write_log("You took your seat back.\n");
+
+                if let Some(role) = role {
+                    let (role_name, _) = describe_role(role);
+
+                    player
+                        .output
+                        .write_user(format!("Your role is {}.\n", role_name));
+                }
+
+                if dead {
+                    player
+                        .output
+                        .write_user("You are dead, but you can still watch.\n");
+                }
+
+                match phase {
+                    Phase::Lobby => player.output.write_log("The game hasn't started yet.\n"),
+                    Phase::Night(night) => {
+                        player.nights = night;
+                        player.output.write_nightfall(night);
+                    }
+                    Phase::Day(night) => {
+                        player.nights = night;
+                        player.output.write_log("It's daytime.\n");
+                    }
+                    Phase::Over => player.output.write_log("The game is over.\n"),
+                }
+            }
+
+            StcMessage::RejoinRejected => {
+                player
+                    .output
+                    .write_user("The host didn't recognise your rejoin code.\n");
+                std::process::exit(1);
+            }
+
+            StcMessage::GameFull(max) => {
+                player.output.write_user(format!(
+                    "The game is full, since it only allows {} players.\n",
+                    max
+                ));
+                std::process::exit(1);
+            }
+
+            StcMessage::SpectatorLimitReached(max) => {
+                player.output.write_user(format!(
+                    "Nobody else can watch, since the game only allows {} spectators.\n",
+                    max
+                ));
+                std::process::exit(1);
+            }
+
+            _ => unreachable!("only joining messages end the loop"),
         }
+
+        player
     }
 
-    /// Enters a loop of waiting for messages from the host and responding to them.
+    /// Enters a loop of waiting for messages from the host and responding to them, while also
+    /// carrying out any commands that the user types in the meantime.
     fn play(&mut self) {
         loop {
-            let msg = bincode::deserialize_from(&mut self.session.stream).unwrap();
+            let event = match self.held.get_mut().pop_front() {
+                Some(msg) => Event::Host(Ok(msg)),
+                None => self.session.next_event(),
+            };
+
+            let msg = match event {
+                Event::Host(msg) => msg.unwrap_or_else(|err| Self::lost_host(&self.output, err)),
+
+                // Commands can be used at any time, but anything else the user types has to wait
+                // until there's a question for it to answer.
+                Event::Line(line) => {
+                    if !self.run_command(line.trim()) {
+                        self.typed.get_mut().push_back(line);
+                    }
+
+                    continue;
+                }
+
+                Event::InputClosed => {
+                    self.input_closed.set(true);
+                    continue;
+                }
+            };
+
+            // Make sure that the message makes sense at this point in the game before doing
+            // anything with it. If it doesn't, we don't act on it, but if it's a prompt we still
+            // have to answer it so that the host isn't left waiting.
+            match self.state.received(&msg) {
+                Some(state) => self.state = state,
+                None => {
+                    warn!("Ignoring {:?} while {:?}", msg, self.state);
+
+                    if msg.is_prompt() {
+                        self.send_ack();
+                    }
+
+                    continue;
+                }
+            }
+
+            // Once the session is closed there's nothing more to do, so stop listening.
+            if self.state == ClientState::Closed {
+                self.output.write_log("The host has closed the session.\n");
+                break;
+            }
 
             if let Some(winner) = self.handle_message(msg) {
+                self.output.set_fg(Color::Green, false);
+
                 match winner {
-                    Winner::Wolf => self.output.write_user(
+                    Winner::Wolf => self.output.write_finale(
                         r#"The werewolves win.
-The number of villagers remaining is equal to the number of werewolves."#,
+There are too few villagers left to stop the werewolves."#,
                     ),
-                    Winner::Village => self.output.write_user(
+                    Winner::Village => self.output.write_finale(
                         r#"The villagers win.
 All of the werewolves have been killed."#,
                     ),
+                    Winner::Lovers => self.output.write_finale(
+                        r#"The lovers win.
+A werewolf and a villager have outlived everyone else, together."#,
+                    ),
+                    Winner::Jester => self.output.write_finale(
+                        r#"The jester wins.
+The village fell for it and voted them out."#,
+                    ),
+                    Winner::LoneWolf => self.output.write_finale(
+                        r#"The lone wolf wins.
+They outlived the rest of the pack, and the village too."#,
+                    ),
                 }
 
-                break;
+                self.output.reset();
+                self.output.writeln("");
             }
         }
     }
 
     /// Does something with the given message from the host.
     fn handle_message(&mut self, msg: StcMessage) -> Option<Winner> {
+        if let Some(id) = announced_death(&msg) {
+            self.fallen.insert(id);
+        }
+
         match msg {
             StcMessage::WolvesWake => {
                 self.output.write_log("The wolves wake.\n");
-                self.send_ack();
             }
 
             StcMessage::NightFalls => {
-                self.output.write_log("Night has fallen.\n");
-                self.send_ack();
+                self.nights += 1;
+                self.output.write_nightfall(self.nights);
+            }
+
+            StcMessage::SleepTight => {
+                self.output.write_user(
+                    "You sleep peacefully while the others go about their business...\n",
+                );
             }
 
             StcMessage::Died(id) => {
@@ -186,35 +1077,324 @@ All of the werewolves have been killed."#,
                     self.output.write_user("You were killed last night.\n");
                     self.dead = true;
                 } else {
-                    self.output.write_name(self.session.player_name(id));
+                    self.write_player(id);
                     self.output.write_log(" was killed last night.\n");
                 }
+            }
 
+            StcMessage::VoteOptions { .. }
+            | StcMessage::ChatPrompt
+            | StcMessage::WolfChatPrompt
+            | StcMessage::KillOptions(_)
+            | StcMessage::InvestigateOptions(_)
+            | StcMessage::InspectOptions(_)
+            | StcMessage::ProtectOptions(_)
+            | StcMessage::GuardOptions(_)
+            | StcMessage::ChooseLovers(_)
+            | StcMessage::WitchPrompt { .. }
+                if self.dead =>
+            {
+                // Dead players don't get to vote or kill. The server shouldn't ask us to, but if a
+                // stray prompt does arrive, answer it straight away rather than leaving the user
+                // stuck at a prompt they can't meaningfully answer.
                 self.send_ack();
             }
 
-            StcMessage::VoteOptions(opts) => {
-                let vote = self.ask_vote(opts);
-                self.send(CtsMessage::Vote(vote));
+            StcMessage::VoteOptions {
+                candidates,
+                can_abstain,
+            } => {
+                let msg = match self.ask_vote(candidates, can_abstain) {
+                    Some(vote) => CtsMessage::Vote(vote),
+                    None => CtsMessage::Abstain,
+                };
+
+                self.send(msg);
             }
 
-            StcMessage::KillOptions(opts) => {
-                let kill = self.ask_kill(opts);
-                self.send(CtsMessage::Kill(kill));
+            StcMessage::WitchPrompt {
+                dying,
+                can_heal,
+                can_poison,
+                targets,
+            } => {
+                let action = self.ask_witch(dying, can_heal, can_poison, targets);
+                self.send(CtsMessage::WitchAction(action));
             }
 
-            StcMessage::AnnounceVote(voter_id, against_id) => {
-                self.output.write_name(self.session.player_name(voter_id));
-                self.output.write_log(" voted against ");
-                self.output.write_name(self.session.player_name(against_id));
-                self.output.write_log(".\n");
+            StcMessage::Poisoned(id) => {
+                if id == self.id {
+                    self.output.write_user("You were poisoned in the night.\n");
+                    self.dead = true;
+                } else {
+                    self.write_player(id);
+                    self.output.write_log(" was poisoned in the night.\n");
+                }
+            }
 
-                self.send_ack();
+            StcMessage::ProtectOptions(opts) => {
+                let choice =
+                    self.show_menu("Who do you want to protect tonight?", "Your choice", opts);
+
+                self.send(CtsMessage::Protect(choice));
             }
 
-            StcMessage::NoMajority => {
+            StcMessage::GuardOptions(opts) => {
+                let choice =
+                    self.show_menu("Who do you want to guard tonight?", "Your choice", opts);
+
+                self.send(CtsMessage::Guard(choice));
+            }
+
+            StcMessage::ChooseLovers(mut opts) => {
+                let first = self.show_menu(
+                    "Who is the first player to fall in love?",
+                    "Your choice",
+                    opts.clone(),
+                );
+
+                opts.retain(|&id| id != first);
+
+                let second = self.show_menu("Who do they fall in love with?", "Your choice", opts);
+
+                self.send(CtsMessage::ChooseLovers(first, second));
+            }
+
+            StcMessage::LoverIs(id) => {
+                self.output.write_user("You have fallen in love with ");
+                self.write_player(id);
+                self.output
+                    .write_user(". If either of you dies, the other will die of a broken heart.\n");
+            }
+
+            StcMessage::Heartbroken(id) => {
+                if id == self.id {
+                    self.output.write_user("You died of a broken heart.\n");
+                    self.dead = true;
+                } else {
+                    self.write_player(id);
+                    self.output.write_log(" died of a broken heart.\n");
+                }
+            }
+
+            StcMessage::InvalidChoice => {
+                self.output
+                    .write_user("You can't choose that player, so you'll have to choose again.\n");
+            }
+
+            StcMessage::GuardRejected => {
+                self.output
+                    .write_user("You can't guard that player tonight, so you guard nobody.\n");
+            }
+
+            StcMessage::ProtectionResult(saved) => {
+                self.output.write_user(if saved {
+                    "The wolves came for the player you protected, but you saved them.\n"
+                } else {
+                    "The wolves didn't come for the player you protected.\n"
+                });
+            }
+
+            StcMessage::PoisonResult(killed) => {
+                self.output.write_user(if killed {
+                    "Your poison did its work.\n"
+                } else {
+                    "Your poison was wasted, since its victim was already dead.\n"
+                });
+            }
+
+            StcMessage::PeacefulNight => {
+                self.output.write_log("Nobody died last night.\n");
+            }
+
+            StcMessage::InspectOptions(opts) => {
+                let choice = self.show_menu(
+                    "Whose secrets do you want to uncover tonight?",
+                    "Your choice",
+                    opts,
+                );
+
+                self.send(CtsMessage::Inspect(choice));
+            }
+
+            StcMessage::InspectResult(id, role) => {
+                self.findings.push((self.nights, id, role));
+
+                self.write_player(id);
+
+                self.output.write_user(match role {
+                    Role::Wolf | Role::WolfCub => " is a werewolf!\n",
+                    _ => " is not a werewolf.\n",
+                });
+            }
+
+            StcMessage::InvestigateOptions(opts) => {
+                let choice =
+                    self.show_menu("Who should the village investigate?", "Your choice", opts);
+
+                self.send(CtsMessage::Investigate(choice));
+            }
+
+            StcMessage::Investigated(id, alignment, left) => {
+                self.output.write_log("The village investigated ");
+                self.write_player(id);
+
+                self.output.write_log(match alignment {
+                    Alignment::Wolf => " and found that they are a werewolf!\n",
+                    Alignment::Village => " and found that they are on the village's side.\n",
+                });
+
+                self.output.write_log(match left {
+                    0 => "The village has no investigations left.\n".to_string(),
+                    1 => "The village has one investigation left.\n".to_string(),
+                    n => format!("The village has {} investigations left.\n", spell_out(n)),
+                });
+            }
+
+            StcMessage::KillOptions(opts) => {
+                let kill = self.ask_kill(opts);
+                self.send(CtsMessage::Kill(kill));
+            }
+
+            StcMessage::BomberPrompt(opts) => {
+                let target = self.show_menu(
+                    "You can take someone down with you. Who will it be?",
+                    "Your target",
+                    opts,
+                );
+
+                self.send(CtsMessage::TakeDown(target));
+            }
+
+            StcMessage::HunterShootOptions(opts) => {
+                let target = self.show_menu(
+                    "With your last breath, you can shoot someone. Who will it be?",
+                    "Your target",
+                    opts,
+                );
+
+                self.send(CtsMessage::HunterShoot(target));
+            }
+
+            StcMessage::Shot(hunter_id, victim_id) => {
+                self.write_player(hunter_id);
+
+                if victim_id == self.id {
+                    self.output.write_user(" shot you as they died.\n");
+                    self.dead = true;
+                } else {
+                    self.output.write_log(" shot ");
+                    self.write_player(victim_id);
+                    self.output.write_log(" as they died.\n");
+                }
+            }
+
+            StcMessage::KnightRevenge(id) => {
+                if id == self.id {
+                    self.output
+                        .write_user("The knight you killed has taken their revenge on you.\n");
+                    self.dead = true;
+                } else {
+                    self.write_player(id);
+                    self.output
+                        .write_log(" was struck down in revenge for killing the knight.\n");
+                }
+            }
+
+            StcMessage::SaintLynched(id) => {
+                if id == self.id {
+                    self.output
+                        .write_user("The village will pay for voting out a saint.\n");
+                } else {
+                    self.write_player(id);
+                    self.output
+                        .write_log(" was a saint, and the village will pay for voting them out.\n");
+                }
+            }
+
+            StcMessage::PrinceRevealed(id) => {
+                if id == self.id {
+                    self.output.write_user(
+                        "You show the village that you're the prince, and they let you go. \
+                         They won't be so forgiving next time.\n",
+                    );
+                } else {
+                    self.write_player(id);
+                    self.output.write_log(
+                        " is the prince, so the village lets them go. They won't be so forgiving \
+                         next time.\n",
+                    );
+                }
+            }
+
+            StcMessage::LynchForbidden => {
+                self.output.write_log(
+                    "Nobody can be voted out today, as penance for voting out the saint.\n",
+                );
+            }
+
+            StcMessage::TakenDown(bomber_id, victim_id) => {
+                self.write_player(bomber_id);
+
+                if victim_id == self.id {
+                    self.output.write_user(" took you down with them.\n");
+                    self.dead = true;
+                } else {
+                    self.output.write_log(" took ");
+                    self.write_player(victim_id);
+                    self.output.write_log(" down with them.\n");
+                }
+            }
+
+            StcMessage::AnnounceVote(voter_id, against_id) => {
+                self.write_player(voter_id);
+                self.output.write_log(" voted against ");
+                self.write_player(against_id);
+                self.output.write_log(".\n");
+            }
+
+            StcMessage::Abstained(id) => {
+                if id == self.id {
+                    self.output.write_user("You abstained.\n");
+                } else {
+                    self.write_player(id);
+                    self.output.write_log(" abstained.\n");
+                }
+            }
+
+            StcMessage::VoteTimedOut(id) => {
+                if id == self.id {
+                    self.output
+                        .write_user("You took too long to vote, so your vote wasn't counted.\n");
+                } else {
+                    self.write_player(id);
+                    self.output
+                        .write_log(" didn't vote in time, so their vote wasn't counted.\n");
+                }
+            }
+
+            StcMessage::VoteTie(tied) => {
+                self.output.write_log("The vote is tied between ");
+
+                for (i, &id) in tied.iter().enumerate() {
+                    if i != 0 {
+                        self.output
+                            .write_log(if i + 1 == tied.len() { " and " } else { ", " });
+                    }
+
+                    self.write_player(id);
+                }
+
+                self.output
+                    .write_log(". There will be a runoff between them.\n");
+            }
+
+            StcMessage::VoteResults(results) => {
+                self.show_vote_results(results);
+            }
+
+            StcMessage::NoMajority => {
                 self.output.write_log("There was no majority vote.\n");
-                self.send_ack();
             }
 
             StcMessage::VotedOut(id) => {
@@ -223,75 +1403,604 @@ All of the werewolves have been killed."#,
                         .write_user("You were voted out by the other players.\n");
                     self.dead = true;
                 } else {
-                    self.output.write_name(self.session.player_name(id));
+                    self.write_player(id);
                     self.output
                         .write_log(" was voted out by the other players.\n");
                 }
+            }
+
+            StcMessage::ChatPrompt => {
+                let line = self.read_answer(|| {
+                    self.output
+                        .write_user("Say something (or press enter to stay quiet): ")
+                });
+
+                self.send(CtsMessage::Chat(line));
+            }
+
+            StcMessage::LastWordsPrompt => {
+                let line = self.read_answer(|| {
+                    self.output
+                        .write_user("Any last words? Press enter to go quietly: ")
+                });
+
+                self.send(CtsMessage::LastWords(line));
+            }
+
+            StcMessage::AskToContinue(step) => {
+                self.read_answer(|| self.output.write_user(format!("Press enter to {}: ", step)));
 
                 self.send_ack();
             }
 
+            StcMessage::LastWordsBroadcast(id, text) => {
+                self.write_player(id);
+                self.output
+                    .write_log(format!("'s last words: \"{}\"\n", text));
+            }
+
+            StcMessage::ChatBroadcast(id, text) => {
+                self.write_player(id);
+                self.output.write_log(format!(": {}\n", text));
+            }
+
+            StcMessage::WolfChatPrompt => {
+                let line = self.read_answer(|| {
+                    self.output.set_fg(Color::Red, false);
+                    self.output
+                        .write("[Wolves] Say something to the other wolves (or press enter): ");
+                    self.output.reset();
+                });
+
+                self.send(CtsMessage::WolfChatSend(line));
+            }
+
+            StcMessage::WolfChat(id, text) => {
+                // Wolf chat is secret, so make it stand out from everything said in the open.
+                self.output.set_fg(Color::Red, false);
+                self.output.write("[Wolves] ");
+                self.output.reset();
+
+                self.write_player(id);
+
+                self.output.set_fg(Color::Red, false);
+                self.output.write(format!(": {}\n", text));
+                self.output.reset();
+            }
+
+            StcMessage::DeadChatPrompt => {
+                let line = self.read_answer(|| {
+                    self.output.set_fg(Color::Magenta, false);
+                    self.output
+                        .write("[Dead] Say something to the other dead (or press enter): ");
+                    self.output.reset();
+                });
+
+                self.send(CtsMessage::DeadChat(line));
+            }
+
+            StcMessage::DeadChatRelay(id, text) => {
+                // The living can't hear any of this, so it's kept apart from the open chat.
+                self.output.set_fg(Color::Magenta, false);
+                self.output.write("[Dead] ");
+                self.output.reset();
+
+                self.write_player(id);
+
+                self.output.set_fg(Color::Magenta, false);
+                self.output.write(format!(": {}\n", text));
+                self.output.reset();
+            }
+
+            StcMessage::PlayerLeft(id) => {
+                self.write_player(id);
+                self.output
+                    .write_log(" lost their connection and left the game.\n");
+            }
+
+            StcMessage::PlayerDisconnected(id) => {
+                self.write_player(id);
+                self.output
+                    .write_log(" lost their connection. Their seat is held for a while.\n");
+            }
+
+            StcMessage::PlayerReconnected(id) => {
+                self.write_player(id);
+                self.output.write_log(" is back.\n");
+            }
+
             StcMessage::RoleAssigned(role) => {
                 self.role = Some(role);
+                self.counting_down.set(false);
 
                 // Tell the player what their role is, and what they are supposed to do.
-                let (role_name, desc) = match role {
-                    Role::Wolf => ("werewolf", "Kill others and avoid detection."),
-                    Role::Villager => (
-                        "villager",
-                        "Do villager things, avoid being killed, and capture the werewolves.",
-                    ),
-                };
+                let (role_name, desc) = describe_role(role);
 
                 self.output
                     .write_user(format!("Your role is {}.\n", role_name));
-                self.output.write(desc);
-                println!();
+                self.output.writeln(desc);
+            }
 
-                self.send_ack();
+            StcMessage::YouAreNowSeer => {
+                self.role = Some(Role::Seer);
+
+                self.output
+                    .write_user("The seer is dead, so you are the seer now.\n");
+                self.output.writeln(describe_role(Role::Seer).1);
+            }
+
+            StcMessage::PackEnraged => {
+                self.output.set_fg(Color::Red, false);
+                self.output
+                    .write("The cub is dead, and the pack wants revenge. Choose another victim.\n");
+                self.output.reset();
+            }
+
+            StcMessage::LoneWolf => {
+                self.output.write_user(
+                    "You are the lone wolf. You only win if you're the last werewolf standing, so \
+                     the rest of the pack has to go too.\n",
+                );
+            }
+
+            StcMessage::WolfTeammates(teammates) => {
+                // A minion isn't a werewolf, so every werewolf is listed rather than just the
+                // others.
+                let intro = if self.role == Some(Role::Minion) {
+                    "The werewolves are "
+                } else {
+                    "Your fellow werewolves are "
+                };
+
+                if teammates.is_empty() {
+                    self.output.write_log("You are the only werewolf.\n");
+                } else {
+                    self.output.write_log(intro);
+
+                    for (i, &id) in teammates.iter().enumerate() {
+                        if i != 0 {
+                            self.output.write_log(", ");
+                        }
+
+                        self.write_player(id);
+                    }
+
+                    self.output.write_log(".\n");
+                }
             }
 
-            StcMessage::AnnounceWinner(winner) => return Some(winner),
+            StcMessage::GameSetup(setup) => match setup {
+                Setup::Full(roles) => {
+                    let names: Vec<&str> = roles
+                        .into_iter()
+                        .map(|role| describe_role(role).0)
+                        .collect();
+
+                    self.output
+                        .write_log(format!("The roles in play are: {}.\n", names.join(", ")));
+                }
+
+                Setup::Partial(1) => {
+                    self.output
+                        .write_log("There is one special role in play.\n");
+                }
+
+                Setup::Partial(count) => {
+                    self.output.write_log(format!(
+                        "There are {} special roles in play.\n",
+                        spell_out(count)
+                    ));
+                }
+
+                Setup::Hidden => {
+                    self.output
+                        .write_log("The roles in play are being kept secret.\n");
+                }
+            },
+
+            StcMessage::Seating(seating) => {
+                self.output.write_log("Around the table, clockwise, are ");
+
+                for (i, &id) in seating.iter().enumerate() {
+                    if i != 0 {
+                        self.output.write_log(", ");
+                    }
+
+                    self.write_player(id);
+                }
+
+                self.output.write_log(".\n");
+
+                if let Some((left, right)) = neighbours(&seating, self.id) {
+                    self.output.write_log("You are sitting between ");
+                    self.write_player(left);
+                    self.output.write_log(" on your left and ");
+                    self.write_player(right);
+                    self.output.write_log(" on your right.\n");
+                }
+            }
+
+            StcMessage::GameEnding(winner) => {
+                self.output.write_finale(match winner {
+                    Winner::Wolf => "The game is over, and the werewolves have won!\n",
+                    Winner::Village => "The game is over, and the villagers have won!\n",
+                    Winner::Lovers => "The game is over, and the lovers have won!\n",
+                    Winner::Jester => "The game is over, and the jester has won!\n",
+                    Winner::LoneWolf => "The game is over, and the lone wolf has won!\n",
+                });
+
+                self.output
+                    .write_finale("Let's see who everyone really was...\n");
+            }
+
+            StcMessage::RoleReveal(id, role) => {
+                let (role_name, _) = describe_role(role);
+
+                if id == self.id {
+                    self.output
+                        .write_finale(format!("You were a {}.\n", role_name));
+                } else {
+                    self.write_player(id);
+                    self.output.write_finale(format!(" was a {}.\n", role_name));
+                }
+            }
+
+            StcMessage::Secret(secret) => {
+                self.reveal_secret(secret);
+            }
+
+            StcMessage::AnnounceWinner(winner) => {
+                return Some(winner);
+            }
+
+            StcMessage::VoteOrder(order) => {
+                self.output.write_log("Players will vote in this order: ");
+
+                for (i, &id) in order.iter().enumerate() {
+                    if i != 0 {
+                        self.output.write_log(", ");
+                    }
+
+                    self.write_player(id);
+                }
+
+                self.output.write_log(".\n");
+            }
 
             StcMessage::WaitingFor(id) => {
                 if id == self.id {
                     self.output.write_user("It's your turn to vote.\n");
                 } else {
                     self.output.write_log("Waiting for ");
-                    self.output.write_name(self.session.player_name(id));
+                    self.write_player(id);
                     self.output.write_log(" to vote.\n");
                 }
-
-                self.send_ack();
             }
 
-            StcMessage::AnnounceJoin(id, name) => {
-                self.output.write_name(&name);
+            StcMessage::AnnounceJoin(id, name, colour) => {
+                self.output.write_name(&name, colour);
                 self.output.write_log(" joined the game.\n");
-                self.send_ack();
 
-                self.session.players.insert(id, name);
+                self.session.players.insert(id, (name, colour));
             }
 
-            StcMessage::Players(map) => {
-                self.session.players.extend(map.into_iter());
-                self.send_ack();
+            StcMessage::PlayerRenamed(id, name) => {
+                self.write_player(id);
+                self.output.write_log(" is now called ");
+
+                if let Some((old, colour)) = self.session.players.get_mut(&id) {
+                    self.output.write_name(&name, *colour);
+                    *old = name;
+                }
+
+                self.output.write_log(".\n");
+            }
+
+            StcMessage::RenameRejected(reason) => {
+                self.output
+                    .write_user(format!("You can't use that name: {}.\n", reason));
+            }
+
+            StcMessage::Players(players) => {
+                self.session.players.extend(
+                    players
+                        .into_iter()
+                        .map(|(id, name, colour)| (id, (name, colour))),
+                );
+            }
+
+            StcMessage::GameSnapshot(snapshot) => {
+                self.catch_up(snapshot);
+            }
+
+            StcMessage::NotEnoughPlayers(needed) => {
+                self.output.writeln(format!(
+                    "The game needs at least {} players for the roles you chose. Waiting for more \
+                     players...",
+                    needed
+                ));
+            }
+
+            StcMessage::AskToStart(everyone_ready) => {
+                let line = self.read_answer(|| {
+                    self.output.write_user(if everyone_ready {
+                        "Everyone is ready. Do you wish to start the game? y/n: "
+                    } else {
+                        "Not everyone is ready. Do you wish to start the game anyway? y/n: "
+                    })
+                });
+
+                if line.starts_with('y') {
+                    self.send(CtsMessage::StartGame);
+                } else {
+                    self.output.writeln("Waiting for more players...");
+                    self.send_ack();
+                }
+            }
+
+            StcMessage::AskReady => {
+                let line =
+                    self.read_answer(|| self.output.write_user("Are you ready to play? y/n: "));
+
+                self.send(CtsMessage::Ready(line.starts_with('y')));
+            }
+
+            StcMessage::LobbyState(lobby) => {
+                let (ready, waiting): (Vec<_>, Vec<_>) =
+                    lobby.into_iter().partition(|&(_, ready)| ready);
+
+                for (label, players) in [("Ready: ", ready), ("Not ready: ", waiting)] {
+                    if players.is_empty() {
+                        continue;
+                    }
+
+                    self.output.write_log(label);
+
+                    for (i, &(id, _)) in players.iter().enumerate() {
+                        if i != 0 {
+                            self.output.write_log(", ");
+                        }
+
+                        self.write_player(id);
+                    }
+
+                    self.output.write_log("\n");
+                }
+            }
+
+            StcMessage::StartingIn(remaining) => {
+                let secs = remaining.as_secs();
+
+                self.output.write_log(format!(
+                    "The game starts in {} second{}.\n",
+                    secs,
+                    if secs == 1 { "" } else { "s" }
+                ));
+
+                if !self.counting_down.replace(true) {
+                    self.output
+                        .write_user("If you're not ready after all, type /unready.\n");
+                }
+            }
+
+            StcMessage::CountdownCancelled(id) => {
+                self.counting_down.set(false);
+
+                if id == self.id {
+                    self.output.write_log("You're not ready any more.\n");
+                } else {
+                    self.write_player(id);
+                    self.output
+                        .write_log(" isn't ready any more, so the game won't start yet.\n");
+                }
+            }
+
+            StcMessage::GameOver => {
+                let line = self.read_answer(|| {
+                    self.output
+                        .write_user("Do you want to play another game? y/n: ")
+                });
+
+                let again = line.starts_with('y');
+
+                if again {
+                    self.start_over();
+                    self.output
+                        .writeln("Waiting for everyone else to decide...");
+                }
+
+                self.send(CtsMessage::PlayAgain(again));
+
+                // Our bots leave after every game, so we need new ones for the next.
+                if again {
+                    self.spawn_bots();
+                }
             }
 
-            msg => println!("Unhandled message {:?} in loop", msg),
+            msg => warn!("Unhandled message {:?} in loop", msg),
         }
 
         None
     }
 
-    /// Sends the `Received` message to the host.
+    /// Starts the bots we're bringing into the game, if there are any.
+    fn spawn_bots(&self) {
+        if let Some((addr, count)) = self.bots {
+            let room = self.session.room.as_deref().unwrap_or_default();
+            bot::spawn_all(addr, room, count);
+        }
+    }
+
+    /// Forgets everything about the last game, ready for the next one. The host tells us who is
+    /// playing again, so we forget the other players too.
+    fn start_over(&mut self) {
+        self.role = None;
+        self.dead = false;
+        self.fallen.clear();
+        self.findings.clear();
+        self.nights = 0;
+        self.session.players.clear();
+    }
+
+    /// Tells the user that we can no longer talk to the host because of the given error, and
+    /// exits.
+    ///
+    /// There's no way to carry on once we've lost track of the host's messages, but a confused
+    /// user is better served by an explanation than by a panic.
+    fn lost_host(output: &Output, err: CommError) -> ! {
+        info!("Lost contact with the host: {}", err);
+
+        output.writeln("");
+
+        match err {
+            CommError::Disconnected(_) | CommError::TimedOut => {
+                output.write_user("Lost connection to the host.\n")
+            }
+            CommError::Malformed(_) => output.write_user(
+                "The host sent something that couldn't be understood. They may be running a \
+                 different version of the game.\n",
+            ),
+        }
+
+        std::process::exit(1);
+    }
+
+    /// Brings our view of the game up to date with the given snapshot, and tells the user what
+    /// they've missed. There's nothing to tell anyone who joins before the game starts.
+    fn catch_up(&mut self, snapshot: GameSnapshot) {
+        if let Phase::Night(night) | Phase::Day(night) = snapshot.phase {
+            self.nights = night;
+        }
+
+        // Spectators aren't players, so they can't be dead.
+        if self.session.players.contains_key(&self.id) && !snapshot.living.contains(&self.id) {
+            self.dead = true;
+        }
+
+        if snapshot.history.is_empty() {
+            return;
+        }
+
+        self.output.write_log("Here's what has happened so far:\n");
+
+        for event in snapshot.history {
+            match event {
+                PublicEvent::NightFell(night) => self
+                    .output
+                    .write_log(format!("Night {} fell.\n", spell_out(night))),
+
+                PublicEvent::Voted(voter, target) => {
+                    self.write_player(voter);
+                    self.output.write_log(" voted against ");
+                    self.write_player(target);
+                    self.output.write_log(".\n");
+                }
+
+                PublicEvent::Died(id) => {
+                    self.fallen.insert(id);
+                    self.write_player(id);
+                    self.output.write_log(" died.\n");
+                }
+            }
+        }
+
+        self.output.write_log("Still alive: ");
+
+        for (i, &id) in snapshot.living.iter().enumerate() {
+            if i != 0 {
+                self.output.write_log(", ");
+            }
+
+            self.write_player(id);
+        }
+
+        self.output.write_log("\n");
+    }
+
+    /// Shows a spectator something that the players can't see.
+    fn reveal_secret(&self, secret: Secret) {
+        match secret {
+            Secret::Roles(roles) => {
+                for (id, role) in roles {
+                    let (role_name, _) = describe_role(role);
+
+                    self.write_player(id);
+                    self.output.write_log(format!(" is a {}.\n", role_name));
+                }
+            }
+
+            Secret::WolvesChose(id) => {
+                self.output.write_log("The wolves chose to kill ");
+                self.write_player(id);
+                self.output.write_log(".\n");
+            }
+
+            Secret::Protected(doctor_id, id) => {
+                self.write_player(doctor_id);
+                self.output.write_log(" chose to protect ");
+                self.write_player(id);
+                self.output.write_log(".\n");
+            }
+
+            Secret::Guarded(bodyguard_id, id) => {
+                self.write_player(bodyguard_id);
+                self.output.write_log(" chose to guard ");
+                self.write_player(id);
+                self.output.write_log(".\n");
+            }
+
+            Secret::Lovers(cupid_id, first, second) => {
+                self.write_player(cupid_id);
+                self.output.write_log(" made ");
+                self.write_player(first);
+                self.output.write_log(" and ");
+                self.write_player(second);
+                self.output.write_log(" fall in love.\n");
+            }
+
+            Secret::Inspected(seer_id, id, role) => {
+                self.write_player(seer_id);
+                self.output.write_log(" found out that ");
+                self.write_player(id);
+
+                self.output.write_log(match role {
+                    Role::Wolf | Role::WolfCub => " is a werewolf.\n",
+                    _ => " is not a werewolf.\n",
+                });
+            }
+
+            Secret::Brewed(witch_id, action) => {
+                self.write_player(witch_id);
+
+                match action {
+                    WitchAction::Heal => self.output.write_log(" used their healing potion.\n"),
+                    WitchAction::Poison(id) => {
+                        self.output.write_log(" poisoned ");
+                        self.write_player(id);
+                        self.output.write_log(".\n");
+                    }
+                    WitchAction::Pass => self.output.write_log(" kept their potions.\n"),
+                }
+            }
+        }
+    }
+
+    /// Writes the name of the player with the given ID in their colour.
+    fn write_player(&self, id: PlayerId) {
+        self.output
+            .write_name(self.session.player_name(id), self.session.player_colour(id));
+    }
+
+    /// Answers a prompt from the host with `Received`, without making a decision.
     fn send_ack(&mut self) {
         self.send(CtsMessage::Received);
     }
 
     /// Sends the given message to the host.
     fn send(&mut self, msg: CtsMessage) {
-        bincode::serialize_into(&mut self.session.stream, &msg).unwrap();
+        if let Err(err) = self.session.send(msg) {
+            Self::lost_host(&self.output, err);
+        }
     }
 
     fn show_menu(
@@ -300,42 +2009,220 @@ All of the werewolves have been killed."#,
         prompt: impl AsRef<str>,
         opts: Vec<PlayerId>,
     ) -> PlayerId {
-        let mut line = String::new();
+        self.choose_from_menu(title, prompt, opts, false)
+            .expect("the user can only abstain when they're allowed to")
+    }
+
+    /// Shows the user a menu of the given players and asks them to pick one. If `can_abstain` is
+    /// `true`, the menu ends with an option to abstain, numbered zero, which returns `None`.
+    fn choose_from_menu(
+        &self,
+        title: impl AsRef<str>,
+        prompt: impl AsRef<str>,
+        mut opts: Vec<PlayerId>,
+        can_abstain: bool,
+    ) -> Option<PlayerId> {
+        // The server sends the options in whatever order it likes, so put them in a predictable
+        // order. We send back the ID of the chosen player rather than its position, so this
+        // doesn't affect which player the server thinks we picked.
+        match self.menu_order {
+            MenuOrder::Join => opts.sort(),
+            MenuOrder::Alphabetical => {
+                opts.sort_by_cached_key(|&id| self.session.player_name(id).to_lowercase())
+            }
+        }
 
         loop {
-            self.output.write_user(title.as_ref());
+            let line = self.read_answer(|| {
+                self.output.write_user(title.as_ref());
+
+                for (i, &id) in opts.iter().enumerate() {
+                    self.output
+                        .write_option(i + 1, self.session.player_name(id));
+                }
+
+                if can_abstain {
+                    self.output.write_option(0, "Abstain");
+                }
+
+                self.output.writeln("");
+                self.output.write_user(format!(
+                    "{} (1 to {}{}): ",
+                    prompt.as_ref(),
+                    opts.len(),
+                    if can_abstain { ", or 0 to abstain" } else { "" }
+                ));
+            });
 
-            for (i, &id) in opts.iter().enumerate() {
-                let name = self.session.player_name(id);
-                self.output.write(format!("  [{}] {}", i + 1, name));
+            match line.parse::<usize>() {
+                Ok(0) if can_abstain => break None,
+
+                // Subtract one to turn the number into an index again, then find the ID the
+                // player is referring to.
+                Ok(num) if (1..=opts.len()).contains(&num) => break Some(opts[num - 1]),
+
+                _ => (),
             }
 
-            println!();
+            self.output.write("Invalid input. Please try again.\n");
+        }
+    }
+
+    /// Shows the given prompt and reads the user's answer, trimmed. If the user types a command
+    /// such as `/players` instead, we carry it out and then show the prompt again, so commands
+    /// can be used whenever the user is asked for something.
+    fn read_answer(&self, show_prompt: impl Fn()) -> String {
+        loop {
+            show_prompt();
+
+            let line = self.next_line();
+            let answer = line.trim();
+
+            if !self.run_command(answer) {
+                break answer.to_string();
+            }
+        }
+    }
+
+    /// Waits for the next line from the user, using up anything they typed ahead first. If their
+    /// input has ended, the line is empty.
+    fn next_line(&self) -> String {
+        if let Some(line) = self.typed.borrow_mut().pop_front() {
+            return line;
+        }
+
+        while !self.input_closed.get() {
+            match self.session.next_event() {
+                Event::Line(line) => return line,
+                Event::InputClosed => self.input_closed.set(true),
+                Event::Host(Ok(msg)) => self.held.borrow_mut().push_back(msg),
+                Event::Host(Err(err)) => Self::lost_host(&self.output, err),
+            }
+        }
+
+        String::new()
+    }
+
+    /// Carries out the given command, if it is one. Returns `false` if it isn't a command, so
+    /// that it can be used as an answer instead.
+    fn run_command(&self, line: &str) -> bool {
+        match line {
+            "/players" => self.show_players(),
+            "/notes" => self.show_findings(),
+            "/unready" => self.back_out(),
+            _ => match line.strip_prefix("/rename ") {
+                Some(name) => self.rename(name.trim()),
+                None => return false,
+            },
+        }
+
+        true
+    }
+
+    /// Tells the host that we're not ready after all, which calls off the countdown to the start
+    /// of the game. This only means anything while the countdown is going.
+    fn back_out(&self) {
+        if !self.counting_down.get() {
             self.output
-                .write_user(format!("{} (1 to {}): ", prompt.as_ref(), opts.len()));
-            std::io::stdout().flush().unwrap();
+                .write_user("You can only back out while the game is counting down to start.\n");
+            return;
+        }
 
-            std::io::stdin().read_line(&mut line).unwrap();
+        if let Err(err) = self.session.send(CtsMessage::Ready(false)) {
+            Self::lost_host(&self.output, err);
+        }
+    }
 
-            if let Ok(num) = line.trim().parse::<usize>() {
-                if (1..=opts.len()).contains(&num) {
-                    // Subtract one to turn the number into an index again, then find the ID the
-                    // player is referring to.
-                    break opts[num - 1];
-                }
+    /// Asks the host to let us go by a different name. Names are settled once the game starts,
+    /// so this is only allowed in the lobby.
+    fn rename(&self, name: &str) {
+        if self.state != ClientState::Lobby {
+            self.output
+                .write_user("You can only change your name before the game starts.\n");
+            return;
+        }
+
+        if let Err(err) = self.session.send(CtsMessage::Rename(name.to_string())) {
+            Self::lost_host(&self.output, err);
+        }
+    }
+
+    /// Lists everything we've found out as a seer, in the order we found it out.
+    fn show_findings(&self) {
+        if self.findings.is_empty() {
+            self.output
+                .write_user("You haven't found out anything about anyone yet.\n");
+            return;
+        }
+
+        for &(night, id, role) in &self.findings {
+            self.output
+                .write_log(format!("Night {}: ", spell_out(night)));
+            self.write_player(id);
+
+            self.output.write_log(match role {
+                Role::Wolf | Role::WolfCub => " is a werewolf.\n",
+                _ => " is not a werewolf.\n",
+            });
+        }
+    }
+
+    /// Shows how many votes each player got, with the most votes first.
+    fn show_vote_results(&self, mut results: Vec<(PlayerId, usize)>) {
+        if results.is_empty() {
+            self.output.write_log("Nobody got any votes.\n");
+            return;
+        }
+
+        results.sort_by_key(|&(id, votes)| (Reverse(votes), id));
+
+        self.output.write_log("The votes were:\n");
+
+        for (id, votes) in results {
+            self.output.write_log("  ");
+            self.write_player(id);
+
+            self.output.write_log(match votes {
+                1 => ": one vote\n".to_string(),
+                n => format!(": {} votes\n", spell_out(n)),
+            });
+        }
+    }
+
+    /// Lists everyone in the game in the order they joined, with the players we know to be dead
+    /// dimmed.
+    fn show_players(&self) {
+        let mut ids: Vec<_> = self.session.players.keys().copied().collect();
+        ids.sort();
+
+        self.output.write_user("Players:\n");
+
+        for id in ids {
+            self.output.write_log("  ");
+
+            if self.fallen.contains(&id) {
+                self.output
+                    .write_dimmed_name(format!("{} (dead)", self.session.player_name(id)));
+            } else {
+                self.write_player(id);
             }
 
-            self.output.write("Invalid input. Please try again.\n");
-            line.clear();
+            self.output.write_log("\n");
         }
     }
 
     /// Presents the user with a voting menu, given a vector of names of players that could be
     /// voted against.
     ///
-    /// Returns the ID of the person the player votes against.
-    fn ask_vote(&self, opts: Vec<PlayerId>) -> PlayerId {
-        self.show_menu("Who do you want to vote out?", "Your vote", opts)
+    /// Returns the ID of the person the player votes against, or `None` if they abstain (which
+    /// they can only do if `can_abstain` is `true`).
+    fn ask_vote(&self, opts: Vec<PlayerId>, can_abstain: bool) -> Option<PlayerId> {
+        self.choose_from_menu(
+            "Who do you want to vote out?",
+            "Your vote",
+            opts,
+            can_abstain,
+        )
     }
 
     /// Presents the user with a kill menu, given a vector of names of potential victims.
@@ -345,19 +2232,69 @@ All of the werewolves have been killed."#,
         self.show_menu("Who do you want to kill?", "Your victim", opts)
     }
 
+    /// Asks the user, as a witch, what they want to do with their potions tonight. They're
+    /// offered the healing potion first, since saving someone uses up the night's choice.
+    ///
+    /// Returns the action the witch chooses.
+    fn ask_witch(
+        &self,
+        dying: Option<PlayerId>,
+        can_heal: bool,
+        can_poison: bool,
+        targets: Vec<PlayerId>,
+    ) -> WitchAction {
+        match dying {
+            Some(id) => {
+                self.output.write_user("The werewolves are about to kill ");
+                self.write_player(id);
+                self.output.write_user(".\n");
+            }
+            None => self
+                .output
+                .write_user("Nobody is going to be killed by the werewolves tonight.\n"),
+        }
+
+        if can_heal {
+            let line = self.read_answer(|| {
+                self.output
+                    .write_user("Do you want to use your healing potion to save them? y/n: ")
+            });
+
+            if line.starts_with('y') {
+                return WitchAction::Heal;
+            }
+        }
+
+        if can_poison {
+            let line = self.read_answer(|| {
+                self.output
+                    .write_user("Do you want to use your poison on someone? y/n: ")
+            });
+
+            if line.starts_with('y') {
+                return WitchAction::Poison(self.show_menu(
+                    "Who do you want to poison?",
+                    "Your victim",
+                    targets,
+                ));
+            }
+        }
+
+        WitchAction::Pass
+    }
+
     /// Gets a valid player name from the user.
-    fn input_name() -> String {
+    fn input_name(output: &Output) -> String {
         let mut name = String::new();
 
         loop {
-            print!("Please enter your name: ");
-            std::io::stdout().flush().unwrap();
+            output.write("Please enter your name: ");
             std::io::stdin().read_line(&mut name).unwrap();
 
             let trimmed = name.trim();
 
             if trimmed.is_empty() {
-                println!("You can't have an empty name! Try again.");
+                output.writeln("You can't have an empty name! Try again.");
 
                 name.clear();
                 continue;
@@ -367,3 +2304,107 @@ All of the werewolves have been killed."#,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::{channel_pair, ChannelTransport};
+
+    /// Returns a player in a game, along with the host's ends of their connection: one to read
+    /// what the player sends, and one to send them messages.
+    fn player() -> (Player<ChannelTransport>, ChannelTransport, ChannelTransport) {
+        let config = Config {
+            name: None,
+            colour: None,
+            dramatic: false,
+            screen_reader: true,
+            menu_order: MenuOrder::Join,
+            no_colour: true,
+            symbols: false,
+            spectate: false,
+            room: RoomChoice::Join(String::new()),
+            rejoin: None,
+            bots: 0,
+            timestamps: false,
+            transcript: None,
+        };
+
+        // The session sends and reads over separate connections, so the host gets one end of
+        // each.
+        let (to_host, from_player) = channel_pair();
+        let (from_host, to_player) = channel_pair();
+
+        let player = Player {
+            id: PlayerId::new(),
+            output: Output::new(&config),
+            role: Some(Role::Villager),
+            dead: false,
+            fallen: HashSet::new(),
+            findings: vec![],
+            nights: 1,
+            menu_order: config.menu_order,
+            state: ClientState::Playing,
+            session: Session::new(to_host, from_host),
+            held: RefCell::new(VecDeque::new()),
+            typed: RefCell::new(VecDeque::new()),
+            input_closed: Cell::new(false),
+            counting_down: Cell::new(false),
+            bots: None,
+        };
+
+        (player, from_player, to_player)
+    }
+
+    #[test]
+    fn dead_player_answers_a_stray_prompt_without_asking() {
+        let (mut player, mut from_player, _to_player) = player();
+        player.dead = true;
+
+        // If the player asked the user, they'd wait for input forever, so we handle the message
+        // on another thread and only wait so long for the answer.
+        std::thread::spawn(move || {
+            player.handle_message(StcMessage::VoteOptions {
+                candidates: vec![PlayerId::new().next()],
+                can_abstain: false,
+            });
+
+            player
+        });
+
+        from_player
+            .set_recv_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        assert_eq!(
+            comm::receive::<CtsMessage>(&mut from_player).unwrap(),
+            CtsMessage::Received
+        );
+    }
+
+    #[test]
+    fn neighbours_wrap_around_the_table() {
+        let first = PlayerId::new();
+        let seating = [first, first.next(), first.next().next()];
+
+        assert_eq!(
+            neighbours(&seating, seating[0]),
+            Some((seating[1], seating[2]))
+        );
+        assert_eq!(
+            neighbours(&seating, seating[1]),
+            Some((seating[2], seating[0]))
+        );
+        assert_eq!(
+            neighbours(&seating, seating[2]),
+            Some((seating[0], seating[1]))
+        );
+    }
+
+    #[test]
+    fn unseated_player_has_no_neighbours() {
+        let first = PlayerId::new();
+        let seating = [first, first.next()];
+
+        assert_eq!(neighbours(&seating, first.next().next()), None);
+    }
+}
@@ -1,10 +1,551 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddrV4, TcpStream},
+    sync::mpsc,
+    time::Duration,
+};
+
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The largest message, in bytes, that will be read from a connection. No real message comes
+/// anywhere close to this, so anything bigger is rejected before it can cause a huge allocation.
+pub const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+/// The version of the protocol that this build speaks. Clients and servers only talk to each
+/// other if their versions match, so this must be bumped whenever `CtsMessage`, `StcMessage` or
+/// anything sent inside them changes.
+pub const PROTOCOL_VERSION: u32 = 27;
+
+/// The most characters that a player's name can have.
+pub const MAX_NAME_LEN: usize = 24;
+
+/// Returns the bincode options used for every message. These match the encoding used by
+/// `bincode::serialize`, but with a limit on how much can be read for a single message.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_MESSAGE_SIZE)
+}
+
+/// Something that went wrong while talking to the other end of a connection.
+#[derive(Debug)]
+pub enum CommError {
+    /// The connection failed or was closed, so nothing more can be sent or received over it.
+    Disconnected(io::Error),
+
+    /// The other end sent something that isn't a message we understand, such as garbage or a
+    /// message from a different version of the game.
+    Malformed(String),
+
+    /// The other end didn't send anything before the read timeout ran out. The connection is
+    /// still fine, and whatever they send next may just be late.
+    TimedOut,
+}
+
+impl fmt::Display for CommError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommError::Disconnected(err) => write!(f, "connection lost: {}", err),
+            CommError::Malformed(err) => write!(f, "malformed message: {}", err),
+            CommError::TimedOut => write!(f, "timed out waiting for a message"),
+        }
+    }
+}
+
+impl std::error::Error for CommError {}
+
+impl From<io::Error> for CommError {
+    fn from(err: io::Error) -> CommError {
+        // A frame that's too big to accept is the other end's fault, not the connection's.
+        match err.kind() {
+            io::ErrorKind::InvalidData => CommError::Malformed(err.to_string()),
+
+            // Different platforms report a read timeout in different ways.
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => CommError::TimedOut,
+
+            _ => CommError::Disconnected(err),
+        }
+    }
+}
+
+impl From<bincode::Error> for CommError {
+    fn from(err: bincode::Error) -> CommError {
+        match *err {
+            bincode::ErrorKind::Io(err) => err.into(),
+            err => CommError::Malformed(err.to_string()),
+        }
+    }
+}
+
+/// Sends a message over the given transport.
+pub fn send(transport: &mut impl Transport, msg: &impl Serialize) -> Result<(), CommError> {
+    let bytes = bincode_options().serialize(msg)?;
+    transport.send(&bytes)?;
+
+    Ok(())
+}
+
+/// Receives a message from the given transport. If the message is larger than
+/// `MAX_MESSAGE_SIZE`, an error is returned rather than trying to read it.
+pub fn receive<T: DeserializeOwned>(transport: &mut impl Transport) -> Result<T, CommError> {
+    let bytes = transport.recv()?;
+
+    // The whole message has already arrived, so if it can't be decoded, that's down to what was
+    // sent rather than the connection, even if bincode ran out of bytes to read.
+    bincode_options()
+        .deserialize(&bytes)
+        .map_err(|err| CommError::Malformed(err.to_string()))
+}
+
+/// The characters used in join codes. Letters that are easily mistaken for digits (I, L, O and U)
+/// are left out so that codes are easy to read out loud.
+const JOIN_CODE_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+/// The number of characters in a join code. Each character holds five bits, and the address and
+/// port take up 48 bits between them.
+const JOIN_CODE_LEN: usize = 10;
+
+/// Returns a short code that encodes the given address, which players can use to join a game
+/// instead of typing out the address and port.
+pub fn join_code(addr: SocketAddrV4) -> String {
+    let value = (u64::from(u32::from(*addr.ip())) << 16) | u64::from(addr.port());
+
+    let mut code: String = (0..JOIN_CODE_LEN)
+        .rev()
+        .map(|i| JOIN_CODE_ALPHABET[((value >> (i * 5)) & 0x1f) as usize] as char)
+        .collect();
+
+    // Split the code in half to make it easier to read.
+    code.insert(JOIN_CODE_LEN / 2, '-');
+    code
+}
+
+/// Decodes the address from a join code made by `join_code`. Dashes and case are ignored, and
+/// the letters that aren't used in codes are read as the digits they look like.
+pub fn parse_join_code(code: &str) -> Result<SocketAddrV4, String> {
+    let mut value = 0u64;
+    let mut len = 0;
+
+    for c in code.chars().filter(|&c| c != '-') {
+        let c = match c.to_ascii_uppercase() {
+            'O' => '0',
+            'I' | 'L' => '1',
+            c => c,
+        };
+
+        let digit = JOIN_CODE_ALPHABET
+            .iter()
+            .position(|&x| x as char == c)
+            .ok_or_else(|| format!("'{}' is not a valid join code", code))?;
+
+        value = (value << 5) | digit as u64;
+        len += 1;
+    }
+
+    // Codes have a couple of bits more room than an address needs, and those bits are never set.
+    if len != JOIN_CODE_LEN || value >> 48 != 0 {
+        return Err(format!("'{}' is not a valid join code", code));
+    }
+
+    let ip = Ipv4Addr::from((value >> 16) as u32);
+    let port = (value & 0xffff) as u16;
+
+    Ok(SocketAddrV4::new(ip, port))
+}
+
+/// A connection that messages can be sent over as blocks of bytes.
+///
+/// Each call to `send` on one end of the connection corresponds to exactly one call to `recv` on
+/// the other end, so implementations need to keep track of where each message starts and ends.
+///
+/// The server talks to several connections at once from different threads, so connections have
+/// to be able to move between threads.
+pub trait Transport: Send {
+    /// Sends the given bytes to the other end of the connection as a single message.
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Waits for the next message from the other end of the connection and returns its bytes.
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+
+    /// Sets how long `recv` waits for a message to start arriving before giving up with a
+    /// timeout error. `None` means that it waits forever.
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Returns the address of the other end of the connection, if it has one.
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        // TCP just gives us a stream of bytes, so prefix each message with its length so that the
+        // other end knows where the message ends.
+        let len = u32::try_from(bytes.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long"))?;
+
+        // Write the length and the message in one go so they don't end up in separate packets.
+        let mut frame = Vec::with_capacity(4 + bytes.len());
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.extend_from_slice(bytes);
+
+        self.write_all(&frame)
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        // The timeout is only for the message to start arriving, so we only wait for the first
+        // byte with it. If we gave up halfway through a message (even halfway through its
+        // length), we'd lose track of where the next one starts.
+        let mut len = [0; 4];
+
+        loop {
+            match self.read(&mut len[..1]) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "connection closed",
+                    ))
+                }
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        TcpStream::set_read_timeout(self, None)?;
+        self.read_exact(&mut len[1..])?;
+
+        // Check the length before allocating anything, so that a bogus length can't make us
+        // allocate a huge buffer.
+        let len = u32::from_le_bytes(len);
+
+        if u64::from(len) > MAX_MESSAGE_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message of {} bytes is too large", len),
+            ));
+        }
+
+        let mut bytes = vec![0; len as usize];
+        self.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+}
+
+/// One end of an in-process connection, which lets a client and server talk to each other
+/// without going through the network.
+pub struct ChannelTransport {
+    sender: mpsc::Sender<Vec<u8>>,
+    receiver: mpsc::Receiver<Vec<u8>>,
+    timeout: Option<Duration>,
+}
+
+/// Creates both ends of an in-process connection. Messages sent on one end are received on the
+/// other.
+pub fn channel_pair() -> (ChannelTransport, ChannelTransport) {
+    let (a_sender, b_receiver) = mpsc::channel();
+    let (b_sender, a_receiver) = mpsc::channel();
+
+    let a = ChannelTransport {
+        sender: a_sender,
+        receiver: a_receiver,
+        timeout: None,
+    };
+
+    let b = ChannelTransport {
+        sender: b_sender,
+        receiver: b_receiver,
+        timeout: None,
+    };
+
+    (a, b)
+}
+
+impl Transport for ChannelTransport {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sender
+            .send(bytes.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "other end was dropped"))
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => {
+                return self.receiver.recv().map_err(|_| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "other end was dropped")
+                })
+            }
+        };
+
+        self.receiver
+            .recv_timeout(timeout)
+            .map_err(|err| match err {
+                mpsc::RecvTimeoutError::Timeout => {
+                    io::Error::new(io::ErrorKind::TimedOut, "no message arrived in time")
+                }
+
+                mpsc::RecvTimeoutError::Disconnected => {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "other end was dropped")
+                }
+            })
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+/// One end of a connection with a script rather than a client on the other end. The script's
+/// messages are received in order, and everything sent over the connection is kept so that it can
+/// be checked afterwards. Once the script runs out, the connection acts as if the other end has
+/// gone.
+pub struct ScriptedTransport {
+    replies: VecDeque<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+}
+
+impl ScriptedTransport {
+    /// Creates a connection on which the given messages are received, in order.
+    pub fn new(replies: impl IntoIterator<Item = CtsMessage>) -> ScriptedTransport {
+        let replies = replies
+            .into_iter()
+            .map(|msg| {
+                bincode_options()
+                    .serialize(&msg)
+                    .expect("messages can always be encoded")
+            })
+            .collect();
+
+        ScriptedTransport {
+            replies,
+            sent: vec![],
+        }
+    }
+
+    /// Returns every message that has been sent over the connection, in order.
+    pub fn sent(&self) -> Vec<StcMessage> {
+        self.sent
+            .iter()
+            .filter_map(|bytes| bincode_options().deserialize(bytes).ok())
+            .collect()
+    }
+}
+
+impl Transport for ScriptedTransport {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.sent.push(bytes.to_vec());
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.replies
+            .pop_front()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "the script has run out"))
+    }
+
+    // The script's messages are all there from the start, so there's never anything to wait for.
+    fn set_recv_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
 
 /// The role of a player in the game.
 #[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
 pub enum Role {
     Wolf,
     Villager,
+
+    /// A villager who, when voted out, takes another player down with them.
+    Bomber,
+
+    /// A villager who, if killed by a wolf, takes that wolf down in revenge.
+    Knight,
+
+    /// A villager whom the village is punished for voting out.
+    Saint,
+
+    /// A villager who can find out whether another player is a werewolf each night.
+    Seer,
+
+    /// A villager who becomes the seer when the seer dies.
+    ApprenticeSeer,
+
+    /// A villager who can protect one player from the werewolves each night.
+    Doctor,
+
+    /// A villager who, however they die, gets to shoot another player as they go.
+    Hunter,
+
+    /// A villager with one potion that saves the wolves' victim and one that poisons someone.
+    Witch,
+
+    /// A villager who can guard someone other than themselves from the werewolves each night,
+    /// but never the same player two nights in a row.
+    Bodyguard,
+
+    /// A villager who picks two players to fall in love on the first night. If one of the lovers
+    /// dies, the other dies of a broken heart.
+    Cupid,
+
+    /// A player on nobody's side, who wins on their own if the village votes them out.
+    Jester,
+
+    /// A player on the wolves' side who knows who the wolves are, but isn't one. The wolves
+    /// don't know who the minion is, and the minion can't kill.
+    Minion,
+
+    /// A villager who shows their role instead of dying the first time the village votes them
+    /// out. The village can still vote them out again later.
+    Prince,
+
+    /// A wolf whose death enrages the rest of the pack, so that they kill two players the next
+    /// night instead of one.
+    WolfCub,
+}
+
+/// What a witch does with their potions on one night.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum WitchAction {
+    /// Use the healing potion to save the player that the wolves are about to kill.
+    Heal,
+
+    /// Use the poison on the player with the given ID.
+    Poison(PlayerId),
+
+    /// Keep both potions for another night.
+    Pass,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Role, String> {
+        match s.to_lowercase().as_str() {
+            "wolf" | "werewolf" => Ok(Role::Wolf),
+            "villager" => Ok(Role::Villager),
+            "bomber" => Ok(Role::Bomber),
+            "knight" => Ok(Role::Knight),
+            "saint" | "blessed" => Ok(Role::Saint),
+            "seer" => Ok(Role::Seer),
+            "apprentice" | "apprentice-seer" | "apprenticeseer" => Ok(Role::ApprenticeSeer),
+            "doctor" => Ok(Role::Doctor),
+            "hunter" => Ok(Role::Hunter),
+            "witch" => Ok(Role::Witch),
+            "bodyguard" => Ok(Role::Bodyguard),
+            "cupid" => Ok(Role::Cupid),
+            "jester" | "tanner" => Ok(Role::Jester),
+            "minion" => Ok(Role::Minion),
+            "prince" => Ok(Role::Prince),
+            "wolfcub" | "wolf-cub" | "cub" => Ok(Role::WolfCub),
+            _ => Err(format!("'{}' is not a role", s)),
+        }
+    }
+}
+
+/// The side of the game that a player is on.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum Alignment {
+    Wolf,
+    Village,
+}
+
+impl Role {
+    /// Returns `true` if a player with this role is one of the wolves, who wake up together to
+    /// kill someone each night.
+    pub fn is_wolf(self) -> bool {
+        matches!(self, Role::Wolf | Role::WolfCub)
+    }
+
+    /// Returns the side of the game that a player with this role is on.
+    pub fn alignment(self) -> Alignment {
+        match self {
+            Role::Wolf | Role::WolfCub | Role::Minion => Alignment::Wolf,
+            Role::Villager
+            | Role::Bomber
+            | Role::Knight
+            | Role::Saint
+            | Role::Seer
+            | Role::ApprenticeSeer
+            | Role::Doctor
+            | Role::Hunter
+            | Role::Witch
+            | Role::Bodyguard
+            | Role::Cupid
+            | Role::Jester
+            | Role::Prince => Alignment::Village,
+        }
+    }
+}
+
+/// The colour that a player's name is shown in.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Debug)]
+pub enum Colour {
+    Blue,
+    Cyan,
+    Magenta,
+    Yellow,
+    Green,
+    Red,
+    White,
+}
+
+impl Colour {
+    /// Every colour that a player can have.
+    pub const ALL: [Colour; 7] = [
+        Colour::Blue,
+        Colour::Cyan,
+        Colour::Magenta,
+        Colour::Yellow,
+        Colour::Green,
+        Colour::Red,
+        Colour::White,
+    ];
+}
+
+impl std::str::FromStr for Colour {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Colour, String> {
+        match s.to_lowercase().as_str() {
+            "blue" => Ok(Colour::Blue),
+            "cyan" => Ok(Colour::Cyan),
+            "magenta" | "purple" => Ok(Colour::Magenta),
+            "yellow" => Ok(Colour::Yellow),
+            "green" => Ok(Colour::Green),
+            "red" => Ok(Colour::Red),
+            "white" => Ok(Colour::White),
+            _ => Err(format!("'{}' is not a colour", s)),
+        }
+    }
+}
+
+/// What the players are told about the roles in play at the start of the game.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum Setup {
+    /// Every role that has been given out, including duplicates. The roles aren't in the same
+    /// order as the players, so this doesn't give away who has which role.
+    Full(Vec<Role>),
+
+    /// Only the number of players who have been given a special role, not counting the wolf.
+    Partial(usize),
+
+    /// Nothing at all about which roles are in play.
+    Hidden,
 }
 
 /// The side that won when the game is over.
@@ -12,10 +553,21 @@ pub enum Role {
 pub enum Winner {
     Wolf,
     Village,
+
+    /// A werewolf and a villager who fell in love, and outlived everyone else.
+    Lovers,
+
+    /// The jester, who got the village to vote them out.
+    Jester,
+
+    /// The lone wolf, who outlived the rest of the pack as well as the village.
+    LoneWolf,
 }
 
 /// A unique identifier for a player within a room.
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(
+    Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
 pub struct PlayerId(usize);
 
 impl PlayerId {
@@ -28,65 +580,1281 @@ impl PlayerId {
     }
 }
 
+impl fmt::Display for PlayerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A secret given to each player when they join, which lets them take their seat again if they
+/// lose their connection.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Token(u64);
+
+impl Token {
+    /// Creates a new token that can't be guessed.
+    pub fn random() -> Token {
+        Token(rand::random())
+    }
+}
+
+/// Returns the code that the player with the given ID and token can use to rejoin the game.
+pub fn rejoin_code(id: PlayerId, token: Token) -> String {
+    format!("{}-{:016x}", id.0, token.0)
+}
+
+/// Reads the player ID and token from a code made by `rejoin_code`.
+pub fn parse_rejoin_code(code: &str) -> Result<(PlayerId, Token), String> {
+    let invalid = || format!("'{}' is not a valid rejoin code", code);
+
+    let (id, token) = code.trim().split_once('-').ok_or_else(invalid)?;
+    let id = id.parse().map_err(|_| invalid())?;
+    let token = u64::from_str_radix(token, 16).map_err(|_| invalid())?;
+
+    Ok((PlayerId(id), Token(token)))
+}
+
+/// The part of the game that is being played.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+pub enum Phase {
+    /// The game hasn't started yet.
+    Lobby,
+
+    /// The given night, counting from one.
+    Night(usize),
+
+    /// The day after the given night.
+    Day(usize),
+
+    /// The game is over.
+    Over,
+}
+
+/// The reasons the server can give for not letting a player use the name they asked for.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum NameRejection {
+    /// The name is empty, or only has whitespace in it.
+    Empty,
+
+    /// The name has more than `MAX_NAME_LEN` characters.
+    TooLong,
+
+    /// Another player in the game already has the name, or one that only differs in case.
+    Taken,
+
+    /// The name has control characters in it, which could mess up other players' terminals.
+    ControlCharacters,
+
+    /// The game has already started, and names can't change until it's over.
+    GameStarted,
+}
+
+impl fmt::Display for NameRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NameRejection::Empty => write!(f, "names can't be empty"),
+            NameRejection::TooLong => {
+                write!(f, "names can't be longer than {} characters", MAX_NAME_LEN)
+            }
+            NameRejection::Taken => write!(f, "someone else already has that name"),
+            NameRejection::ControlCharacters => {
+                write!(f, "names can't have control characters in them")
+            }
+            NameRejection::GameStarted => {
+                write!(f, "names can't be changed once the game has started")
+            }
+        }
+    }
+}
+
+/// Something that happened in the game that every player saw.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Debug)]
+pub enum PublicEvent {
+    /// The given night began, counting from one.
+    NightFell(usize),
+
+    /// Player A voted against player B.
+    Voted(PlayerId, PlayerId),
+
+    /// The player with the given ID died.
+    Died(PlayerId),
+}
+
+/// Everything a client needs to know to catch up with a game that it's joining partway through.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub struct GameSnapshot {
+    /// The part of the game being played.
+    pub phase: Phase,
+
+    /// The IDs of the players who are still alive.
+    pub living: Vec<PlayerId>,
+
+    /// Everything that every player has seen happen so far, in order.
+    pub history: Vec<PublicEvent>,
+}
+
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub enum CtsMessage {
-    /// A message containing the player's name. This should be sent immediately after the client
-    /// connects to the server. The server should reply with the player's ID.
-    Connect(String),
+    /// Says which version of the protocol the client speaks. This must be the first message the
+    /// client sends. If the server speaks a different version, it replies with
+    /// `VersionMismatch`. Otherwise it doesn't reply, and the client goes on to pick a room.
+    ///
+    /// This has to stay the first variant so that every version encodes it in the same way.
+    Hello(u32),
+
+    /// Asks the server to open a new room for a game. This should be sent immediately after
+    /// `Hello`. The server should reply with `RoomCreated`.
+    CreateRoom,
+
+    /// Asks to enter the room with the given code. An empty code means the only room on the
+    /// server, if there's just one. Like `CreateRoom`, this should be sent immediately after
+    /// `Hello`. The server should reply with `RoomJoined` or `NoSuchRoom`.
+    JoinRoom(String),
+
+    /// A message containing the player's name and the colour they would like their name shown in,
+    /// if they have a preference. This should be sent as soon as the client is in a room. The
+    /// server should reply with the player's ID and colour.
+    Connect(String, Option<Colour>),
+
+    /// Asks to watch the game without playing in it. Like `Connect`, this should be sent as soon
+    /// as the client is in a room. The server should reply with
+    /// `Spectating`.
+    Spectate,
+
+    /// Asks to take back the seat of the player with the given ID, using the token they were
+    /// given when they joined. Like `Connect`, this should be sent as soon as the client is in a
+    /// room. The server should reply with `Reconnected` or `RejoinRejected`.
+    Reconnect(PlayerId, Token),
+
+    /// The host wants to start the game now. This is only accepted as a reply to `AskToStart`.
+    StartGame,
+
+    /// Whether the player is ready for the game to start. This answers `AskReady`, but
+    /// `Ready(false)` can also be sent unprompted during the countdown to call the start off.
+    Ready(bool),
+
+    /// The player wants to be known by a different name. This is sent unprompted, and is only
+    /// accepted in the lobby, before the game starts.
+    Rename(String),
+
+    /// Whether the player wants to play another game once this one is over.
+    PlayAgain(bool),
 
     /// A vote against the player with the given ID.
     Vote(PlayerId),
 
+    /// The player doesn't want to vote against anyone.
+    Abstain,
+
     /// A wolf's victim's ID.
     Kill(PlayerId),
 
-    /// Acknowledges receipt of a message from the server. The server should wait to receive this
-    /// before sending any more messages to ensure that everything is sent in order.
+    /// The ID of the player a bomber is taking down with them.
+    TakeDown(PlayerId),
+
+    /// The ID of the player a hunter is shooting as they die.
+    HunterShoot(PlayerId),
+
+    /// A vote to investigate the player with the given ID.
+    Investigate(PlayerId),
+
+    /// The ID of the player the seer wants to inspect.
+    Inspect(PlayerId),
+
+    /// The ID of the player the doctor wants to protect.
+    Protect(PlayerId),
+
+    /// The ID of the player the bodyguard wants to guard.
+    Guard(PlayerId),
+
+    /// What the witch wants to do with their potions tonight.
+    WitchAction(WitchAction),
+
+    /// The IDs of the two players that cupid wants to fall in love.
+    ChooseLovers(PlayerId, PlayerId),
+
+    /// Something the player wants to say to everyone during the day's discussion. An empty
+    /// message means that they have nothing to say.
+    Chat(String),
+
+    /// Something a wolf wants to say to the other wolves during the night. An empty message
+    /// means that they have nothing to say.
+    WolfChatSend(String),
+
+    /// Something a dead player wants to say to the other dead players. An empty message means
+    /// that they have nothing to say.
+    DeadChat(String),
+
+    /// What the player wants to say to everyone as they die. An empty message means that they
+    /// have nothing to say.
+    LastWords(String),
+
+    /// Answers a prompt without making a decision, such as when the player is dead or the host
+    /// doesn't want to start the game yet. Only prompts are answered, so this is never sent in
+    /// reply to anything else.
     Received,
+
+    /// Answers a `Ping` to show that the client is still there. This is sent as soon as the
+    /// `Ping` arrives, even if the player is in the middle of deciding something, so it isn't a
+    /// reply to any prompt.
+    Pong,
 }
 
 #[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
 pub enum StcMessage {
+    /// The server speaks a different version of the protocol to the client, so they can't play
+    /// together. The server closes the connection straight after sending this.
+    ///
+    /// This has to stay the first variant so that every version encodes it in the same way.
+    VersionMismatch { server: u32, client: u32 },
+
+    /// A new room has been opened for the recipient, and other players can join it with the
+    /// given code. The recipient is the first into the room, so they'll be its host.
+    RoomCreated(String),
+
+    /// The recipient is now in the room with the given code.
+    RoomJoined(String),
+
+    /// There's no room with the given code. The server closes the connection straight after
+    /// sending this.
+    NoSuchRoom(String),
+
     /// The wolves have woken up and are going to vote on who to kill.
     WolvesWake,
 
     /// The game is entering a night.
     NightFalls,
 
+    /// The recipient has nothing to do tonight, and can sleep until the night is over.
+    SleepTight,
+
     /// The ID of the player who died last night.
     Died(PlayerId),
 
+    /// The ID of the player who was poisoned by the witch last night.
+    Poisoned(PlayerId),
+
+    /// The IDs of the players that cupid can choose two lovers from.
+    ChooseLovers(Vec<PlayerId>),
+
+    /// The recipient has fallen in love with the player with the given ID. If either of them
+    /// dies, so does the other.
+    LoverIs(PlayerId),
+
+    /// The ID of a player who died of a broken heart because their lover died.
+    Heartbroken(PlayerId),
+
+    /// Nobody died last night.
+    PeacefulNight,
+
     /// The IDs of the players that can be voted against.
-    VoteOptions(Vec<PlayerId>),
+    VoteOptions {
+        candidates: Vec<PlayerId>,
+
+        /// Whether the player may abstain instead of voting against anyone.
+        can_abstain: bool,
+    },
 
     /// The IDs of the players that can be killed by a wolf.
     KillOptions(Vec<PlayerId>),
 
+    /// The IDs of the players that the doctor can protect tonight.
+    ProtectOptions(Vec<PlayerId>),
+
+    /// The IDs of the players that the bodyguard can guard tonight. The player they guarded last
+    /// night is left out.
+    GuardOptions(Vec<PlayerId>),
+
+    /// The bodyguard chose a player they aren't allowed to guard, so they guard nobody tonight.
+    GuardRejected,
+
+    /// Whether the player that the recipient protected or guarded tonight was attacked by the
+    /// wolves, so that the protection saved them. Only the doctor or bodyguard who chose them is
+    /// sent this, and it doesn't say which wolf attacked.
+    ProtectionResult(bool),
+
+    /// Whether the recipient's poison killed the player they gave it to. It doesn't if that
+    /// player had already died some other way by the time it took effect. Only the witch who
+    /// used the poison is sent this.
+    PoisonResult(bool),
+
+    /// The recipient chose a player they weren't offered, so they're about to be asked again.
+    InvalidChoice,
+
+    /// Asks the witch what they want to do with their potions tonight. The witch is told who the
+    /// wolves are about to kill, if anyone, and which potions they can use. If they can use the
+    /// poison, `targets` holds the IDs of the players they can use it on.
+    WitchPrompt {
+        dying: Option<PlayerId>,
+        can_heal: bool,
+        can_poison: bool,
+        targets: Vec<PlayerId>,
+    },
+
+    /// The IDs of the players that the seer can inspect tonight.
+    InspectOptions(Vec<PlayerId>),
+
+    /// The player with the given ID, whom the seer chose to inspect, has the given role. Only the
+    /// seer is sent this.
+    InspectResult(PlayerId, Role),
+
+    /// The seer has died, so the recipient, who was their apprentice, is the seer from now on.
+    YouAreNowSeer,
+
+    /// The IDs of the players that the village can vote to investigate.
+    InvestigateOptions(Vec<PlayerId>),
+
+    /// The village investigated the player with the given ID and found them to be on the given
+    /// side. The village has the given number of investigations left.
+    Investigated(PlayerId, Alignment, usize),
+
+    /// The recipient is a bomber who has just died, and can take down one of the players with the
+    /// given IDs.
+    BomberPrompt(Vec<PlayerId>),
+
+    /// Player A, a bomber, took player B down with them.
+    TakenDown(PlayerId, PlayerId),
+
+    /// The recipient is a hunter who has just died, and can shoot one of the players with the
+    /// given IDs.
+    HunterShootOptions(Vec<PlayerId>),
+
+    /// Player A, a hunter, shot player B as they died.
+    Shot(PlayerId, PlayerId),
+
+    /// The wolf with the given ID died in revenge for killing the knight.
+    KnightRevenge(PlayerId),
+
+    /// Asks the recipient, who has just died, if they have any last words for everyone.
+    LastWordsPrompt,
+
+    /// The player with the given ID said the given last words as they died.
+    LastWordsBroadcast(PlayerId, String),
+
+    /// The recipient is the host, who is moving the night along by hand. They should reply with
+    /// `Received` once they're ready for the given step, such as "wake the seer".
+    AskToContinue(String),
+
+    /// The player with the given ID, who was just voted out, was a saint. The village will be
+    /// punished for it.
+    SaintLynched(PlayerId),
+
+    /// Nobody can be voted out today, because the village voted out a saint yesterday.
+    LynchForbidden,
+
+    /// The player with the given ID was voted out, but they're the prince, so they showed their
+    /// role and were let off. This only happens once.
+    PrinceRevealed(PlayerId),
+
+    /// It's the recipient's turn to say something during the day's discussion.
+    ChatPrompt,
+
+    /// Something that the player with the given ID said during the day's discussion.
+    ChatBroadcast(PlayerId, String),
+
+    /// It's the recipient wolf's turn to say something to the other wolves.
+    WolfChatPrompt,
+
+    /// Something that the wolf with the given ID said to the other wolves. Only wolves should
+    /// ever be sent this.
+    WolfChat(PlayerId, String),
+
+    /// It's the recipient's turn to say something to the other dead players.
+    DeadChatPrompt,
+
+    /// Something that the dead player with the given ID said to the other dead players. Only
+    /// the dead should ever be sent this, so that nothing they say reaches the living.
+    DeadChatRelay(PlayerId, String),
+
     /// Player A has voted against player B.
     AnnounceVote(PlayerId, PlayerId),
 
     /// There was not a majority on the vote.
     NoMajority,
 
+    /// How many votes each player got in the day's vote, most first. If there was a runoff, these
+    /// are the votes from the runoff. Players who got no votes are left out.
+    VoteResults(Vec<(PlayerId, usize)>),
+
+    /// The players with the given IDs tied for the most votes, so there will be a runoff between
+    /// them.
+    VoteTie(Vec<PlayerId>),
+
+    /// The player with the given ID didn't vote in time, so their vote wasn't counted.
+    VoteTimedOut(PlayerId),
+
+    /// The player with the given ID chose not to vote against anyone.
+    Abstained(PlayerId),
+
     /// The ID of the player who was just voted out.
     VotedOut(PlayerId),
 
+    /// The ID of a player who lost their connection and is out of the game.
+    PlayerLeft(PlayerId),
+
+    /// The ID of a player who lost their connection, but whose seat is being held for a while in
+    /// case they rejoin. If they don't, they leave the game as usual.
+    PlayerDisconnected(PlayerId),
+
+    /// The ID of a player who rejoined the game in time to keep their seat.
+    PlayerReconnected(PlayerId),
+
     /// The role assigned to the recipient player.
     RoleAssigned(Role),
 
+    /// The wolf cub has died, so the wolves get to kill someone else tonight as well. This is
+    /// only sent to the wolves, just before they're asked who to kill the second time.
+    PackEnraged,
+
+    /// The IDs of the recipient wolf's fellow wolves, sent on the first night. A minion is sent
+    /// this too, with the IDs of every wolf.
+    WolfTeammates(Vec<PlayerId>),
+
+    /// The recipient is the lone wolf, who only wins if they're the last wolf standing when the
+    /// wolves win. Only the lone wolf is sent this, straight after their role.
+    LoneWolf,
+
+    /// Information about which roles are in play in this game.
+    GameSetup(Setup),
+
+    /// The order that the players are sitting in around the table, going clockwise. The last
+    /// player is sitting next to the first.
+    Seating(Vec<PlayerId>),
+
+    /// The game has ended with a win for the given side. Each player's role will be revealed
+    /// before the winner is formally announced.
+    GameEnding(Winner),
+
+    /// The player with the given ID had the given role.
+    RoleReveal(PlayerId, Role),
+
     /// A side has won the game.
     AnnounceWinner(Winner),
 
+    /// The order in which players will be asked for their votes today.
+    VoteOrder(Vec<PlayerId>),
+
     /// The host is waiting for a player to vote.
     WaitingFor(PlayerId),
 
-    /// The ID-username pair for a player that just joined the game.
-    AnnounceJoin(PlayerId, String),
+    /// The ID, username and colour of a player that just joined the game.
+    AnnounceJoin(PlayerId, String, Colour),
+
+    /// The ID assigned to a player who just joined, the colour their name is shown in, and the
+    /// token they can use to rejoin if they lose their connection. The colour is only the one
+    /// they asked for if nobody else already has it.
+    IdAssigned(PlayerId, Colour, Token),
+
+    /// The recipient has taken back their seat after losing their connection. This gives their
+    /// role (if they have one yet), whether they're dead, and the part of the game being played,
+    /// so that they can catch up.
+    Reconnected(Option<Role>, bool, Phase),
+
+    /// The state of the game as the recipient joins it, so that they can catch up with whatever
+    /// they missed. This is sent straight after `IdAssigned`, `Spectating` or `Reconnected`, once
+    /// the recipient has been told who's playing.
+    GameSnapshot(GameSnapshot),
+
+    /// The recipient tried to rejoin with an ID and token that don't match any player. The
+    /// server closes the connection straight after sending this.
+    RejoinRejected,
+
+    /// The recipient can't join, because the game already has as many players as it allows,
+    /// which is the given number. The server closes the connection straight after sending this.
+    GameFull(usize),
+
+    /// The recipient can't watch, because the game already has as many spectators as it allows,
+    /// which is the given number. The server closes the connection straight after sending this.
+    SpectatorLimitReached(usize),
+
+    /// The recipient can't join with the name they gave, for the given reason. Although this
+    /// isn't a prompt, the client has to reply with another `Connect` to try a different name.
+    NameRejected(NameRejection),
+
+    /// The player with the given ID changed their name to the given one in the lobby.
+    PlayerRenamed(PlayerId, String),
+
+    /// The recipient asked to change their name, but can't have the one they asked for, for the
+    /// given reason. They keep the name they had.
+    RenameRejected(NameRejection),
+
+    /// The recipient is the host, and can start the game now. They should reply with `StartGame`
+    /// to start, or `Received` to wait for more players. The flag says whether every player is
+    /// ready, since the host can start without waiting for everyone.
+    AskToStart(bool),
+
+    /// The recipient is the host, but the game can't start until at least the given number of
+    /// players have joined, because there aren't enough of them for the roles the host chose.
+    NotEnoughPlayers(usize),
+
+    /// The recipient should say whether they're ready for the game to start.
+    AskReady,
+
+    /// Whether each player in the lobby is ready for the game to start.
+    LobbyState(Vec<(PlayerId, bool)>),
+
+    /// The game will start once the given amount of time has passed. Until then, the client can
+    /// send `Ready(false)` without being asked to call the start off.
+    StartingIn(Duration),
+
+    /// The countdown to the start of the game was called off, because the player with the given
+    /// ID isn't ready after all.
+    CountdownCancelled(PlayerId),
+
+    /// The host has closed the session, so the client should disconnect.
+    SessionClosed,
+
+    /// The game has finished, and the player is asked whether they want to play another one in
+    /// the same room.
+    GameOver,
+
+    /// The player IDs, usernames and colours that should be sent to a newly-connected client so
+    /// that they can identify players by ID.
+    Players(Vec<(PlayerId, String, Colour)>),
+
+    /// The recipient is watching the game rather than playing in it, and has been given the
+    /// given ID. This is sent instead of `IdAssigned` to anyone who asks to spectate or who tries
+    /// to join after the game has started.
+    Spectating(PlayerId),
+
+    /// Something that happened out of sight of most players. This is only sent to spectators,
+    /// and only if the rules let them see it.
+    Secret(Secret),
+
+    /// Checks that the client is still there. The client answers with `Pong` straight away,
+    /// whatever else it's doing.
+    Ping,
+}
+
+/// The hidden parts of the game that spectators can be shown.
+#[derive(Clone, Serialize, Deserialize, PartialEq, Debug)]
+pub enum Secret {
+    /// The role of every player.
+    Roles(Vec<(PlayerId, Role)>),
+
+    /// The wolves chose to kill the player with the given ID.
+    WolvesChose(PlayerId),
+
+    /// Doctor A chose to protect player B.
+    Protected(PlayerId, PlayerId),
+
+    /// Bodyguard A chose to guard player B.
+    Guarded(PlayerId, PlayerId),
+
+    /// Seer A found out that player B has the given role.
+    Inspected(PlayerId, PlayerId, Role),
+
+    /// The witch with the given ID chose what to do with their potions.
+    Brewed(PlayerId, WitchAction),
+
+    /// Cupid A made players B and C fall in love.
+    Lovers(PlayerId, PlayerId, PlayerId),
+}
+
+impl StcMessage {
+    /// Returns `true` if the message asks the user to make a decision, rather than just telling
+    /// them something.
+    pub fn is_prompt(&self) -> bool {
+        matches!(
+            self,
+            StcMessage::VoteOptions { .. }
+                | StcMessage::KillOptions(_)
+                | StcMessage::BomberPrompt(_)
+                | StcMessage::HunterShootOptions(_)
+                | StcMessage::InvestigateOptions(_)
+                | StcMessage::InspectOptions(_)
+                | StcMessage::ProtectOptions(_)
+                | StcMessage::GuardOptions(_)
+                | StcMessage::ChooseLovers(_)
+                | StcMessage::WitchPrompt { .. }
+                | StcMessage::ChatPrompt
+                | StcMessage::WolfChatPrompt
+                | StcMessage::DeadChatPrompt
+                | StcMessage::LastWordsPrompt
+                | StcMessage::AskToContinue(_)
+                | StcMessage::AskToStart(_)
+                | StcMessage::AskReady
+                | StcMessage::GameOver
+        )
+    }
+
+    /// Returns `true` if the message is meant for the wolves' eyes only.
+    pub fn is_wolves_only(&self) -> bool {
+        matches!(
+            self,
+            StcMessage::WolfChatPrompt | StcMessage::WolfChat(..) | StcMessage::PackEnraged
+        )
+    }
+
+    /// Returns `true` if the message is meant for the dead players' eyes only.
+    pub fn is_dead_only(&self) -> bool {
+        matches!(
+            self,
+            StcMessage::DeadChatPrompt | StcMessage::DeadChatRelay(..)
+        )
+    }
+}
+
+// The protocol goes through the same stages on every connection:
+//
+// 1. The client connects and sends `Hello` with its protocol version, which the server only
+//    replies to (with `VersionMismatch`) if it speaks a different version. The client then sends
+//    `CreateRoom` or `JoinRoom`. The server replies with `RoomCreated` or `RoomJoined`, and the
+//    client is handed over to that room's game. Everything after this happens within the room.
+// 2. The client sends `Connect` with the player's name and preferred colour.
+// 3. The server replies with `IdAssigned`, and the client is now in the lobby. While in the lobby,
+//    the client is told about other players joining and the countdown to the game starting. The
+//    players are asked whether they're ready, and the host (the first player to join) is asked
+//    whether to start.
+// 4. The server sends `RoleAssigned`, and the game begins. Nights and days are played out until
+//    one side wins.
+// 5. The server sends `GameEnding`, then reveals everyone's role and announces the winner.
+// 6. The server sends `GameOver` to ask each player whether they want to play again. Players who
+//    answer `PlayAgain(true)` go back to the lobby (stage 3) with everyone else who stayed, and
+//    are sent `Players` with the new line-up.
+// 7. Everyone else is sent `SessionClosed`, and the client disconnects.
+//
+// A client that lost its connection can send `Reconnect` instead of `Connect` to take back its
+// seat. The server replies with `Reconnected`, which says what stage the game is at, and the
+// client carries on from there.
+//
+// A client can send `Spectate` instead of `Connect` to watch the game. The server replies with
+// `Spectating`, and from then on the client is sent everything that isn't a prompt, whatever
+// stage the game is at.
+//
+// Apart from the messages that start a connection off (`Hello`, `CreateRoom`, `JoinRoom`,
+// `Connect`, `Spectate` and `Reconnect`), every message from the client is a reply to a prompt
+// from the server (see `StcMessage::is_prompt`), or a new `Connect` after `NameRejected`. Every
+// other message from the server is just information, and the client doesn't reply to it. TCP
+// already keeps messages in order, so the server can send these without waiting for the client
+// to catch up, and one slow client can't hold everyone else up.
+//
+// A prompt is always answered, either with a decision or with `Received` if the client has
+// nothing to decide. The server may give up waiting for an answer, in which case the answer is
+// still owed, and it arrives before the answer to the next prompt.
+//
+// While the server is waiting for an answer, it sends `Ping` whenever the client has been quiet
+// for a while, and the client answers with `Pong` however long the player is taking to decide.
+// A client that doesn't answer in time is treated as disconnected, so a connection that died
+// without being closed can't leave the server waiting forever. `Pong` doesn't answer any prompt,
+// so the server skips over it when looking for answers.
+//
+// The states below spell this out so that each end can check that what it receives makes sense
+// at that point in the conversation.
+
+/// The stages that a connection goes through, as seen by the client.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ClientState {
+    /// The client has asked for a room, and is waiting to find out whether it got in.
+    ChoosingRoom,
+
+    /// The client has sent its name, and is waiting for the server to give it an ID.
+    Connecting,
+
+    /// The client has joined, and is waiting for the game to start.
+    Lobby,
+
+    /// The game is being played.
+    Playing,
+
+    /// The game is over, and the roles and winner are being revealed.
+    GameOver,
+
+    /// The client is watching the game, so it can be told anything that doesn't need a decision.
+    Spectating,
+
+    /// The session has been closed by the server, so nothing more will be sent.
+    Closed,
+}
+
+impl ClientState {
+    /// Returns the state that the client moves into when it receives the given message, or `None`
+    /// if the message shouldn't be sent to a client in this state.
+    pub fn received(self, msg: &StcMessage) -> Option<ClientState> {
+        use ClientState::*;
+
+        match (self, msg) {
+            // The server can close the session at any point.
+            (Closed, _) => None,
+            (_, StcMessage::SessionClosed) => Some(Closed),
+
+            (ChoosingRoom, StcMessage::RoomCreated(_) | StcMessage::RoomJoined(_)) => {
+                Some(Connecting)
+            }
+            (ChoosingRoom, StcMessage::NoSuchRoom(_) | StcMessage::VersionMismatch { .. }) => {
+                Some(Closed)
+            }
+
+            (Connecting, StcMessage::IdAssigned(..)) => Some(Lobby),
+            (Connecting, StcMessage::Spectating(_)) => Some(Spectating),
+            (Connecting, StcMessage::Reconnected(_, _, phase)) => Some(match phase {
+                Phase::Lobby => Lobby,
+                Phase::Night(_) | Phase::Day(_) => Playing,
+                Phase::Over => GameOver,
+            }),
+            (
+                Connecting,
+                StcMessage::RejoinRejected
+                | StcMessage::GameFull(_)
+                | StcMessage::SpectatorLimitReached(_),
+            ) => Some(Closed),
+            (Connecting, StcMessage::NameRejected(_)) => Some(Connecting),
+
+            // Anyone who rejoins is told who's playing again and what they missed, whatever stage
+            // the game is at.
+            (Lobby | Playing | GameOver, StcMessage::Players(_) | StcMessage::GameSnapshot(_)) => {
+                Some(self)
+            }
+            (Spectating, msg) if !msg.is_prompt() => Some(Spectating),
+
+            (
+                Lobby,
+                StcMessage::AnnounceJoin(..)
+                | StcMessage::AskToStart(_)
+                | StcMessage::NotEnoughPlayers(_)
+                | StcMessage::AskReady
+                | StcMessage::LobbyState(_)
+                | StcMessage::StartingIn(_)
+                | StcMessage::CountdownCancelled(_)
+                | StcMessage::PlayerRenamed(..)
+                | StcMessage::RenameRejected(_),
+            ) => Some(Lobby),
+            (Lobby, StcMessage::RoleAssigned(_)) => Some(Playing),
 
-    /// The ID assigned to a player who just joined.
-    IdAssigned(PlayerId),
+            (
+                Playing,
+                StcMessage::GameSetup(_)
+                | StcMessage::Seating(_)
+                | StcMessage::NightFalls
+                | StcMessage::SleepTight
+                | StcMessage::WolvesWake
+                | StcMessage::WolfTeammates(_)
+                | StcMessage::LoneWolf
+                | StcMessage::PackEnraged
+                | StcMessage::KillOptions(_)
+                | StcMessage::InspectOptions(_)
+                | StcMessage::InspectResult(..)
+                | StcMessage::YouAreNowSeer
+                | StcMessage::ProtectOptions(_)
+                | StcMessage::GuardOptions(_)
+                | StcMessage::GuardRejected
+                | StcMessage::ProtectionResult(_)
+                | StcMessage::PoisonResult(_)
+                | StcMessage::InvalidChoice
+                | StcMessage::ChooseLovers(_)
+                | StcMessage::LoverIs(_)
+                | StcMessage::Heartbroken(_)
+                | StcMessage::WitchPrompt { .. }
+                | StcMessage::Died(_)
+                | StcMessage::Poisoned(_)
+                | StcMessage::PeacefulNight
+                | StcMessage::PlayerLeft(_)
+                | StcMessage::PlayerDisconnected(_)
+                | StcMessage::PlayerReconnected(_)
+                | StcMessage::ChatPrompt
+                | StcMessage::ChatBroadcast(..)
+                | StcMessage::VoteTimedOut(_)
+                | StcMessage::Abstained(_)
+                | StcMessage::VoteTie(_)
+                | StcMessage::WolfChatPrompt
+                | StcMessage::WolfChat(..)
+                | StcMessage::DeadChatPrompt
+                | StcMessage::DeadChatRelay(..)
+                | StcMessage::LastWordsPrompt
+                | StcMessage::LastWordsBroadcast(..)
+                | StcMessage::AskToContinue(_)
+                | StcMessage::BomberPrompt(_)
+                | StcMessage::TakenDown(..)
+                | StcMessage::HunterShootOptions(_)
+                | StcMessage::Shot(..)
+                | StcMessage::KnightRevenge(_)
+                | StcMessage::SaintLynched(_)
+                | StcMessage::LynchForbidden
+                | StcMessage::PrinceRevealed(_)
+                | StcMessage::VoteOrder(_)
+                | StcMessage::WaitingFor(_)
+                | StcMessage::VoteOptions { .. }
+                | StcMessage::InvestigateOptions(_)
+                | StcMessage::Investigated(..)
+                | StcMessage::AnnounceVote(..)
+                | StcMessage::NoMajority
+                | StcMessage::VoteResults(_)
+                | StcMessage::VotedOut(_),
+            ) => Some(Playing),
+            (Playing, StcMessage::GameEnding(_)) => Some(GameOver),
 
-    /// A player IDs and usernames that should be sent to a newly-connected client so that they can
-    /// identify players by ID.
-    Players(Vec<(PlayerId, String)>),
+            (GameOver, StcMessage::RoleReveal(..) | StcMessage::AnnounceWinner(_)) => {
+                Some(GameOver)
+            }
+
+            // Once the player has said whether they want to play again, they're either back in
+            // the lobby or about to be disconnected.
+            (GameOver, StcMessage::GameOver) => Some(Lobby),
+
+            _ => None,
+        }
+    }
+}
+
+/// The stages that a connection goes through, as seen by the server.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ServerState {
+    /// The client has connected, but hasn't told us the player's name yet.
+    Connecting,
+
+    /// Nothing sent to the client needs a reply.
+    Idle,
+
+    /// Waiting for the client to vote against someone.
+    AwaitingVote,
+
+    /// Waiting for the client to choose who to kill.
+    AwaitingKill,
+
+    /// Waiting for the client to choose who to take down.
+    AwaitingTakeDown,
+
+    /// Waiting for the client to choose who to shoot.
+    AwaitingShot,
+
+    /// Waiting for the client to vote on who to investigate.
+    AwaitingInvestigate,
+
+    /// Waiting for the client to choose who to inspect.
+    AwaitingInspect,
+
+    /// Waiting for the client to choose who to protect.
+    AwaitingProtect,
+
+    /// Waiting for the client to choose who to guard.
+    AwaitingGuard,
+
+    /// Waiting for the client to choose two lovers.
+    AwaitingLovers,
+
+    /// Waiting for the client to decide what to do with their potions.
+    AwaitingWitch,
+
+    /// Waiting for the client to say something in the discussion.
+    AwaitingChat,
+
+    /// Waiting for the client to say something to the other wolves.
+    AwaitingWolfChat,
+
+    /// Waiting for the client to say something to the other dead players.
+    AwaitingDeadChat,
+
+    /// Waiting for the client to say their last words.
+    AwaitingLastWords,
+
+    /// Waiting for the host's client to say that the night can go on.
+    AwaitingContinue,
+
+    /// Waiting for the host's client to decide whether to start the game.
+    AwaitingStart,
+
+    /// Waiting for the client to say whether they're ready.
+    AwaitingReady,
+
+    /// Waiting for the client to say whether they want to play again.
+    AwaitingPlayAgain,
+}
+
+impl ServerState {
+    /// Returns the state that the server is in after sending the given message.
+    pub fn sent(msg: &StcMessage) -> ServerState {
+        match msg {
+            StcMessage::VoteOptions { .. } => ServerState::AwaitingVote,
+            StcMessage::KillOptions(_) => ServerState::AwaitingKill,
+            StcMessage::BomberPrompt(_) => ServerState::AwaitingTakeDown,
+            StcMessage::HunterShootOptions(_) => ServerState::AwaitingShot,
+            StcMessage::InvestigateOptions(_) => ServerState::AwaitingInvestigate,
+            StcMessage::InspectOptions(_) => ServerState::AwaitingInspect,
+            StcMessage::ProtectOptions(_) => ServerState::AwaitingProtect,
+            StcMessage::GuardOptions(_) => ServerState::AwaitingGuard,
+            StcMessage::ChooseLovers(_) => ServerState::AwaitingLovers,
+            StcMessage::WitchPrompt { .. } => ServerState::AwaitingWitch,
+            StcMessage::ChatPrompt => ServerState::AwaitingChat,
+            StcMessage::WolfChatPrompt => ServerState::AwaitingWolfChat,
+            StcMessage::DeadChatPrompt => ServerState::AwaitingDeadChat,
+            StcMessage::LastWordsPrompt => ServerState::AwaitingLastWords,
+            StcMessage::AskToContinue(_) => ServerState::AwaitingContinue,
+            StcMessage::AskToStart(_) => ServerState::AwaitingStart,
+            StcMessage::AskReady => ServerState::AwaitingReady,
+            StcMessage::GameOver => ServerState::AwaitingPlayAgain,
+            _ => ServerState::Idle,
+        }
+    }
+
+    /// Returns the state that the server moves into when it receives the given message, or `None`
+    /// if the client shouldn't have sent the message in this state.
+    ///
+    /// A prompt can be answered with the matching decision, or with `Received` by a client that
+    /// has nothing to decide (such as one whose player is already dead).
+    pub fn received(self, msg: &CtsMessage) -> Option<ServerState> {
+        use ServerState::*;
+
+        match (self, msg) {
+            (
+                Connecting,
+                CtsMessage::Connect(..) | CtsMessage::Spectate | CtsMessage::Reconnect(..),
+            )
+            | (
+                AwaitingVote | AwaitingKill | AwaitingTakeDown | AwaitingShot | AwaitingInvestigate
+                | AwaitingInspect | AwaitingProtect | AwaitingGuard | AwaitingLovers
+                | AwaitingWitch | AwaitingChat | AwaitingWolfChat | AwaitingDeadChat
+                | AwaitingLastWords | AwaitingContinue | AwaitingStart | AwaitingPlayAgain,
+                CtsMessage::Received,
+            )
+            | (AwaitingVote, CtsMessage::Vote(_) | CtsMessage::Abstain)
+            | (AwaitingInvestigate, CtsMessage::Investigate(_))
+            | (AwaitingInspect, CtsMessage::Inspect(_))
+            | (AwaitingProtect, CtsMessage::Protect(_))
+            | (AwaitingGuard, CtsMessage::Guard(_))
+            | (AwaitingLovers, CtsMessage::ChooseLovers(..))
+            | (AwaitingWitch, CtsMessage::WitchAction(_))
+            | (AwaitingChat, CtsMessage::Chat(_))
+            | (AwaitingWolfChat, CtsMessage::WolfChatSend(_))
+            | (AwaitingDeadChat, CtsMessage::DeadChat(_))
+            | (AwaitingLastWords, CtsMessage::LastWords(_))
+            | (AwaitingStart, CtsMessage::StartGame)
+            | (AwaitingReady, CtsMessage::Ready(_))
+            | (AwaitingPlayAgain, CtsMessage::PlayAgain(_))
+            | (AwaitingKill, CtsMessage::Kill(_))
+            | (AwaitingTakeDown, CtsMessage::TakeDown(_))
+            | (AwaitingShot, CtsMessage::HunterShoot(_)) => Some(Idle),
+
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns both ends of a TCP connection over the loopback interface.
+    fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let a = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (b, _) = listener.accept().unwrap();
+
+        (a, b)
+    }
+
+    #[test]
+    fn tcp_timeout_only_applies_to_the_start_of_a_message() {
+        let (mut a, mut b) = tcp_pair();
+        b.set_recv_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        let bytes = bincode_options()
+            .serialize(&StcMessage::NightFalls)
+            .unwrap();
+        let mut frame = (bytes.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&bytes);
+
+        // Send half of the length, and the rest well after the timeout would have run out.
+        a.write_all(&frame[..2]).unwrap();
+
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            a.write_all(&frame[2..]).unwrap();
+            a
+        });
+
+        assert_eq!(
+            receive::<StcMessage>(&mut b).unwrap(),
+            StcMessage::NightFalls
+        );
+
+        // The stream is still in step, so the next message arrives intact.
+        let mut a = writer.join().unwrap();
+        send(&mut a, &StcMessage::NoMajority).unwrap();
+        b.set_recv_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(
+            receive::<StcMessage>(&mut b).unwrap(),
+            StcMessage::NoMajority
+        );
+    }
+
+    #[test]
+    fn channel_messages_arrive_in_order() {
+        let (mut a, mut b) = channel_pair();
+
+        send(&mut a, &StcMessage::NightFalls).unwrap();
+        send(&mut a, &StcMessage::Died(PlayerId::new().next())).unwrap();
+        send(&mut b, &CtsMessage::Received).unwrap();
+
+        assert_eq!(
+            receive::<StcMessage>(&mut b).unwrap(),
+            StcMessage::NightFalls
+        );
+        assert_eq!(
+            receive::<StcMessage>(&mut b).unwrap(),
+            StcMessage::Died(PlayerId::new().next())
+        );
+        assert_eq!(receive::<CtsMessage>(&mut a).unwrap(), CtsMessage::Received);
+    }
+
+    #[test]
+    fn channel_times_out() {
+        let (mut a, _b) = channel_pair();
+        a.set_recv_timeout(Some(Duration::from_millis(10))).unwrap();
+
+        assert!(matches!(
+            receive::<StcMessage>(&mut a),
+            Err(CommError::TimedOut)
+        ));
+    }
+
+    #[test]
+    fn channel_notices_the_other_end_dropping() {
+        let (mut a, b) = channel_pair();
+        drop(b);
+
+        assert!(matches!(
+            receive::<StcMessage>(&mut a),
+            Err(CommError::Disconnected(_))
+        ));
+        assert!(matches!(
+            send(&mut a, &CtsMessage::Received),
+            Err(CommError::Disconnected(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let (mut a, mut b) = tcp_pair();
+
+        // Only the length is sent. If it were believed, the read would wait for the rest of the
+        // message forever.
+        let len = u32::try_from(MAX_MESSAGE_SIZE + 1).unwrap();
+        a.write_all(&len.to_le_bytes()).unwrap();
+
+        assert!(matches!(
+            receive::<CtsMessage>(&mut b),
+            Err(CommError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn frame_at_the_limit_is_accepted() {
+        let (mut a, mut b) = tcp_pair();
+        let bytes = vec![7; MAX_MESSAGE_SIZE as usize];
+
+        a.send(&bytes).unwrap();
+
+        assert_eq!(Transport::recv(&mut b).unwrap(), bytes);
+    }
+
+    #[test]
+    fn oversized_collection_is_rejected_without_allocating_it() {
+        let (mut a, mut b) = channel_pair();
+
+        // A small message whose length field claims that a huge list follows.
+        a.send(&u64::MAX.to_le_bytes()).unwrap();
+
+        assert!(matches!(
+            receive::<Vec<PlayerId>>(&mut b),
+            Err(CommError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn join_codes_round_trip() {
+        for addr in [
+            SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 20), 57079),
+            SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0),
+            SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), 65535),
+        ] {
+            let code = join_code(addr);
+
+            assert_eq!(code.len(), JOIN_CODE_LEN + 1);
+            assert_eq!(parse_join_code(&code), Ok(addr));
+        }
+    }
+
+    #[test]
+    fn join_codes_are_forgiving_to_type() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 57079);
+        let code = join_code(addr);
+
+        // Dashes and case don't matter, and letters that look like digits are read as them.
+        let typed = code
+            .replace('-', "")
+            .to_lowercase()
+            .replace('0', "o")
+            .replace('1', "l");
+
+        assert_eq!(parse_join_code(&typed), Ok(addr));
+    }
+
+    #[test]
+    fn bad_join_codes_are_rejected() {
+        for code in ["", "12345", "ABCDE-FGHJK-M", "ABCDE-FGHJ!", "ZZZZZ-ZZZZZ"] {
+            assert!(parse_join_code(code).is_err(), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn rejoin_codes_round_trip() {
+        let id = PlayerId::new().next().next();
+        let token = Token::random();
+
+        assert_eq!(parse_rejoin_code(&rejoin_code(id, token)), Ok((id, token)));
+        assert_eq!(
+            parse_rejoin_code(&format!("  {}\n", rejoin_code(id, token))),
+            Ok((id, token))
+        );
+    }
+
+    #[test]
+    fn bad_rejoin_codes_are_rejected() {
+        for code in ["", "2", "2-", "-00ff", "two-00ff", "2-nothex"] {
+            assert!(parse_rejoin_code(code).is_err(), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn client_follows_a_whole_game() {
+        let id = PlayerId::new();
+
+        let messages = [
+            StcMessage::RoomJoined("ABCD".to_string()),
+            StcMessage::IdAssigned(id, Colour::Red, Token::random()),
+            StcMessage::AskReady,
+            StcMessage::RoleAssigned(Role::Villager),
+            StcMessage::NightFalls,
+            StcMessage::Died(id.next()),
+            StcMessage::VoteOptions {
+                candidates: vec![id.next().next()],
+                can_abstain: false,
+            },
+            StcMessage::GameEnding(Winner::Village),
+            StcMessage::AnnounceWinner(Winner::Village),
+            StcMessage::GameOver,
+            StcMessage::SessionClosed,
+        ];
+
+        let end = messages
+            .iter()
+            .try_fold(ClientState::ChoosingRoom, |state, msg| {
+                state.received(msg).ok_or((state, msg))
+            });
+
+        assert_eq!(end, Ok(ClientState::Closed));
+    }
+
+    #[test]
+    fn client_refuses_out_of_place_messages() {
+        let id = PlayerId::new();
+
+        for (state, msg) in [
+            (ClientState::ChoosingRoom, StcMessage::NightFalls),
+            (ClientState::Connecting, StcMessage::KillOptions(vec![id])),
+            (ClientState::Lobby, StcMessage::VotedOut(id)),
+            (ClientState::Playing, StcMessage::AskReady),
+            (ClientState::GameOver, StcMessage::NightFalls),
+            (ClientState::Spectating, StcMessage::ChatPrompt),
+            (ClientState::Closed, StcMessage::SessionClosed),
+        ] {
+            assert_eq!(state.received(&msg), None, "{:?} in {:?}", msg, state);
+        }
+    }
+
+    #[test]
+    fn server_accepts_only_the_answer_to_its_prompt() {
+        let id = PlayerId::new();
+
+        for (prompt, answer) in [
+            (
+                StcMessage::VoteOptions {
+                    candidates: vec![id],
+                    can_abstain: true,
+                },
+                CtsMessage::Abstain,
+            ),
+            (StcMessage::KillOptions(vec![id]), CtsMessage::Kill(id)),
+            (
+                StcMessage::ProtectOptions(vec![id]),
+                CtsMessage::Protect(id),
+            ),
+            (StcMessage::GuardOptions(vec![id]), CtsMessage::Guard(id)),
+            (StcMessage::BomberPrompt(vec![id]), CtsMessage::TakeDown(id)),
+            (StcMessage::AskReady, CtsMessage::Ready(true)),
+            (
+                StcMessage::DeadChatPrompt,
+                CtsMessage::DeadChat("boo".to_string()),
+            ),
+        ] {
+            let state = ServerState::sent(&prompt);
+
+            assert_eq!(state.received(&answer), Some(ServerState::Idle));
+            assert_eq!(state.received(&CtsMessage::Chat("hi".to_string())), None);
+
+            // A vote doesn't answer anything but a ballot.
+            if !matches!(prompt, StcMessage::VoteOptions { .. }) {
+                assert_eq!(state.received(&CtsMessage::Vote(id)), None, "{:?}", prompt);
+            }
+        }
+    }
+
+    #[test]
+    fn server_accepts_an_acknowledgement_in_place_of_a_decision() {
+        let id = PlayerId::new();
+
+        assert_eq!(
+            ServerState::sent(&StcMessage::KillOptions(vec![id])).received(&CtsMessage::Received),
+            Some(ServerState::Idle)
+        );
+        assert_eq!(
+            ServerState::sent(&StcMessage::NightFalls).received(&CtsMessage::Kill(id)),
+            None
+        );
+    }
+
+    #[test]
+    fn tcp_messages_round_trip_in_order() {
+        let (mut server, mut client) = tcp_pair();
+        let id = PlayerId::new();
+
+        let sent = [
+            StcMessage::NightFalls,
+            StcMessage::KillOptions(vec![id, id.next()]),
+            StcMessage::ChatBroadcast(id, "a fairly long message ".repeat(100)),
+            StcMessage::Died(id.next()),
+        ];
+
+        // The client answers in between the server's messages, so both directions are in use at
+        // once.
+        for msg in &sent {
+            send(&mut server, msg).unwrap();
+            send(&mut client, &CtsMessage::Received).unwrap();
+        }
+
+        for msg in &sent {
+            assert_eq!(&receive::<StcMessage>(&mut client).unwrap(), msg);
+        }
+
+        for _ in &sent {
+            assert_eq!(
+                receive::<CtsMessage>(&mut server).unwrap(),
+                CtsMessage::Received
+            );
+        }
+    }
+
+    #[test]
+    fn garbled_message_doesnt_throw_off_the_next_one() {
+        let (mut a, mut b) = tcp_pair();
+
+        a.send(&[0xff; 16]).unwrap();
+        send(&mut a, &CtsMessage::Received).unwrap();
+
+        assert!(matches!(
+            receive::<CtsMessage>(&mut b),
+            Err(CommError::Malformed(_))
+        ));
+        assert_eq!(receive::<CtsMessage>(&mut b).unwrap(), CtsMessage::Received);
+    }
 }
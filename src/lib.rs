@@ -0,0 +1,15 @@
+//! A game of werewolf played over the network.
+//!
+//! The `wolf` binary is a thin layer over this library that reads its settings from the command
+//! line. The game itself doesn't depend on TCP: anything that implements `comm::Transport` can
+//! connect a player to a game, so a whole game can be played in-process with
+//! `server::play_game`, using `comm::channel_pair` for the connections and `bot::play` for the
+//! players.
+
+pub mod bot;
+pub mod client;
+pub mod comm;
+pub mod log;
+pub mod record;
+pub mod server;
+pub mod stats;
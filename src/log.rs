@@ -0,0 +1,184 @@
+//! A small logging framework for diagnostics that shouldn't be mixed in with the game's normal
+//! output.
+//!
+//! Log messages go to stderr, and are filtered by level and by target (the module that logged the
+//! message, unless another target is given). The filter comes from the `RUST_LOG` environment
+//! variable if it's set, in the same format as `env_logger`: a comma-separated list of directives
+//! that are either a level (`debug`), which applies to every target, or `target=level`
+//! (`wolf::server=trace`), which applies to that target and anything under it. Otherwise, only
+//! warnings and errors are shown, with each `-v` on the command line showing one more level.
+
+use std::{fmt, sync::OnceLock};
+
+/// How important a log message is.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Parses a level name. `off` is parsed as `Some(None)`, meaning that nothing should be
+    /// logged.
+    fn parse(s: &str) -> Option<Option<Level>> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Some(None),
+            "error" => Some(Some(Level::Error)),
+            "warn" => Some(Some(Level::Warn)),
+            "info" => Some(Some(Level::Info)),
+            "debug" => Some(Some(Level::Debug)),
+            "trace" => Some(Some(Level::Trace)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        })
+    }
+}
+
+/// Decides which messages get logged.
+struct Filter {
+    /// The most detailed level logged for targets without a directive of their own. `None` means
+    /// that nothing is logged.
+    default: Option<Level>,
+
+    /// Targets with their own maximum levels.
+    targets: Vec<(String, Option<Level>)>,
+}
+
+impl Filter {
+    /// Parses a filter from a `RUST_LOG`-style string. Directives that can't be parsed are
+    /// ignored.
+    fn parse(spec: &str) -> Filter {
+        let mut filter = Filter {
+            default: None,
+            targets: vec![],
+        };
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Some(level) = Level::parse(level) {
+                        filter.targets.push((target.trim().to_string(), level));
+                    }
+                }
+
+                None => {
+                    if let Some(level) = Level::parse(directive) {
+                        filter.default = level;
+                    }
+                }
+            }
+        }
+
+        filter
+    }
+
+    /// Returns `true` if a message at the given level for the given target should be logged.
+    fn enabled(&self, level: Level, target: &str) -> bool {
+        // The most specific directive (the one with the longest target) that covers this target
+        // wins.
+        let max_level = self
+            .targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || target
+                        .strip_prefix(prefix.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |&(_, level)| level);
+
+        max_level.is_some_and(|max| level <= max)
+    }
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+
+/// Sets up logging. If `RUST_LOG` isn't set, the given verbosity (the number of times `-v` was
+/// passed) decides how much is logged.
+///
+/// Anything logged before this is called is dropped.
+pub fn init(verbosity: u64) {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(spec) => Filter::parse(&spec),
+        Err(_) => Filter {
+            default: Some(match verbosity {
+                0 => Level::Warn,
+                1 => Level::Info,
+                2 => Level::Debug,
+                _ => Level::Trace,
+            }),
+            targets: vec![],
+        },
+    };
+
+    // If logging has already been set up, the first setup sticks.
+    let _ = FILTER.set(filter);
+}
+
+/// Logs a message at the given level for the given target. The logging macros should be used
+/// instead of calling this directly.
+pub fn log(level: Level, target: &str, args: fmt::Arguments) {
+    let enabled = FILTER
+        .get()
+        .is_some_and(|filter| filter.enabled(level, target));
+
+    if enabled {
+        eprintln!("[{} {}] {}", level, target, args);
+    }
+}
+
+/// Logs a message at the given level. The target defaults to the current module, but can be set
+/// with `target: "..."` before the message.
+macro_rules! log_at {
+    ($level:expr, target: $target:expr, $($arg:tt)+) => {
+        $crate::log::log($level, $target, format_args!($($arg)+))
+    };
+
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+/// Logs something that has gone wrong.
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Error, $($arg)+) };
+}
+
+/// Logs something that is probably wrong, but that we can carry on from.
+// This can't be called `warn` directly because that name is taken by the built-in lint attribute,
+// so we rename it when we export it instead.
+macro_rules! warning {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Warn, $($arg)+) };
+}
+
+/// Logs something that's useful to know about while things are working normally.
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Info, $($arg)+) };
+}
+
+/// Logs details that are useful for debugging.
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Debug, $($arg)+) };
+}
+
+/// Logs every detail, such as the contents of every message.
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log::log_at!($crate::log::Level::Trace, $($arg)+) };
+}
+
+pub(crate) use {debug, error, info, log_at, trace, warning as warn};
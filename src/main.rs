@@ -1,43 +1,681 @@
-use clap::{Arg, Command};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
-mod client;
-mod comm;
-mod server;
+use clap::{Arg, ArgMatches, Command};
+use wolf::{client, comm, log, record::GameRecord, server};
 
 fn main() {
     let res = Command::new("werewolf")
+        .subcommand_required(true)
+        .arg_required_else_help(true)
         .arg(
-            Arg::new("host")
-                .required_unless_present("ip")
-                .long("host")
-                .help("Hosts a game"),
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .multiple_occurrences(true)
+                .global(true)
+                .help("Log more detail to stderr (repeat for even more), unless RUST_LOG is set"),
         )
+        .subcommand(host_command())
+        .subcommand(serve_command())
+        .subcommand(join_command())
+        .subcommand(replay_command())
+        .get_matches();
+
+    match res.subcommand() {
+        Some(("host", res)) => host(res),
+        Some(("serve", res)) => serve(res),
+        Some(("join", res)) => join(res),
+        Some(("replay", res)) => replay(res),
+        _ => unreachable!("clap requires a known subcommand"),
+    }
+}
+
+/// Returns the `host` subcommand, which hosts a game and joins it.
+fn host_command() -> Command<'static> {
+    server_args(Command::new("host").about("Hosts a game and joins it"))
+        .args(client_args())
         .arg(
-            Arg::new("ip")
-                .required_unless_present("host")
+            Arg::new("bots")
                 .takes_value(true)
-                .long("ip")
-                .help("IP address of the game to connect to (if not hosting)"),
+                .default_value("0")
+                .long("bots")
+                .help("Number of bots to fill the game with, for testing or small groups"),
         )
+}
+
+/// Returns the `serve` subcommand, which hosts games for other players without joining any.
+fn serve_command() -> Command<'static> {
+    server_args(
+        Command::new("serve").about("Hosts games in rooms that players create, until stopped"),
+    )
+}
+
+/// Adds the arguments for hosting games to the given command.
+fn server_args(command: Command<'static>) -> Command<'static> {
+    command
+        .arg(port_arg())
         .arg(
-            Arg::new("port")
+            Arg::new("bind")
                 .takes_value(true)
-                .default_value("57079")
-                .long("port")
-                .short('p')
-                .help("Port to host on or connect to (optional)"),
+                .default_value("0.0.0.0")
+                .long("bind")
+                .help("Address to host on; use 127.0.0.1 to only allow this machine (optional)"),
         )
-        .get_matches();
+        .arg(
+            Arg::new("start-countdown")
+                .takes_value(true)
+                .default_value("5")
+                .long("start-countdown")
+                .help("Seconds to count down before a hosted game starts (optional)"),
+        )
+        .arg(
+            Arg::new("seed")
+                .takes_value(true)
+                .long("seed")
+                .help("Seed for a hosted game's random decisions (optional)"),
+        )
+        .arg(
+            Arg::new("mode")
+                .takes_value(true)
+                .default_value("classic")
+                .long("mode")
+                .help("Preset roles and rules: classic, seer-doctor or chaos (optional)"),
+        )
+        .arg(
+            Arg::new("roles")
+                .takes_value(true)
+                .use_value_delimiter(true)
+                .long("roles")
+                .help("Comma-separated special roles to use instead of the mode's (optional)"),
+        )
+        .arg(
+            Arg::new("players-per-wolf")
+                .takes_value(true)
+                .long("players-per-wolf")
+                .help("Number of players for each wolf in a hosted game (optional)"),
+        )
+        .arg(
+            Arg::new("role-config")
+                .takes_value(true)
+                .long("role-config")
+                .help("JSON file giving how many of each role to use by player count (optional)"),
+        )
+        .arg(
+            Arg::new("bomber-at-night")
+                .long("bomber-at-night")
+                .help("Let a bomber killed at night take someone down, not just a voted-out one"),
+        )
+        .arg(
+            Arg::new("godfather")
+                .long("godfather")
+                .help("Make one of the wolves look like a villager to the seer"),
+        )
+        .arg(
+            Arg::new("lone-wolf")
+                .long("lone-wolf")
+                .help("Make one of the wolves a lone wolf who only wins as the last wolf standing"),
+        )
+        .arg(
+            Arg::new("allow-self-vote")
+                .long("allow-self-vote")
+                .help("Let players vote against themselves during the day"),
+        )
+        .arg(
+            Arg::new("no-abstain")
+                .long("no-abstain")
+                .help("Make every player vote against someone during the day"),
+        )
+        .arg(
+            Arg::new("skip-first-night")
+                .long("skip-first-night")
+                .help("Start the game with a day instead of a night"),
+        )
+        .arg(
+            Arg::new("knight-immediate")
+                .long("knight-immediate")
+                .help("Kill a wolf who kills the knight straight away instead of the next day"),
+        )
+        .arg(
+            Arg::new("saint-penalty")
+                .takes_value(true)
+                .long("saint-penalty")
+                .help("What voting out a saint costs the village: lose or skip-next-lynch"),
+        )
+        .arg(
+            Arg::new("doctor-self-protect")
+                .long("doctor-self-protect")
+                .help("Let the doctor choose to protect themselves"),
+        )
+        .arg(
+            Arg::new("bodyguard-dies")
+                .long("bodyguard-dies")
+                .help("Make a bodyguard who guards the werewolves' victim die in their place"),
+        )
+        .arg(
+            Arg::new("spectators-see-secrets")
+                .long("spectators-see-secrets")
+                .help("Show spectators everyone's roles and what happens at night"),
+        )
+        .arg(
+            Arg::new("seating")
+                .long("seating")
+                .help("Seat players around a table in join order and show their neighbours"),
+        )
+        .arg(
+            Arg::new("max-players")
+                .takes_value(true)
+                .long("max-players")
+                .help("Most players that can join a game, or 0 for no limit (default 20)"),
+        )
+        .arg(
+            Arg::new("max-spectators")
+                .takes_value(true)
+                .long("max-spectators")
+                .help("Most spectators that can watch a game at once, or 0 for no limit (default)"),
+        )
+        .arg(
+            Arg::new("vote-timeout")
+                .takes_value(true)
+                .long("vote-timeout")
+                .help("Seconds each player has to vote, or 0 for no limit (default 60)"),
+        )
+        .arg(
+            Arg::new("reconnect-grace")
+                .takes_value(true)
+                .long("reconnect-grace")
+                .help("Seconds a player who drops out mid-game has to rejoin (default 0)"),
+        )
+        .arg(
+            Arg::new("discussion")
+                .takes_value(true)
+                .long("discussion")
+                .help("Seconds the village can talk for before each vote (optional)"),
+        )
+        .arg(
+            Arg::new("wolf-chat")
+                .takes_value(true)
+                .long("wolf-chat")
+                .help("Seconds the wolves can talk among themselves each night (optional)"),
+        )
+        .arg(
+            Arg::new("dead-chat")
+                .takes_value(true)
+                .long("dead-chat")
+                .help("Seconds the dead can talk among themselves each night (optional)"),
+        )
+        .arg(
+            Arg::new("last-words")
+                .takes_value(true)
+                .long("last-words")
+                .help("Seconds players killed or voted out get to say their last words (optional)"),
+        )
+        .arg(
+            Arg::new("village-investigations")
+                .takes_value(true)
+                .long("village-investigations")
+                .help("Times the village can vote to learn a player's side (optional)"),
+        )
+        .arg(
+            Arg::new("manual-night")
+                .long("manual-night")
+                .help("Wait for the host to press enter before each step of the night"),
+        )
+        .arg(
+            Arg::new("wolf-win-rule")
+                .takes_value(true)
+                .long("wolf-win-rule")
+                .help("When the wolves win: parity or majority (optional)"),
+        )
+        .arg(
+            Arg::new("reveal-setup")
+                .takes_value(true)
+                .long("reveal-setup")
+                .help("What players are told about the roles in play: full, partial or none"),
+        )
+        .arg(
+            Arg::new("reveal-delay")
+                .takes_value(true)
+                .long("reveal-delay")
+                .help("Milliseconds to pause between role reveals at the end (optional)"),
+        )
+        .arg(
+            Arg::new("stats")
+                .takes_value(true)
+                .long("stats")
+                .help("File to append a hosted game's statistics to (optional)"),
+        )
+        .arg(
+            Arg::new("log-dir")
+                .takes_value(true)
+                .long("log-dir")
+                .help("Directory to save a JSON record of each finished game in (optional)"),
+        )
+        .arg(
+            Arg::new("post-game-timeout")
+                .takes_value(true)
+                .default_value("60")
+                .long("post-game-timeout")
+                .help("Seconds players have to say whether they'll play again, or 0 for no limit"),
+        )
+        .arg(
+            Arg::new("heartbeat")
+                .takes_value(true)
+                .default_value("15")
+                .long("heartbeat")
+                .help(
+                    "Seconds of quiet before checking that a player is still there (0 for never)",
+                ),
+        )
+        .arg(
+            Arg::new("fixed-vote-order")
+                .long("fixed-vote-order")
+                .help("Ask players for their votes in the order they joined instead of shuffling"),
+        )
+        .arg(
+            Arg::new("replay")
+                .takes_value(true)
+                .long("replay")
+                .help("Replay a game record against this build instead of hosting (optional)"),
+        )
+}
+
+/// Returns the `join` subcommand, which joins a game that someone else is hosting.
+fn join_command() -> Command<'static> {
+    Command::new("join")
+        .about("Joins a game that someone else is hosting")
+        .arg(
+            Arg::new("address")
+                .required(true)
+                .help("IP address or join code of the game to join"),
+        )
+        .arg(port_arg())
+        .arg(
+            Arg::new("room")
+                .takes_value(true)
+                .long("room")
+                .help("Code of the room to join, if the server has more than one (optional)"),
+        )
+        .arg(
+            Arg::new("new-room")
+                .long("new-room")
+                .conflicts_with_all(&["room", "spectate", "rejoin"])
+                .help("Open a new room on the server and host its game"),
+        )
+        .arg(
+            Arg::new("spectate")
+                .long("spectate")
+                .conflicts_with_all(&["name", "colour"])
+                .help("Watch the game without playing in it"),
+        )
+        .arg(
+            Arg::new("rejoin")
+                .takes_value(true)
+                .long("rejoin")
+                .conflicts_with_all(&["name", "colour", "spectate"])
+                .help("Take back your seat using the rejoin code you were given (optional)"),
+        )
+        .args(client_args())
+}
+
+/// Returns the `replay` subcommand, which prints what happened in a recorded game.
+fn replay_command() -> Command<'static> {
+    Command::new("replay")
+        .about("Prints what happened in a game recorded with --log-dir")
+        .arg(
+            Arg::new("file")
+                .required(true)
+                .help("Path of the game record to print"),
+        )
+}
+
+/// Returns the argument for the port to host on or connect to.
+fn port_arg() -> Arg<'static> {
+    Arg::new("port")
+        .takes_value(true)
+        .default_value("57079")
+        .long("port")
+        .short('p')
+        .help("Port to host on or connect to (optional)")
+}
+
+/// Returns the arguments that change how the game is shown to the user, which apply both when
+/// hosting and when joining.
+fn client_args() -> [Arg<'static>; 9] {
+    [
+        Arg::new("name")
+            .takes_value(true)
+            .forbid_empty_values(true)
+            .long("name")
+            .help("Name to join with, instead of being asked for one (optional)"),
+        Arg::new("colour")
+            .takes_value(true)
+            .long("colour")
+            .alias("color")
+            .help("Colour to show your name in, if nobody else has it (optional)"),
+        Arg::new("dramatic")
+            .long("dramatic")
+            .help("Type out the end of the game slowly for dramatic effect"),
+        Arg::new("screen-reader")
+            .long("screen-reader")
+            .help("Show plain, linear output that works well with screen readers"),
+        Arg::new("no-color")
+            .long("no-color")
+            .alias("no-colour")
+            .help("Don't colour the output (also turned on by setting NO_COLOR)"),
+        Arg::new("symbols").long("symbols").help(
+            "Mark names with @ and messages for you with *, so they stand out without colour",
+        ),
+        Arg::new("menu-order")
+            .takes_value(true)
+            .default_value("join")
+            .long("menu-order")
+            .help("Order of players in menus: join or alphabetical (optional)"),
+        Arg::new("timestamps")
+            .long("timestamps")
+            .help("Start each line with how long it's been since you connected"),
+        Arg::new("transcript")
+            .takes_value(true)
+            .long("transcript")
+            .help("File to add a plain copy of everything shown to (optional)"),
+    ]
+}
+
+/// Hosts a game with the options given to the `host` subcommand, and joins it.
+fn host(res: &ArgMatches) {
+    log::init(res.occurrences_of("verbose"));
+
+    if let Some(path) = res.value_of("replay") {
+        return check_replay(path.as_ref(), res);
+    }
+
+    let (addr, rooms) = server::start(
+        res.value_of_t_or_exit("bind"),
+        res.value_of_t_or_exit("port"),
+        host_config(res),
+    );
+
+    // Even though we're hosting the game, we need to connect to the server. Our client opens
+    // the room that everyone else joins.
+    let mut config = client_config(res);
+    config.bots = res.value_of_t_or_exit("bots");
+
+    client::start(addr, config);
+
+    // Our own client can finish before the server has closed the session for everyone else, so
+    // wait for the rooms to close before exiting.
+    rooms.wait();
+}
+
+/// Hosts games with the options given to the `serve` subcommand, without joining any of them.
+fn serve(res: &ArgMatches) {
+    log::init(res.occurrences_of("verbose"));
+
+    if let Some(path) = res.value_of("replay") {
+        return check_replay(path.as_ref(), res);
+    }
+
+    server::start(
+        res.value_of_t_or_exit("bind"),
+        res.value_of_t_or_exit("port"),
+        host_config(res),
+    );
+
+    println!(
+        "Players can open a room with `join --new-room`, and others can join it with `--room`"
+    );
+
+    // The server runs on other threads, and carries on until the process is stopped.
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Joins a game with the options given to the `join` subcommand.
+fn join(res: &ArgMatches) {
+    log::init(res.occurrences_of("verbose"));
+
+    let address = res.value_of("address").unwrap();
+
+    let addr = match address.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, res.value_of_t_or_exit("port")),
+
+        // Anything that isn't an IP address should be a join code, which holds both the address
+        // and the port.
+        Err(_) => comm::parse_join_code(address)
+            .unwrap_or_else(|err| {
+                clap::Error::raw(clap::ErrorKind::InvalidValue, format!("{}\n", err)).exit()
+            })
+            .into(),
+    };
 
-    let port: u16 = res.value_of_t_or_exit("port");
+    let rejoin = res.value_of("rejoin").map(|code| {
+        comm::parse_rejoin_code(code).unwrap_or_else(|err| {
+            clap::Error::raw(clap::ErrorKind::InvalidValue, format!("{}\n", err)).exit()
+        })
+    });
 
-    let game_address = if res.is_present("host") {
-        // Hosting the game, so start a server.
-        server::start(port)
-    } else {
-        std::net::SocketAddr::new(res.value_of_t_or_exit("ip"), port)
+    let config = client::Config {
+        spectate: res.is_present("spectate"),
+        rejoin,
+        bots: 0,
+        room: if res.is_present("new-room") {
+            client::RoomChoice::Create
+        } else {
+            client::RoomChoice::Join(res.value_of("room").unwrap_or_default().into())
+        },
+        ..client_config(res)
     };
 
-    // Even if we're hosting the game, we need to connect to the server.
-    client::start(game_address);
+    client::start(addr, config);
+}
+
+/// Builds the client's configuration from the command line arguments.
+fn client_config(res: &ArgMatches) -> client::Config {
+    client::Config {
+        name: res.value_of("name").map(Into::into),
+        colour: res
+            .is_present("colour")
+            .then(|| res.value_of_t_or_exit("colour")),
+        dramatic: res.is_present("dramatic"),
+        screen_reader: res.is_present("screen-reader"),
+        menu_order: res.value_of_t_or_exit("menu-order"),
+        timestamps: res.is_present("timestamps"),
+        symbols: res.is_present("symbols"),
+        transcript: res.value_of("transcript").map(Into::into),
+
+        // Only the `join` subcommand can spectate or rejoin, since the host's client has to play
+        // and can't lose its connection to a server in the same process.
+        spectate: false,
+        rejoin: None,
+        bots: 0,
+
+        // The host's client opens a new room, which everyone else then joins.
+        room: client::RoomChoice::Create,
+
+        // See https://no-color.org: the variable only counts if it isn't empty.
+        no_colour: res.is_present("no-color")
+            || std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()),
+    }
+}
+
+/// Builds the configuration for hosting a game from the command line arguments.
+///
+/// The game mode provides the starting roles and rules, and any flags given explicitly override
+/// what the mode chose.
+fn host_config(res: &ArgMatches) -> server::Config {
+    let mode: server::Preset = res.value_of_t_or_exit("mode");
+
+    let mut rules = mode.rules();
+    let mut roles = mode.roles();
+
+    if res.is_present("roles") {
+        roles.specials = res.values_of_t_or_exit("roles");
+    }
+
+    if res.is_present("players-per-wolf") {
+        roles.players_per_wolf = res.value_of_t_or_exit("players-per-wolf");
+    }
+
+    if let Some(path) = res.value_of("role-config") {
+        roles.counts = server::RoleSetup::load_counts(path.as_ref()).unwrap_or_else(|err| {
+            clap::Error::raw(clap::ErrorKind::InvalidValue, format!("{}\n", err)).exit()
+        });
+    }
+
+    if res.is_present("fixed-vote-order") {
+        rules.fixed_vote_order = true;
+    }
+
+    if res.is_present("bomber-at-night") {
+        rules.bomber_at_night = true;
+    }
+
+    if res.is_present("godfather") {
+        rules.godfather = true;
+    }
+
+    if res.is_present("lone-wolf") {
+        rules.lone_wolf = true;
+    }
+
+    if res.is_present("allow-self-vote") {
+        rules.allow_self_vote = true;
+    }
+
+    if res.is_present("no-abstain") {
+        rules.allow_no_lynch = false;
+    }
+
+    if res.is_present("skip-first-night") {
+        rules.skip_first_night = true;
+    }
+
+    if res.is_present("knight-immediate") {
+        rules.knight_revenge_immediate = true;
+    }
+
+    if res.is_present("doctor-self-protect") {
+        rules.doctor_self_protect = true;
+    }
+
+    if res.is_present("bodyguard-dies") {
+        rules.bodyguard_dies = true;
+    }
+
+    if res.is_present("spectators-see-secrets") {
+        rules.spectators_see_secrets = true;
+    }
+
+    if res.is_present("seating") {
+        rules.seating = true;
+    }
+
+    if res.is_present("max-players") {
+        rules.max_players = res.value_of_t_or_exit("max-players");
+    }
+
+    if res.is_present("max-spectators") {
+        rules.max_spectators = res.value_of_t_or_exit("max-spectators");
+    }
+
+    if res.is_present("reconnect-grace") {
+        rules.reconnect_grace = Duration::from_secs(res.value_of_t_or_exit("reconnect-grace"));
+    }
+
+    if res.is_present("vote-timeout") {
+        rules.vote_timeout = Duration::from_secs(res.value_of_t_or_exit("vote-timeout"));
+    }
+
+    if res.is_present("discussion") {
+        rules.discussion_time = Duration::from_secs(res.value_of_t_or_exit("discussion"));
+    }
+
+    if res.is_present("wolf-chat") {
+        rules.wolf_chat_time = Duration::from_secs(res.value_of_t_or_exit("wolf-chat"));
+    }
+
+    if res.is_present("dead-chat") {
+        rules.dead_chat_time = Duration::from_secs(res.value_of_t_or_exit("dead-chat"));
+    }
+
+    if res.is_present("last-words") {
+        rules.last_words_time = Duration::from_secs(res.value_of_t_or_exit("last-words"));
+    }
+
+    if res.is_present("village-investigations") {
+        rules.village_investigations = res.value_of_t_or_exit("village-investigations");
+    }
+
+    if res.is_present("manual-night") {
+        rules.manual_night = true;
+    }
+
+    if res.is_present("saint-penalty") {
+        rules.saint_penalty = res.value_of_t_or_exit("saint-penalty");
+    }
+
+    if res.is_present("wolf-win-rule") {
+        rules.wolf_win_rule = res.value_of_t_or_exit("wolf-win-rule");
+    }
+
+    if res.is_present("reveal-setup") {
+        rules.setup_reveal = res.value_of_t_or_exit("reveal-setup");
+    }
+
+    if res.is_present("reveal-delay") {
+        rules.reveal_delay = Duration::from_millis(res.value_of_t_or_exit("reveal-delay"));
+    }
+
+    server::Config {
+        start_countdown: Duration::from_secs(res.value_of_t_or_exit("start-countdown")),
+        seed: res
+            .is_present("seed")
+            .then(|| res.value_of_t_or_exit("seed")),
+        rules,
+        roles,
+        stats_path: res.value_of("stats").map(Into::into),
+        log_dir: res.value_of("log-dir").map(Into::into),
+        post_game_timeout: Duration::from_secs(res.value_of_t_or_exit("post-game-timeout")),
+        heartbeat: Duration::from_secs(res.value_of_t_or_exit("heartbeat")),
+    }
+}
+
+/// Prints the game record named on the command line.
+fn replay(res: &ArgMatches) {
+    log::init(res.occurrences_of("verbose"));
+
+    let path = std::path::Path::new(res.value_of("file").unwrap());
+
+    match GameRecord::read_from(path) {
+        Ok(record) => print!("{}", record),
+        Err(err) => clap::Error::raw(
+            clap::ErrorKind::InvalidValue,
+            format!("Unable to read {}: {}\n", path.display(), err),
+        )
+        .exit(),
+    }
+}
+
+/// Plays the recorded game at the given path again with the rules and roles given on the command
+/// line, and checks that every player is sent the same messages as they were the first time.
+fn check_replay(path: &std::path::Path, res: &ArgMatches) {
+    let record = GameRecord::read_from(path).unwrap_or_else(|err| {
+        clap::Error::raw(
+            clap::ErrorKind::InvalidValue,
+            format!("Unable to read {}: {}\n", path.display(), err),
+        )
+        .exit()
+    });
+
+    match server::replay(&record, &host_config(res)) {
+        Ok(()) => println!("The game played out the same way as it did before."),
+
+        Err(difference) => {
+            eprintln!("The game played out differently: {}", difference);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,288 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::comm::{CtsMessage, PlayerId, Role, StcMessage, Winner};
+
+/// A complete account of a finished game, written out as JSON so that games can be looked back on
+/// (or analysed) later.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GameRecord {
+    /// When the game finished, in seconds since the Unix epoch.
+    pub finished_at: u64,
+
+    /// The seed that the game's random decisions were made with.
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Everyone who played, in the order they joined.
+    pub players: Vec<PlayerRecord>,
+
+    /// What happened each night and the day that followed it, in order.
+    pub rounds: Vec<RoundRecord>,
+
+    /// The side that won the game, if it was finished.
+    pub winner: Option<Winner>,
+}
+
+/// A player's part in a recorded game.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlayerRecord {
+    /// The player's ID, which the rest of the record uses to refer to them.
+    pub id: PlayerId,
+
+    /// The player's name.
+    pub name: String,
+
+    /// The role the player had, if the game got far enough for them to be given one.
+    pub role: Option<Role>,
+
+    /// Whether the player was still alive at the end of the game.
+    pub survived: bool,
+
+    /// Every message that passed between the server and the player's client during the game, in
+    /// order. This is what lets the game be replayed.
+    #[serde(default)]
+    pub transcript: Vec<Message>,
+}
+
+/// A message that passed between the server and a player's client.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Message {
+    /// The server sent the message to the client.
+    Sent(StcMessage),
+
+    /// The server received the message from the client.
+    Received(CtsMessage),
+}
+
+/// A night and the day after it.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct RoundRecord {
+    /// The players who died during the night, including anyone taken down with them when the
+    /// deaths were announced in the morning.
+    pub night_deaths: Vec<DeathRecord>,
+
+    /// The votes cast to get rid of someone during the day. This is empty if the game ended
+    /// before the village could vote.
+    pub votes: Vec<VoteRecord>,
+
+    /// The players who died during the day.
+    pub day_deaths: Vec<DeathRecord>,
+}
+
+/// A single player's death.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeathRecord {
+    /// The player who died.
+    pub player: PlayerId,
+
+    /// How the player died, such as `"killed"` or `"voted out"`.
+    pub cause: String,
+
+    /// The player responsible for the death, if it was down to one player.
+    pub by: Option<PlayerId>,
+}
+
+/// A single vote.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoteRecord {
+    /// The player who voted.
+    pub voter: PlayerId,
+
+    /// The player they voted against.
+    pub target: PlayerId,
+}
+
+impl GameRecord {
+    /// Writes the record to a new JSON file in the given directory, named after the time the game
+    /// finished. The directory is created if it doesn't exist yet.
+    ///
+    /// Returns the path of the file that was written.
+    pub fn write_to(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+
+        // Several rooms could finish in the same second, so rather than overwrite another game's
+        // record, we number the files that would otherwise clash.
+        for n in 1.. {
+            let name = match n {
+                1 => format!("game-{}.json", self.finished_at),
+                n => format!("game-{}-{}.json", self.finished_at, n),
+            };
+
+            let path = dir.join(name);
+
+            let file = match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => file,
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            };
+
+            serde_json::to_writer_pretty(file, self)?;
+            return Ok(path);
+        }
+
+        unreachable!("ran out of file names")
+    }
+
+    /// Reads a record back from a JSON file written by [`GameRecord::write_to`].
+    pub fn read_from(path: &Path) -> std::io::Result<GameRecord> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// Returns the name of the player with the given ID, or the ID itself if they aren't in the
+    /// record.
+    fn name_of(&self, id: PlayerId) -> String {
+        self.players
+            .iter()
+            .find(|p| p.id == id)
+            .map(|p| p.name.clone())
+            .unwrap_or_else(|| id.to_string())
+    }
+
+    /// Writes one line for each death in the list.
+    fn write_deaths(&self, f: &mut fmt::Formatter, deaths: &[DeathRecord]) -> fmt::Result {
+        for death in deaths {
+            match death.by {
+                Some(by) => writeln!(
+                    f,
+                    "    {}: {} by {}",
+                    self.name_of(death.player),
+                    death.cause,
+                    self.name_of(by)
+                )?,
+                None => writeln!(f, "    {}: {}", self.name_of(death.player), death.cause)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for GameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Game finished at {} (Unix time)", self.finished_at)?;
+        writeln!(f, "  Players:")?;
+
+        for player in &self.players {
+            let role = match player.role {
+                Some(role) => format!("{:?}", role),
+                None => "no role".to_string(),
+            };
+
+            let fate = if player.survived { "survived" } else { "died" };
+
+            writeln!(f, "    {} ({}): {}", player.name, role, fate)?;
+        }
+
+        for (n, round) in self.rounds.iter().enumerate() {
+            writeln!(f, "  Round {}", n + 1)?;
+
+            if !round.night_deaths.is_empty() {
+                writeln!(f, "   Night deaths:")?;
+                self.write_deaths(f, &round.night_deaths)?;
+            }
+
+            if !round.votes.is_empty() {
+                writeln!(f, "   Votes:")?;
+
+                for vote in &round.votes {
+                    writeln!(
+                        f,
+                        "    {} voted against {}",
+                        self.name_of(vote.voter),
+                        self.name_of(vote.target)
+                    )?;
+                }
+            }
+
+            if !round.day_deaths.is_empty() {
+                writeln!(f, "   Day deaths:")?;
+                self.write_deaths(f, &round.day_deaths)?;
+            }
+        }
+
+        match self.winner {
+            Some(winner) => writeln!(f, "  Winner: {:?}", winner),
+            None => writeln!(f, "  Winner: none"),
+        }
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a short game in which the village votes out the wolf on the first day.
+    fn record() -> GameRecord {
+        let alice = PlayerId::new();
+        let bob = alice.next();
+        let carol = bob.next();
+
+        let player = |id, name: &str, role, survived| PlayerRecord {
+            id,
+            name: name.to_string(),
+            role: Some(role),
+            survived,
+            transcript: vec![],
+        };
+
+        GameRecord {
+            finished_at: 1_600_000_000,
+            seed: 0,
+            players: vec![
+                player(alice, "Alice", Role::Wolf, false),
+                player(bob, "Bob", Role::Villager, false),
+                player(carol, "Carol", Role::Villager, true),
+            ],
+            rounds: vec![RoundRecord {
+                night_deaths: vec![DeathRecord {
+                    player: bob,
+                    cause: "killed".to_string(),
+                    by: Some(alice),
+                }],
+                votes: vec![VoteRecord {
+                    voter: carol,
+                    target: alice,
+                }],
+                day_deaths: vec![DeathRecord {
+                    player: alice,
+                    cause: "voted out".to_string(),
+                    by: None,
+                }],
+            }],
+            winner: Some(Winner::Village),
+        }
+    }
+
+    #[test]
+    fn records_can_be_read_back_and_replayed() {
+        let dir = std::env::temp_dir().join(format!("wolf-record-test-{}", std::process::id()));
+        let path = record().write_to(&dir).unwrap();
+        let replayed = GameRecord::read_from(&path).unwrap().to_string();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(replayed, record().to_string());
+        assert!(replayed.contains("Bob: killed by Alice"));
+        assert!(replayed.contains("Carol voted against Alice"));
+        assert!(replayed.contains("Alice: voted out"));
+        assert!(replayed.contains("Winner: Village"));
+    }
+}
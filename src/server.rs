@@ -1,366 +1,5576 @@
 use std::{
-    collections::HashMap,
-    io::Write,
-    net::{IpAddr, SocketAddr, TcpStream},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, TcpStream, UdpSocket},
     ops::DerefMut,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use parking_lot::Mutex;
-use rand::Rng;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 
-use crate::comm::{CtsMessage, PlayerId, Role, StcMessage, Winner};
+use crate::{
+    comm::{
+        self, Colour, CommError, CtsMessage, GameSnapshot, NameRejection, Phase, PlayerId,
+        PublicEvent, Role, ScriptedTransport, Secret, ServerState, Setup, StcMessage, Token,
+        Transport, Winner, WitchAction,
+    },
+    log::{debug, error, info, trace, warn},
+    record::{self, DeathRecord, GameRecord, Message, PlayerRecord, RoundRecord, VoteRecord},
+    stats::{GameStats, PlayerStats, Timing},
+};
+
+/// The most characters that a player can say in one go during a discussion. Anything longer is
+/// cut short.
+const MAX_CHAT_LEN: usize = 200;
+
+/// Tidies up something a player wants to say to others. Control characters could mess with other
+/// players' terminals, and there's no need for anyone to write an essay.
+fn clean_chat(text: &str) -> String {
+    text.trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_CHAT_LEN)
+        .collect()
+}
+
+/// A new connection to a room, along with the message that it introduced itself with.
+type Arrival<T> = (T, CtsMessage);
+
+/// How long a new client has to send another name after its first one was rejected. The user has
+/// to type it, so this is generous, but a client that never answers can't hold up the game.
+const RENAME_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often we check whether anyone has backed out while counting down to the start of a game.
+const COUNTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often we check for players renaming themselves while the lobby waits for someone to join.
+const LOBBY_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-pub fn start(port: u16) -> SocketAddr {
-    let addr = SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), port);
+/// The most times that a player is asked to choose again after choosing someone they weren't
+/// offered. After that, they're treated as if they didn't choose anyone.
+const MAX_PICK_ATTEMPTS: usize = 3;
 
-    // Create the listener on the calling thread so that this function blocks until the server is
-    // ready to listen for messages.
-    let listener = std::net::TcpListener::bind(addr).expect("Unable to start server");
+/// Settings chosen by the host for running a game.
+#[derive(Clone)]
+pub struct Config {
+    /// How long to count down for between the host starting the game and the game actually
+    /// starting.
+    pub start_countdown: Duration,
 
-    println!("Hosting on {}", addr);
+    /// The seed to use for the game's random decisions. If this is `None`, a random seed is used.
+    pub seed: Option<u64>,
 
-    std::thread::spawn(move || run_server(listener));
+    /// The rules that the game should be played with.
+    pub rules: GameRules,
 
-    addr
+    /// The roles to hand out to the players.
+    pub roles: RoleSetup,
+
+    /// A file to append the game's statistics to once it's over, if any.
+    pub stats_path: Option<PathBuf>,
+
+    /// A directory to save a JSON record of each finished game to, if any.
+    pub log_dir: Option<PathBuf>,
+
+    /// How long the players have to say whether they want to play again once a game is over.
+    /// Anyone who hasn't answered by then is disconnected. If this is zero, we wait for as long
+    /// as it takes.
+    pub post_game_timeout: Duration,
+
+    /// How long a player's client can be quiet while we're waiting for it before we check that
+    /// it's still there. A client that doesn't answer the check within the same amount of time is
+    /// disconnected. If this is zero, we never check.
+    pub heartbeat: Duration,
 }
 
-fn run_server(listener: std::net::TcpListener) {
-    let mut game = Game::new();
+/// A named combination of roles and rules, so that hosts don't have to put a game together from
+/// individual options.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Preset {
+    /// One wolf against a seer and a village of plain villagers.
+    Classic,
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // Add a new player for the stream.
-                Player::join(&mut game, stream);
-                std::io::stdout().flush().unwrap();
+    /// The classic game with a doctor as well, who can save the wolves' victim.
+    SeerDoctor,
 
-                std::thread::sleep(std::time::Duration::from_millis(500));
+    /// As many special roles as possible, with their most disruptive rules turned on.
+    Chaos,
+}
 
-                print!("Do you wish to start the game? y/n: ");
-                std::io::stdout().flush().unwrap();
+impl Preset {
+    /// Returns the roles that the preset hands out.
+    pub fn roles(self) -> RoleSetup {
+        let (players_per_wolf, specials) = match self {
+            Preset::Classic => (4, vec![Role::Seer]),
+            Preset::SeerDoctor => (4, vec![Role::Seer, Role::Doctor]),
 
-                let mut buf = String::new();
-                std::io::stdin().read_line(&mut buf).unwrap();
+            // Specials are handed out in order until there are no players left, so the roles
+            // that make the most difference come first. The minion and the wolf cub both add to
+            // the wolves' side, so there are fewer ordinary wolves to make up for them.
+            Preset::Chaos => (
+                5,
+                vec![
+                    Role::Seer,
+                    Role::Doctor,
+                    Role::Hunter,
+                    Role::Witch,
+                    Role::Cupid,
+                    Role::Jester,
+                    Role::Minion,
+                    Role::Bodyguard,
+                    Role::Knight,
+                    Role::Prince,
+                    Role::WolfCub,
+                    Role::Bomber,
+                    Role::Saint,
+                ],
+            ),
+        };
 
-                if buf.starts_with('y') {
-                    break;
+        RoleSetup {
+            players_per_wolf,
+            specials,
+            counts: vec![],
+        }
+    }
+
+    /// Returns the rules that the preset plays with.
+    pub fn rules(self) -> GameRules {
+        match self {
+            Preset::Classic | Preset::SeerDoctor => GameRules::default(),
+
+            // Nobody is told which roles are in play, only how many, so there's no knowing what
+            // the village is up against.
+            Preset::Chaos => GameRules {
+                bomber_at_night: true,
+                setup_reveal: SetupReveal::Partial,
+                ..GameRules::default()
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Preset, String> {
+        match s.to_lowercase().as_str() {
+            "classic" => Ok(Preset::Classic),
+            "seer-doctor" | "seerdoctor" => Ok(Preset::SeerDoctor),
+            "chaos" => Ok(Preset::Chaos),
+            _ => Err(format!("'{}' is not a game mode", s)),
+        }
+    }
+}
+
+/// The roles to give to players at the start of a game.
+#[derive(Clone)]
+pub struct RoleSetup {
+    /// The number of players there are for each wolf. There is always at least one wolf, however
+    /// few players there are.
+    pub players_per_wolf: usize,
+
+    /// The special roles to give out alongside the wolves. Every player who doesn't get a wolf or
+    /// one of these roles is a villager.
+    pub specials: Vec<Role>,
+
+    /// Exactly how many of each role to give out, depending on how many players there are. If
+    /// this isn't empty, it's used instead of `players_per_wolf` and `specials`.
+    pub counts: Vec<RoleCounts>,
+}
+
+/// How many of each role to give out in games with at least a certain number of players.
+#[derive(Clone, Debug)]
+pub struct RoleCounts {
+    /// The fewest players a game can have for these counts to be used. Games with more players
+    /// use the counts with the highest minimum that they reach.
+    pub min_players: usize,
+
+    /// The number of players to give each role, with the wolves first. Every player left over is
+    /// a villager.
+    pub roles: Vec<(Role, usize)>,
+}
+
+/// An entry in a role config file, before the role names have been checked.
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RoleCountsEntry {
+    min_players: usize,
+    roles: BTreeMap<String, usize>,
+}
+
+impl RoleSetup {
+    /// Reads the role counts to use from the JSON config file at the given path. The file holds a
+    /// list of entries, each giving the fewest players it applies to and how many of each role
+    /// to hand out, such as:
+    ///
+    /// ```json
+    /// [
+    ///     { "min_players": 4, "roles": { "wolf": 1, "seer": 1 } },
+    ///     { "min_players": 8, "roles": { "wolf": 2, "seer": 1, "doctor": 1 } }
+    /// ]
+    /// ```
+    ///
+    /// Villagers make up the rest of the players, but they can be listed too, in which case the
+    /// counts must add up to exactly `min_players`.
+    pub fn load_counts(path: &Path) -> Result<Vec<RoleCounts>, String> {
+        let file = std::fs::File::open(path)
+            .map_err(|err| format!("Couldn't open {}: {}", path.display(), err))?;
+
+        let entries: Vec<RoleCountsEntry> = serde_json::from_reader(std::io::BufReader::new(file))
+            .map_err(|err| format!("Couldn't read {}: {}", path.display(), err))?;
+
+        if entries.is_empty() {
+            return Err(format!("{} doesn't give any role counts", path.display()));
+        }
+
+        let mut all_counts: Vec<RoleCounts> = vec![];
+
+        for entry in entries {
+            let min_players = entry.min_players;
+
+            if all_counts.iter().any(|c| c.min_players == min_players) {
+                return Err(format!(
+                    "There is more than one entry for {} or more players",
+                    min_players
+                ));
+            }
+
+            let mut roles: Vec<(Role, usize)> = vec![];
+
+            for (name, count) in entry.roles {
+                let role: Role = name.parse()?;
+
+                match roles.iter_mut().find(|(r, _)| *r == role) {
+                    Some((_, existing)) => *existing += count,
+                    None => roles.push((role, count)),
                 }
+            }
 
-                println!("Waiting for more players...");
+            // The wolves are always handed out first.
+            roles.retain(|&(_, count)| count != 0);
+            roles.sort_by_key(|&(role, _)| !role.is_wolf());
+
+            if !roles.iter().any(|&(role, _)| role.is_wolf()) {
+                return Err(format!(
+                    "The roles for {} or more players don't include any wolves",
+                    min_players
+                ));
             }
-            Err(err) => {
-                eprintln!("Failed to connect to incoming stream: {}", err);
-                continue;
+
+            let total: usize = roles.iter().map(|&(_, count)| count).sum();
+            let villagers_given = roles.iter().any(|&(role, _)| role == Role::Villager);
+
+            if total > min_players || (villagers_given && total != min_players) {
+                return Err(format!(
+                    "The roles for {} or more players add up to {} players, not {}",
+                    min_players, total, min_players
+                ));
             }
+
+            all_counts.push(RoleCounts { min_players, roles });
         }
+
+        all_counts.sort_by_key(|c| c.min_players);
+        Ok(all_counts)
     }
 
-    game.play();
+    /// Returns the fewest players that a game needs for its roles to be handed out.
+    fn min_players(&self) -> usize {
+        self.counts.first().map_or(0, |c| c.min_players)
+    }
+
+    /// Returns the role counts to use for a game with the given number of players, if there are
+    /// any.
+    fn counts_for(&self, players: usize) -> Option<&RoleCounts> {
+        self.counts.iter().rev().find(|c| c.min_players <= players)
+    }
 }
 
-/// A player in the game.
-struct Player {
-    /// The player's ID. This allows us to refer to this player without needing to identify by
-    /// name.
-    id: PlayerId,
+/// Rules that change how the game plays out.
+#[derive(Clone)]
+pub struct GameRules {
+    /// Whether players are asked for their votes in the order they joined the game. If this is
+    /// `false`, the voting order is shuffled every day.
+    pub fixed_vote_order: bool,
 
-    /// The name chosen by the player. This should be unique within the game, as it will be used to
-    /// identify individual players to each other.
-    name: String,
+    /// Whether a bomber killed by the wolves gets to take someone down with them. If this is
+    /// `false`, only a bomber who is voted out can do so.
+    pub bomber_at_night: bool,
 
-    /// The stream through which we communicate with the client.
-    stream: Mutex<TcpStream>,
+    /// Whether one of the wolves, chosen when the roles are handed out, looks like a villager to
+    /// the seer.
+    pub godfather: bool,
 
-    /// Whether the player has died (either by being killed or voted out).
-    dead: bool,
+    /// Whether one of the wolves, chosen when the roles are handed out, is a lone wolf who only
+    /// wins if they're the last wolf standing. This only happens if there's more than one wolf.
+    pub lone_wolf: bool,
 
-    /// The player's role.
-    role: Option<Role>,
+    /// How long to pause for before each step of revealing everyone's roles at the end of the
+    /// game.
+    pub reveal_delay: Duration,
+
+    /// How long the village gets to talk before each day's vote. If this is zero, the village
+    /// votes without any discussion.
+    pub discussion_time: Duration,
+
+    /// How long the wolves get to talk among themselves each night before choosing who to kill.
+    /// If this is zero, or there's only one wolf left, the wolves don't talk.
+    pub wolf_chat_time: Duration,
+
+    /// How long the dead get to talk among themselves each night. If this is zero, the dead
+    /// can't talk to each other.
+    pub dead_chat_time: Duration,
+
+    /// How long a player who was killed by the wolves or voted out has to say their last words.
+    /// If this is zero, the dead don't get a say.
+    pub last_words_time: Duration,
+
+    /// The most players that can join the game. If this is zero, anyone can join. Spectators
+    /// don't count.
+    pub max_players: usize,
+
+    /// The most spectators that can watch the game at once. If this is zero, anyone can watch.
+    pub max_spectators: usize,
+
+    /// How long each player has to vote before their vote is skipped. If this is zero, players
+    /// can take as long as they like.
+    pub vote_timeout: Duration,
+
+    /// How long a player who loses their connection during the game has to rejoin before they're
+    /// out of the game. Until then, their seat is held but they don't act. If this is zero, they're
+    /// out as soon as we notice.
+    pub reconnect_grace: Duration,
+
+    /// Whether players may vote against themselves during the day.
+    pub allow_self_vote: bool,
+
+    /// Whether players may abstain from the day's vote. If enough of them do, nobody is voted
+    /// out that day.
+    pub allow_no_lynch: bool,
+
+    /// Whether the game opens with a day rather than a night, so that the village gets a vote
+    /// before the wolves have killed anyone.
+    pub skip_first_night: bool,
+
+    /// Whether a wolf who kills the knight dies as soon as the knight's death is announced. If
+    /// this is `false`, the wolf survives for one more night and dies the day after.
+    pub knight_revenge_immediate: bool,
+
+    /// When the wolves win.
+    pub wolf_win_rule: WolfWinRule,
+
+    /// How much the players are told about which roles are in play when the game starts.
+    pub setup_reveal: SetupReveal,
+
+    /// What happens to the village when it votes out a saint.
+    pub saint_penalty: SaintPenalty,
+
+    /// Whether the players are told where everyone is sitting around the table when the game
+    /// starts.
+    pub seating: bool,
+
+    /// Whether the doctor may choose to protect themselves.
+    pub doctor_self_protect: bool,
+
+    /// Whether a bodyguard who guards the wolves' victim dies in their place. If this is `false`,
+    /// the attack is simply blocked.
+    pub bodyguard_dies: bool,
+
+    /// Whether spectators are shown what the players can't see, such as everyone's roles and
+    /// what happens at night. Even then, a spectator connecting from the same address as a living
+    /// player is only shown what the players can all see, so that nobody can cheat by watching
+    /// their own game.
+    pub spectators_see_secrets: bool,
+
+    /// The number of times over the game that the village can vote to investigate a player and
+    /// find out which side they're on.
+    pub village_investigations: usize,
+
+    /// Whether the host has to tell the game to move on before each step of the night. If this is
+    /// `false`, the night plays out without waiting for the host.
+    pub manual_night: bool,
 }
 
-impl Player {
-    /// Creates a new `Player` for the given stream, and add the player to a game.
-    fn join(game: &mut Game, mut stream: TcpStream) {
-        // We need a message to specify the player's name.
-        let msg: CtsMessage = bincode::deserialize_from(&mut stream).unwrap();
+/// The rules for when the wolves win the game.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WolfWinRule {
+    /// The wolves win as soon as there are as many wolves as villagers. At that point the village
+    /// can no longer outvote the wolves, so there's often no point in playing on.
+    Parity,
 
-        let name = match msg {
-            CtsMessage::Connect(name) => name,
-            msg => panic!("Expected name message, got {:?} instead", msg),
-        };
+    /// The wolves only win once they strictly outnumber the villagers. This gives the village one
+    /// more chance when the two sides are level.
+    Majority,
+}
 
-        // Get the game to generate a new ID for this player.
-        let id = game.take_next_id();
+impl std::str::FromStr for WolfWinRule {
+    type Err = String;
 
-        let player = Player {
-            id,
-            stream: Mutex::new(stream),
-            dead: false,
-            name,
-            role: None,
-        };
+    fn from_str(s: &str) -> Result<WolfWinRule, String> {
+        match s.to_lowercase().as_str() {
+            "parity" => Ok(WolfWinRule::Parity),
+            "majority" => Ok(WolfWinRule::Majority),
+            _ => Err(format!("'{}' is not a wolf win rule", s)),
+        }
+    }
+}
 
-        // Send the ID to the player's client so that they know what their own ID is.
-        player.send(&StcMessage::IdAssigned(id));
+/// How much of the game's setup is revealed to the players at the start of the game.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SetupReveal {
+    /// Every role in play is revealed, but not who has which.
+    Full,
 
-        // Create the new player and add them to the game.
-        game.add_player(player);
+    /// Only the number of special roles in play is revealed.
+    Partial,
+
+    /// The players aren't told anything about the roles in play.
+    Hidden,
+}
+
+impl std::str::FromStr for SetupReveal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<SetupReveal, String> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(SetupReveal::Full),
+            "partial" => Ok(SetupReveal::Partial),
+            "none" | "hidden" => Ok(SetupReveal::Hidden),
+            _ => Err(format!("'{}' is not a setup reveal level", s)),
+        }
     }
+}
 
-    /// Sends a message to the client.
-    fn send(&self, msg: &StcMessage) -> CtsMessage {
-        println!("server sending: {:?}", msg);
+/// The ways in which the village can be punished for voting out a saint.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SaintPenalty {
+    /// The village loses the game straight away.
+    Lose,
 
-        let mut stream = self.stream.lock();
-        bincode::serialize_into(stream.deref_mut(), &msg).unwrap();
+    /// Nobody can be voted out on the following day.
+    SkipNextLynch,
+}
 
-        // Every message sent from the host should prompt a response from the client.
-        let resp = bincode::deserialize_from(stream.deref_mut()).unwrap();
+impl std::str::FromStr for SaintPenalty {
+    type Err = String;
 
-        println!("got back: {:?}", resp);
-        resp
+    fn from_str(s: &str) -> Result<SaintPenalty, String> {
+        match s.to_lowercase().as_str() {
+            "lose" => Ok(SaintPenalty::Lose),
+            "skip-next-lynch" => Ok(SaintPenalty::SkipNextLynch),
+            _ => Err(format!("'{}' is not a saint penalty", s)),
+        }
     }
+}
 
-    /// Returns the player's role. Panics if the role has not been assigned yet.
-    fn role(&self) -> Role {
-        self.role.expect("No role given")
+impl Default for GameRules {
+    fn default() -> GameRules {
+        GameRules {
+            fixed_vote_order: false,
+            bomber_at_night: false,
+            godfather: false,
+            lone_wolf: false,
+            reveal_delay: Duration::from_millis(1500),
+            discussion_time: Duration::ZERO,
+            vote_timeout: Duration::from_secs(60),
+            reconnect_grace: Duration::ZERO,
+            max_players: 20,
+            max_spectators: 0,
+            wolf_chat_time: Duration::ZERO,
+            dead_chat_time: Duration::ZERO,
+            last_words_time: Duration::ZERO,
+            allow_self_vote: false,
+            allow_no_lynch: true,
+            skip_first_night: false,
+            knight_revenge_immediate: false,
+            wolf_win_rule: WolfWinRule::Parity,
+            setup_reveal: SetupReveal::Hidden,
+            saint_penalty: SaintPenalty::Lose,
+            manual_night: false,
+            village_investigations: 0,
+            seating: false,
+            doctor_self_protect: false,
+            bodyguard_dies: false,
+            spectators_see_secrets: false,
+        }
     }
 }
 
-struct Game {
-    /// The players participating in the game.
-    players: HashMap<PlayerId, Player>,
+/// Something that happened during a game, recorded so that the game can be looked back on once
+/// it's over.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Event {
+    /// A new night began.
+    NightFell,
 
-    /// The next available player ID for this game.
-    next_id: PlayerId,
+    /// The night's deaths were dealt with, and the village started its day.
+    DayBroke,
+
+    /// Player A voted against player B.
+    Voted(PlayerId, PlayerId),
+
+    /// The player with the given ID died.
+    Died(PlayerId, DeathCause),
+
+    /// The game was won by the given side.
+    Won(Winner),
 }
 
-impl Game {
-    fn new() -> Game {
-        Game {
-            players: HashMap::new(),
-            next_id: PlayerId::new(),
+/// The things that the village can vote on during the day.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Ballot {
+    /// Who should be voted out.
+    Lynch,
+
+    /// Who should be investigated to find out which side they're on.
+    Investigation,
+}
+
+/// Who can take part in a discussion.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Channel {
+    /// Everyone who is alive can speak, and everyone can listen.
+    Village,
+
+    /// Only the living wolves can speak or listen.
+    Wolves,
+
+    /// Only the dead can speak or listen.
+    Graveyard,
+}
+
+/// The ways in which a player can die.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum DeathCause {
+    /// Killed during the night by the wolf with the given ID.
+    Killed(PlayerId),
+
+    /// Voted out by the other players during the day.
+    VotedOut,
+
+    /// Taken down by the bomber with the given ID as they died.
+    TakenDown(PlayerId),
+
+    /// Shot by the hunter with the given ID as they died.
+    Shot(PlayerId),
+
+    /// Killed in revenge for killing the knight with the given ID.
+    Revenge(PlayerId),
+
+    /// Poisoned during the night by the witch with the given ID.
+    Poisoned(PlayerId),
+
+    /// Died of a broken heart when the lover with the given ID died.
+    Heartbreak(PlayerId),
+
+    /// Left the game after losing their connection.
+    Left,
+}
+
+impl DeathCause {
+    /// Returns a short description of the cause of death, along with the ID of the player
+    /// responsible for it, if there was one.
+    fn describe(self) -> (&'static str, Option<PlayerId>) {
+        match self {
+            DeathCause::Killed(id) => ("killed", Some(id)),
+            DeathCause::VotedOut => ("voted out", None),
+            DeathCause::TakenDown(id) => ("taken down", Some(id)),
+            DeathCause::Shot(id) => ("shot", Some(id)),
+            DeathCause::Revenge(id) => ("revenge", Some(id)),
+            DeathCause::Poisoned(id) => ("poisoned", Some(id)),
+            DeathCause::Heartbreak(id) => ("heartbreak", Some(id)),
+            DeathCause::Left => ("left", None),
         }
     }
+}
 
-    fn play(&mut self) {
-        self.assign_roles();
+/// Starts hosting games on the given address and port, and returns the address that a client on
+/// this machine can use to join them.
+///
+/// Binding to the unspecified address (`0.0.0.0`) listens on every interface, so that players on
+/// other machines can join. Binding to a loopback address keeps the game to this machine.
+///
+/// Players can create as many rooms as they like, and each room plays its own game with the given
+/// config. The rooms are returned alongside the address, so that the caller can wait for them to
+/// close.
+pub fn start(bind: IpAddr, port: u16, config: Config) -> (SocketAddr, Rooms) {
+    let listener = std::net::TcpListener::bind((bind, port)).expect("Unable to start server");
 
-        loop {
-            let killed_id = self.play_night();
+    // If we were given port zero, the OS picked a free port for us, and that's the one players
+    // need to know about.
+    let port = listener
+        .local_addr()
+        .expect("Unable to start server")
+        .port();
 
-            // Play one day, and if either side wins, report that and end the game.
-            if let Some(winning_side) = self.play_day(killed_id) {
-                self.send_all(&StcMessage::AnnounceWinner(winning_side));
-                break;
+    // Other players need to know which address to connect to. When we're listening everywhere,
+    // the loopback address we connect to ourselves isn't it, so we find our LAN address instead.
+    let shared = if bind.is_unspecified() {
+        lan_address().map(IpAddr::V4)
+    } else if bind.is_loopback() {
+        None
+    } else {
+        Some(bind)
+    };
+
+    match shared {
+        Some(ip) => {
+            println!("Hosting on {}", SocketAddr::new(ip, port));
+
+            match ip {
+                IpAddr::V4(v4) => println!(
+                    "Players can join with `join {} --port {}` or `join {}`",
+                    ip,
+                    port,
+                    comm::join_code(SocketAddrV4::new(v4, port))
+                ),
+
+                // Join codes only hold IPv4 addresses.
+                IpAddr::V6(_) => println!("Players can join with `join {} --port {}`", ip, port),
             }
         }
+
+        None if bind.is_loopback() => {
+            println!("Hosting on {}:{} (only this machine can join)", bind, port)
+        }
+
+        None => println!("Hosting on port {}", port),
     }
 
-    /// Adds the given player to the game after announcing that they are joining.
-    fn add_player(&mut self, player: Player) {
-        self.send_all(&StcMessage::AnnounceJoin(player.id, player.name.clone()));
+    let rooms = Rooms::new(config);
+    let router = rooms.clone();
 
-        // Tell the new player about all the players who are already in the game.
-        player.send(&StcMessage::Players(
-            self.players
-                .iter()
-                .map(|(&id, p)| (id, p.name.clone()))
-                .collect(),
-        ));
+    // Each connection is sent to its room on its own thread, so that a client that is slow to
+    // say which room it wants doesn't hold up anyone else.
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let router = router.clone();
 
-        self.players.insert(player.id, player);
-    }
+                    std::thread::spawn(move || {
+                        if let Err(err) = router.route(stream) {
+                            warn!("Dropping new connection: {}", err);
+                        }
+                    });
+                }
 
-    /// Returns a player ID that can be used for a new player joining the game.
-    ///
-    /// An identical player ID will not be generated by this `Game` instance on any subsequent call
-    /// to this method.
-    fn take_next_id(&mut self) -> PlayerId {
-        let id = self.next_id;
+                Err(err) => warn!("Failed to connect to incoming stream: {}", err),
+            }
+        }
+    });
 
-        // Get another player ID for the next call to this method, since we've used this one
-        // already.
-        self.next_id = id.next();
+    // We can always reach ourselves over loopback when listening everywhere, but otherwise we
+    // have to use the address we bound to.
+    let local = match bind {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
+    };
 
-        id
+    (SocketAddr::new(local, port), rooms)
+}
+
+/// Returns the address that this machine uses on its local network, if it can be found.
+fn lan_address() -> Option<Ipv4Addr> {
+    // Connecting a UDP socket doesn't send anything, but it does make the OS pick the interface
+    // that it would send from. This is the interface that other players can reach us through.
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V4(ip) if !ip.is_loopback() && !ip.is_unspecified() => Some(ip),
+        _ => None,
     }
+}
 
-    /// Assigns a random role to each player.
-    fn assign_roles(&mut self) {
-        let mut rng = rand::thread_rng();
+/// The letters used in room codes. Letters that are easily mistaken for digits are left out.
+const ROOM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ";
 
-        // Pick a wolf. Once we've done that, we know the rest of the players are villagers.
-        // This will have to change when we add support for multiple wolves, but for now this is
-        // fine.
-        let wolf_index = rng.gen_range(0..self.players.len());
+/// The number of letters in a room code.
+const ROOM_CODE_LEN: usize = 4;
 
-        for (i, player) in self.players.values_mut().enumerate() {
-            let role = if i == wolf_index {
-                Role::Wolf
-            } else {
-                Role::Villager
-            };
+/// The game rooms being hosted. Each room is known by a short code, which players give when they
+/// connect so that they end up in the right game.
+#[derive(Clone)]
+pub struct Rooms {
+    /// The channel for sending new connections to each room, keyed by the room's code. Each
+    /// connection is sent along with the message it introduced itself with.
+    rooms: Arc<Mutex<HashMap<String, mpsc::Sender<Arrival<TcpStream>>>>>,
 
-            player.role = Some(role);
-            player.send(&StcMessage::RoleAssigned(role));
+    /// The threads running the rooms, so that we can wait for them to finish.
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+
+    /// The config that every room's game is played with.
+    config: Arc<Config>,
+}
+
+impl Rooms {
+    fn new(config: Config) -> Rooms {
+        Rooms {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            threads: Arc::new(Mutex::new(vec![])),
+            config: Arc::new(config),
         }
     }
 
-    /// Plays through one night in the game, returning the ID of the player killed by the werewolf.
-    fn play_night(&mut self) -> PlayerId {
-        // Tell all the players that night has fallen.
-        self.send_all(&StcMessage::NightFalls);
+    /// Waits until every room has closed.
+    pub fn wait(&self) {
+        loop {
+            let threads = std::mem::take(self.threads.lock().deref_mut());
 
-        // Tell all the players that the wolves have woken up.
-        self.send_all(&StcMessage::WolvesWake);
+            // A room might have been created while we were waiting for the others.
+            if threads.is_empty() {
+                break;
+            }
 
-        // Find the wolf in the players so we can ask them who to kill.
-        let wolf = self
-            .players
-            .values()
-            .find(|p| matches!(p.role(), Role::Wolf))
-            .unwrap();
+            for thread in threads {
+                thread.join().expect("Room thread panicked");
+            }
+        }
+    }
 
-        // Find the non-wolf players. These are the players that can be killed by the wolf.
-        let kill_candidates: Vec<PlayerId> = self
-            .players
-            .values()
-            .filter_map(|p| match p.role() {
-                Role::Wolf => None,
-                _ if !p.dead => Some(p.id),
-                _ => None,
-            })
-            .collect();
+    /// Finds out which room a new connection wants, and hands it over to that room. A client
+    /// that asks to create a room gets a new one.
+    fn route(&self, mut stream: TcpStream) -> Result<(), CommError> {
+        // Before anything else, make sure that the client speaks the same protocol as us. If it
+        // doesn't, we can't trust anything else it sends to mean what we think it means.
+        match comm::receive(&mut stream)? {
+            CtsMessage::Hello(version) if version == comm::PROTOCOL_VERSION => (),
 
-        // Send the wolf the list of players that they can kill. This should trigger their client
-        // to ask them for and send back their choice of player.
-        let response = wolf.send(&StcMessage::KillOptions(kill_candidates.clone()));
+            CtsMessage::Hello(version) => {
+                info!(
+                    "Turning away a client speaking protocol version {} instead of {}",
+                    version,
+                    comm::PROTOCOL_VERSION
+                );
 
-        let kill_id = match response {
-            CtsMessage::Kill(id) => id,
-            msg => {
-                // We shouldn't get anything else here, so panic if we do.
-                panic!("Expected kill message from wolf, but got {:?} instead", msg);
+                let mismatch = StcMessage::VersionMismatch {
+                    server: comm::PROTOCOL_VERSION,
+                    client: version,
+                };
+
+                comm::send(&mut stream, &mismatch)?;
+                return Ok(());
             }
-        };
 
-        if !kill_candidates.contains(&kill_id) {
-            panic!(
-                "Wolf attempted to kill non-candidate {}",
-                self.players.get(&kill_id).unwrap().name
-            );
+            msg => {
+                warn!(
+                    "Rejecting connection that sent {:?} before saying hello",
+                    msg
+                );
+                return Ok(());
+            }
         }
 
-        // Get a reference to the player the wolf is killing.
-        let player_killed = self.players.get_mut(&kill_id).unwrap();
+        let msg: CtsMessage = comm::receive(&mut stream)?;
 
-        // Kill them.
-        player_killed.dead = true;
+        let (code, reply) = match msg {
+            CtsMessage::CreateRoom => {
+                let code = self.create();
+                (code.clone(), StcMessage::RoomCreated(code))
+            }
 
-        // Return the ID of the killed player for use in the day phase.
-        kill_id
-    }
+            CtsMessage::JoinRoom(code) => match self.find(&code) {
+                Some(code) => (code.clone(), StcMessage::RoomJoined(code)),
 
-    /// Plays through one day in the game, given the name of the player that was killed the night
-    /// before.
-    ///
-    /// If this day ends the game, the winning side will be returned. Otherwise, `None` will be
-    /// returned.
-    fn play_day(&mut self, killed_id: PlayerId) -> Option<Winner> {
-        // Tell all the players which one died.
-        self.send_all(&StcMessage::Died(killed_id));
+                None => {
+                    info!("Turning away a player looking for room '{}'", code);
 
-        // Find all the living players. These are the players who will get a vote, and who can be
-        // voted against by other players.
-        let living = self.players.values().filter(|p| !p.dead);
+                    comm::send(&mut stream, &StcMessage::NoSuchRoom(code))?;
 
-        // Get the names of all the players that can be voted against.
-        let candidates: Vec<_> = living.clone().map(|p| p.id).collect();
+                    return Ok(());
+                }
+            },
 
-        // Create a vector from the iterator of living players so we don't need to keep cloning the
-        // iterator.
-        let living: Vec<&Player> = living.collect();
+            msg => {
+                warn!(
+                    "Rejecting connection that sent {:?} before picking a room",
+                    msg
+                );
+                return Ok(());
+            }
+        };
 
-        // We don't want to allow a player to vote multiple times, so store votes in a hashmap to
-        // ensure that there is only one vote per player ID.
-        let mut votes = HashMap::<String, PlayerId>::new();
+        comm::send(&mut stream, &reply)?;
 
-        for player in &living {
-            // Say who we're waiting for so players can tell others that they need to vote.
-            self.send_all(&StcMessage::WaitingFor(player.id));
+        // The client introduces itself next, which can take a while if the user is typing their
+        // name. We wait for it here rather than in the room, so that the room's game is never
+        // held up by someone who is still deciding what to be called.
+        let intro: CtsMessage = comm::receive(&mut stream)?;
 
-            let response = player.send(&StcMessage::VoteOptions(candidates.clone()));
+        // The room could have closed since we found it, in which case the connection is dropped.
+        if let Some(room) = self.rooms.lock().get(&code) {
+            let _ = room.send((stream, intro));
+        }
 
-            match response {
-                CtsMessage::Vote(vote) => {
-                    // Tell all the players about the vote.
-                    self.send_all(&StcMessage::AnnounceVote(player.id, vote));
+        Ok(())
+    }
 
-                    // Record the vote.
-                    votes.insert(player.name.clone(), vote);
-                }
+    /// Returns the full code of the room with the given code, ignoring case. An empty code finds
+    /// the only room, as long as there's exactly one.
+    fn find(&self, code: &str) -> Option<String> {
+        let rooms = self.rooms.lock();
 
-                msg => {
-                    println!("Expected vote message, got {:?} instead", msg);
-                }
+        if code.is_empty() {
+            return match rooms.keys().collect::<Vec<_>>()[..] {
+                [only] => Some(only.clone()),
+                _ => None,
             };
         }
 
-        // Count the votes by making a new hashmap with the player ID as a key, and the number of
-        // votes they have received as the value.
-        let mut vote_counts = HashMap::<PlayerId, usize>::new();
+        let code = code.trim().to_uppercase();
+        rooms.contains_key(&code).then_some(code)
+    }
 
-        for (_, player_index) in votes {
-            *vote_counts.entry(player_index).or_default() += 1;
-        }
+    /// Opens a new room, and returns its code.
+    fn create(&self) -> String {
+        let mut rooms = self.rooms.lock();
+        let mut rng = rand::thread_rng();
 
-        // Find the player with the most votes.
-        let (&voted_id, &num_votes) = vote_counts.iter().max_by_key(|(_, &num)| num).unwrap();
+        let code = loop {
+            let code: String = (0..ROOM_CODE_LEN)
+                .map(|_| *ROOM_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect();
 
-        // Check if the vote has a majority (i.e. whether more than half of the players agreed).
-        if num_votes > (living.len() / 2) {
-            // Majority vote, so the person should die.
-            self.send_all(&StcMessage::VotedOut(voted_id));
+            if !rooms.contains_key(&code) {
+                break code;
+            }
+        };
 
-            // Drop the living players vector so we can get a mutable reference to the player and
-            // kill them. (We need to drop the immutable references first, or we'd be mutably
-            // borrowing the players when there are still immutable references around.)
-            drop(living);
+        info!("Opening room {}", code);
 
-            // Get a mutable reference to the player who has been voted out.
-            let voted = self.players.get_mut(&voted_id).unwrap();
+        let (sender, joins) = mpsc::channel();
+        rooms.insert(code.clone(), sender);
 
-            // Kill them.
-            voted.dead = true;
-        } else {
-            self.send_all(&StcMessage::NoMajority);
-        }
+        let this = self.clone();
+        let room_code = code.clone();
 
-        // Count wolves and villagers to see if the game has ended.
-        let (wolves, villagers) =
-            self.players
-                .values()
-                .fold((0, 0), |(w, v), p| match p.role.unwrap() {
-                    Role::Wolf => (w + 1, v),
-                    Role::Villager => (w, v + 1),
-                });
+        self.threads.lock().push(std::thread::spawn(move || {
+            run_room(joins, &this.config);
 
-        if wolves == villagers {
-            // If there are as many wolves as there are villagers, the wolves win.
-            Some(Winner::Wolf)
-        } else if wolves == 0 {
-            // If the villagers have killed all the wolves, the village wins.
-            Some(Winner::Village)
-        } else {
-            None
-        }
-    }
+            info!("Closing room {}", room_code);
+            this.rooms.lock().remove(&room_code);
+        }));
 
-    /// Sends the given message to every player.
-    fn send_all(&self, message: &StcMessage) {
+        code
+    }
+}
+
+/// Runs a room's games from start to finish, letting in the connections sent to the room. Once a
+/// game is over, the players who want to play again go back to the lobby for another one, and the
+/// room closes once nobody does.
+///
+/// Connections are accepted in the background and sent over `joins`, so that players can still
+/// connect while the host is deciding whether to start.
+fn run_room(mut joins: mpsc::Receiver<Arrival<TcpStream>>, config: &Config) {
+    let mut game = Game::<TcpStream>::new(config.rules.clone(), config.roles.clone(), config.seed);
+    game.heartbeat = (!config.heartbeat.is_zero()).then_some(config.heartbeat);
+
+    println!("Using seed {}", game.seed);
+
+    while run_lobby(&mut game, &joins) {
+        // Give everyone a moment to get ready rather than starting the instant the host decides
+        // to. If anyone backs out in the meantime, it's back to the lobby.
+        if !game.count_down(config.start_countdown) {
+            continue;
+        }
+
+        // Anyone who connects from now on is too late to play, but the game lets them in to
+        // watch.
+        game.late_joins = Some(joins);
+        game.reseed();
+        game.play();
+
+        let stats = game.summarize_stats();
+        println!("{}", stats);
+
+        if let Some(path) = &config.stats_path {
+            if let Err(err) = stats.append_to(path) {
+                error!("Failed to write stats to {}: {}", path.display(), err);
+            }
+        }
+
+        if let Some(dir) = &config.log_dir {
+            match game.record().write_to(dir) {
+                Ok(path) => info!("Saved the game's record to {}", path.display()),
+                Err(err) => error!(
+                    "Failed to save the game's record in {}: {}",
+                    dir.display(),
+                    err
+                ),
+            }
+        }
+
+        game.rematch(config.post_game_timeout);
+
+        if game.players.is_empty() {
+            info!("Nobody wanted to play again");
+            return;
+        }
+
+        // Anyone still waiting to get in joins the lobby for the next game, rather than watching.
+        joins = game
+            .late_joins
+            .take()
+            .expect("late joins are taken when the game starts");
+    }
+}
+
+/// Plays a single game between the players at the other ends of the given connections, and
+/// returns the side that won. Unlike a room on the server, the game starts as soon as everyone
+/// has joined, nothing is saved once it's over, and nobody can stay for another game.
+///
+/// This works over any kind of connection, so a game can be played in-process (between bots,
+/// say) using `comm::channel_pair`. Players get their IDs in the order of `connections`, starting
+/// from `PlayerId::new()`. Returns `None` if there weren't enough players for the roles, or if
+/// somebody backed out during the countdown.
+pub fn play_game<T: Transport>(connections: Vec<T>, config: &Config) -> Option<Winner> {
+    let mut game = Game::<T>::new(config.rules.clone(), config.roles.clone(), config.seed);
+    game.heartbeat = (!config.heartbeat.is_zero()).then_some(config.heartbeat);
+
+    info!("Using seed {}", game.seed);
+
+    for mut stream in connections {
+        let joined =
+            comm::receive(&mut stream).and_then(|intro| Player::join(&mut game, stream, intro));
+
+        if let Err(err) = joined {
+            warn!("Dropping new connection: {}", err);
+        }
+    }
+
+    let needed = game.roles.min_players();
+
+    if game.players.len() < needed {
+        warn!("Can't start a game with fewer than {} players", needed);
+        return None;
+    }
+
+    // There's no lobby to go back to if someone backs out.
+    if !game.count_down(config.start_countdown) {
+        return None;
+    }
+
+    let winner = game.play();
+
+    // There's no rematch, so everyone is told that the session is over rather than just losing
+    // their connection.
+    for player in game.players.values().filter(|p| !p.is_disconnected()) {
+        player.tell(&StcMessage::SessionClosed);
+    }
+
+    for spectator in &game.spectators {
+        spectator.tell(&StcMessage::SessionClosed);
+    }
+
+    Some(winner)
+}
+
+/// Plays a recorded game again with the current code, with every player giving the answers that
+/// they gave the first time, and checks that each of them is sent exactly what they were sent
+/// before. Returns a description of the first difference, if there is one.
+///
+/// The game is played with the rules and roles in the given config, so those need to be the ones
+/// that the recorded game was played with. Anything that depended on timing, such as a vote that
+/// timed out, won't play out the same way.
+pub fn replay(record: &GameRecord, config: &Config) -> Result<(), String> {
+    let mut game = Game::new(
+        config.rules.clone(),
+        config.roles.clone(),
+        Some(record.seed),
+    );
+
+    for player in &record.players {
+        let replies = player.transcript.iter().filter_map(|msg| match msg {
+            Message::Received(msg) => Some(msg.clone()),
+            Message::Sent(_) => None,
+        });
+
+        let stream = ScriptedTransport::new(replies);
+        let mut replayed = Player::new(player.id, player.name.clone(), Colour::White, stream, None);
+        replayed.ready = true;
+
+        game.players.insert(player.id, replayed);
+    }
+
+    game.next_id = game
+        .players
+        .keys()
+        .last()
+        .map_or(PlayerId::new(), |id| id.next());
+    game.play();
+
+    for player in &record.players {
+        let expected = player.transcript.iter().filter_map(|msg| match msg {
+            Message::Sent(msg) => Some(msg),
+            Message::Received(_) => None,
+        });
+
+        let sent = game.players[&player.id].stream.lock().sent();
+
+        for (n, (sent, expected)) in sent.iter().zip(expected.clone()).enumerate() {
+            if sent != expected {
+                return Err(format!(
+                    "Message {} to {} was {:?}, but the record has {:?}",
+                    n + 1,
+                    player.name,
+                    sent,
+                    expected
+                ));
+            }
+        }
+
+        let expected = expected.count();
+
+        if sent.len() != expected {
+            return Err(format!(
+                "{} was sent {} messages, but the record has {}",
+                player.name,
+                sent.len(),
+                expected
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Lets players into the game's lobby until the host decides to start. Returns `false` if
+/// everyone left before the game could start.
+///
+/// After a game, the players who wanted to play again are already in the lobby, so they're asked
+/// whether they're ready straight away rather than once someone new joins.
+fn run_lobby(game: &mut Game<TcpStream>, joins: &mpsc::Receiver<Arrival<TcpStream>>) -> bool {
+    let mut streams: Vec<Arrival<TcpStream>> = joins.try_iter().collect();
+
+    if streams.is_empty() && game.players.is_empty() {
+        match joins.recv() {
+            Ok(stream) => streams.push(stream),
+            Err(_) => return false,
+        }
+    }
+
+    // Each time someone joins, everyone who isn't ready yet is asked if they are, and then the
+    // host gets to decide whether to start the game. Anyone who connected in the meantime is let
+    // in before the host is asked again.
+    loop {
+        for (stream, intro) in streams.drain(..) {
+            if let Err(err) = Player::join(game, stream, intro) {
+                warn!("Dropping new connection: {}", err);
+            }
+        }
+
+        // Nobody is left to start the game, so there's no point keeping the room open.
+        if game.host().is_none() {
+            info!("Everyone left the room before the game started");
+            return false;
+        }
+
+        game.handle_unprompted();
+        game.check_ready();
+
+        if game.host_wants_to_start() {
+            return true;
+        }
+
+        // Until everyone is ready, we keep asking the players who aren't. Once they are, nothing
+        // changes until somebody else joins, other than players renaming themselves or backing
+        // out.
+        while game.everyone_ready() {
+            match joins.recv_timeout(LOBBY_POLL_INTERVAL) {
+                Ok(stream) => {
+                    streams.push(stream);
+                    break;
+                }
+
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    game.handle_unprompted();
+                }
+
+                Err(mpsc::RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+
+        streams.extend(joins.try_iter());
+    }
+}
+
+/// A player in the game.
+struct Player<T: Transport> {
+    /// The player's ID. This allows us to refer to this player without needing to identify by
+    /// name.
+    id: PlayerId,
+
+    /// The name chosen by the player. This should be unique within the game, as it will be used to
+    /// identify individual players to each other.
+    name: String,
+
+    /// The colour that the player's name is shown in. No two players share a colour unless there
+    /// are more players than colours.
+    colour: Colour,
+
+    /// The secret that the player can use to take their seat back if they lose their connection.
+    token: Token,
+
+    /// The address that the client connected from, if the connection has one.
+    address: Option<IpAddr>,
+
+    /// The connection through which we communicate with the client.
+    stream: Mutex<T>,
+
+    /// Whether the player has died (either by being killed or voted out).
+    dead: bool,
+
+    /// The player's role.
+    role: Option<Role>,
+
+    /// How long the player has taken to answer prompts.
+    decisions: Mutex<Timing>,
+
+    /// Whether the connection to the player's client has failed.
+    disconnected: AtomicBool,
+
+    /// Whether the player has said that they're ready for the game to start.
+    ready: bool,
+
+    /// Whether the player still has a witch's healing potion to use. Only witches use this.
+    healing_potion: bool,
+
+    /// Whether the player still has a witch's poison to use. Only witches use this.
+    poison: bool,
+
+    /// The player that this player guarded last night, who they can't guard again tonight. Only
+    /// bodyguards use this.
+    last_guarded: Option<PlayerId>,
+
+    /// Whether the player has already shown that they're the prince to get out of being voted
+    /// out, which only works once. Only princes use this.
+    prince_revealed: bool,
+
+    /// Whether the seer sees the player as a villager, whatever their role. Only the godfather
+    /// wolf has this.
+    seer_immune: bool,
+
+    /// Whether the player is the lone wolf, who wants the rest of the pack dead too.
+    lone_wolf: bool,
+
+    /// When we noticed that the player had lost their connection, if they haven't rejoined since.
+    /// Their seat is held until the rules' grace period has passed.
+    dropped_at: Option<Instant>,
+
+    /// The number of messages we stopped waiting for replies to. The client will still send
+    /// these replies (in order) before it replies to anything else.
+    owed_replies: AtomicUsize,
+
+    /// Renames that the client asked for while we were waiting for it to answer something else.
+    /// The lobby deals with these once it's free to.
+    unsolicited: Mutex<VecDeque<CtsMessage>>,
+
+    /// Every message that has passed between us and the client since the game started, so that
+    /// the game can be replayed from its record.
+    transcript: Mutex<Vec<Message>>,
+
+    /// How long the client can be quiet while we're waiting for it before we ping it, if at all.
+    heartbeat: Option<Duration>,
+}
+
+impl<T: Transport> Player<T> {
+    /// Creates a new `Player` with the given details.
+    fn new(
+        id: PlayerId,
+        name: String,
+        colour: Colour,
+        stream: T,
+        heartbeat: Option<Duration>,
+    ) -> Player<T> {
+        Player {
+            id,
+            address: stream.peer_ip(),
+            stream: Mutex::new(stream),
+            dead: false,
+            name,
+            colour,
+
+            // Tokens come from the thread's generator rather than the game's, since anyone who
+            // knew the seed could work out a seeded token.
+            token: Token::random(),
+
+            role: None,
+            decisions: Mutex::new(Timing::default()),
+            disconnected: AtomicBool::new(false),
+            owed_replies: AtomicUsize::new(0),
+            unsolicited: Mutex::new(VecDeque::new()),
+            transcript: Mutex::new(vec![]),
+            heartbeat,
+            ready: false,
+            healing_potion: true,
+            poison: true,
+            last_guarded: None,
+            prince_revealed: false,
+            seer_immune: false,
+            lone_wolf: false,
+            dropped_at: None,
+        }
+    }
+
+    /// Forgets everything about the player that only mattered for the last game, so that they
+    /// can play another one. They keep their ID, name, colour and token.
+    fn reset(&mut self) {
+        self.dead = false;
+        self.role = None;
+        self.ready = false;
+        self.healing_potion = true;
+        self.poison = true;
+        self.last_guarded = None;
+        self.prince_revealed = false;
+        self.seer_immune = false;
+        self.lone_wolf = false;
+        self.dropped_at = None;
+        self.decisions = Mutex::new(Timing::default());
+    }
+
+    /// Creates a new `Player` for the given stream, which introduced itself with `intro`, and adds
+    /// the player to a game. If the client asked to spectate, or the game has already started,
+    /// they're added as a spectator instead.
+    ///
+    /// If we can't talk to the new client, the error is returned and the connection is dropped
+    /// without the player joining, so that a bad client can't take the whole game down.
+    fn join(game: &mut Game<T>, mut stream: T, intro: CtsMessage) -> Result<(), CommError> {
+        // A new connection can only introduce itself, so anything else means the client doesn't
+        // know what it's doing and we don't want it in the game.
+        let (mut name, mut colour) = match intro {
+            CtsMessage::Connect(name, colour) if !game.started() => (name, colour),
+
+            // Once the roles have been handed out it's too late to play, but not to watch.
+            CtsMessage::Connect(name, _) => return Player::spectate(game, stream, name),
+            CtsMessage::Spectate => {
+                return Player::spectate(game, stream, String::from("A spectator"))
+            }
+
+            CtsMessage::Reconnect(id, token) => return game.reconnect(id, token, stream),
+
+            msg => {
+                warn!(
+                    "Rejecting connection that sent {:?} while {:?}",
+                    msg,
+                    ServerState::Connecting
+                );
+
+                return Ok(());
+            }
+        };
+
+        // Every player makes the game longer and the menus longer, so there's a limit.
+        let max = game.rules.max_players;
+
+        if max != 0 && game.players.len() >= max {
+            info!("Turning {:?} away, since the game is full", name);
+
+            comm::send(&mut stream, &StcMessage::GameFull(max))?;
+            return Ok(());
+        }
+
+        // Names are shown to everyone, so we don't let anyone in with a name that would confuse
+        // people or break their terminals. The client answers a rejection by trying another name,
+        // but the game can't carry on while we wait for it, so it only gets so long.
+        stream.set_recv_timeout(Some(RENAME_TIMEOUT))?;
+
+        while let Err(reason) = game.check_name(&name, None) {
+            info!("Rejecting the name {:?}: {}", name, reason);
+
+            comm::send(&mut stream, &StcMessage::NameRejected(reason))?;
+
+            match comm::receive(&mut stream)? {
+                CtsMessage::Connect(new_name, new_colour) => {
+                    name = new_name;
+                    colour = new_colour;
+                }
+
+                msg => {
+                    warn!(
+                        "Rejecting connection that sent {:?} after its name was rejected",
+                        msg
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        stream.set_recv_timeout(None)?;
+
+        // Get the game to generate a new ID for this player.
+        let id = game.take_next_id();
+
+        // Someone else might already have the colour the player asked for, in which case they get
+        // a different one.
+        let colour = game.pick_colour(colour);
+
+        info!("{} joined with {:?} and {:?}", name, id, colour);
+
+        let player = Player::new(id, name, colour, stream, game.heartbeat);
+
+        // Send the ID to the player's client so that they know what their own ID is.
+        player.post(&StcMessage::IdAssigned(id, colour, player.token))?;
+
+        // Create the new player and add them to the game.
+        game.add_player(player);
+
+        Ok(())
+    }
+
+    /// Adds a spectator with the given name to the game over the given stream.
+    ///
+    /// Spectators are kept as `Player`s so that we can talk to them in the same way, but they're
+    /// never given a role or asked to do anything, and they're kept apart from the players so
+    /// that they can't count towards a win or a vote.
+    fn spectate(game: &mut Game<T>, mut stream: T, name: String) -> Result<(), CommError> {
+        // Spectators cost us as much to keep up to date as players do, so there's a limit on
+        // them too. Anyone who has lost their connection doesn't count.
+        let max = game.rules.max_spectators;
+        let watching = game
+            .spectators
+            .iter()
+            .filter(|s| !s.is_disconnected())
+            .count();
+
+        if max != 0 && watching >= max {
+            info!(
+                "Turning {:?} away, since there are too many spectators",
+                name
+            );
+
+            comm::send(&mut stream, &StcMessage::SpectatorLimitReached(max))?;
+            return Ok(());
+        }
+
+        // Spectators get IDs from the same place as players so that their IDs never clash with a
+        // player's, even though nobody else ever sees them. Their colour is never shown.
+        let id = game.take_next_id();
+
+        info!("{} is spectating with {:?}", name, id);
+
+        let spectator = Player::new(id, name, Colour::White, stream, game.heartbeat);
+        spectator.post(&StcMessage::Spectating(id))?;
+
+        game.add_spectator(spectator);
+
+        Ok(())
+    }
+
+    /// Sends a prompt to the client and returns its reply. If the prompt can't be sent or the
+    /// reply can't be received, the player is marked as disconnected and the error is returned.
+    fn send(&self, msg: &StcMessage) -> Result<CtsMessage, CommError> {
+        self.send_with_timeout(msg, None)
+    }
+
+    /// Sends a prompt to the client and returns its reply, giving up with
+    /// `CommError::TimedOut` if the reply doesn't arrive in time. A `timeout` of `None` waits as
+    /// long as it takes.
+    ///
+    /// If the message can't be sent or the reply can't be received, the player is marked as
+    /// disconnected and the error is returned. Running out of time doesn't count.
+    fn send_with_timeout(
+        &self,
+        msg: &StcMessage,
+        timeout: Option<Duration>,
+    ) -> Result<CtsMessage, CommError> {
+        debug_assert!(msg.is_prompt(), "only prompts get replies");
+
+        // A player whose seat is being held until they rejoin can't answer, so their turn passes
+        // as if they'd chosen nothing rather than us waiting on a connection we know is gone.
+        if self.is_disconnected() {
+            return Err(CommError::Disconnected(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "lost the connection earlier",
+            )));
+        }
+
+        let result = self.exchange(msg, timeout);
+        self.note_failure(&result);
+        result
+    }
+
+    /// Sends a message to the client that doesn't need a reply, without waiting for the client
+    /// to do anything with it. If it can't be sent, the player is marked as disconnected and the
+    /// error is returned.
+    fn post(&self, msg: &StcMessage) -> Result<(), CommError> {
+        debug_assert!(!msg.is_prompt(), "prompts need replies");
+
+        trace!(target: "wolf::server::net", "Sending {:?} to {}", msg, self.name);
+
+        let result = comm::send(self.stream.lock().deref_mut(), msg);
+        self.note_failure(&result);
+
+        if result.is_ok() {
+            self.transcript.lock().push(Message::Sent(msg.clone()));
+        }
+
+        result
+    }
+
+    /// Sends a message to the client that doesn't need a reply. If it can't be sent, the player
+    /// is marked as disconnected and we log it rather than stopping the game.
+    fn tell(&self, msg: &StcMessage) {
+        if let Err(err) = self.post(msg) {
+            warn!("Lost connection to {}: {}", self.name, err);
+        }
+    }
+
+    /// Marks the player as disconnected if the given result means that we've lost their
+    /// connection. Running out of time doesn't count.
+    fn note_failure<R>(&self, result: &Result<R, CommError>) {
+        if let Err(CommError::Disconnected(_) | CommError::Malformed(_)) = result {
+            self.disconnected.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Sends a prompt to the client and waits for its reply.
+    fn exchange(
+        &self,
+        msg: &StcMessage,
+        timeout: Option<Duration>,
+    ) -> Result<CtsMessage, CommError> {
+        trace!(target: "wolf::server::net", "Sending {:?} to {}", msg, self.name);
+
+        let sent_at = Instant::now();
+
+        let mut stream = self.stream.lock();
+        comm::send(stream.deref_mut(), msg)?;
+        self.transcript.lock().push(Message::Sent(msg.clone()));
+
+        // The client answers prompts in order, so the answers to any prompts we gave up on come
+        // first. They're too late to be any use, so we throw them away.
+        while self.owed_replies.load(Ordering::Relaxed) > 0 {
+            let resp = self.receive(stream.deref_mut(), timeout)?;
+            self.owed_replies.fetch_sub(1, Ordering::Relaxed);
+
+            debug!("Discarding late {:?} from {}", resp, self.name);
+        }
+
+        // A client could also send an action that we didn't ask for. If we took that as the
+        // reply, every reply after it would be out of step, so we throw away anything that isn't
+        // a valid reply in our current state and keep waiting for one that is.
+        let state = ServerState::sent(msg);
+
+        let resp = loop {
+            let resp = self.receive(stream.deref_mut(), timeout)?;
+
+            if state.received(&resp).is_some() {
+                break resp;
+            }
+
+            // A rename isn't an answer to anything, so the client can send one while we're
+            // waiting for it to answer something else. We keep it for the lobby to deal with,
+            // unless the game has started, in which case names are settled until it's over.
+            if let CtsMessage::Rename(_) = resp {
+                if self.role.is_none() {
+                    self.unsolicited.lock().push_back(resp);
+                    continue;
+                }
+
+                // We already have the stream locked, so we can't use `tell`.
+                let rejected = StcMessage::RenameRejected(NameRejection::GameStarted);
+                comm::send(stream.deref_mut(), &rejected)?;
+                self.transcript.lock().push(Message::Sent(rejected));
+                continue;
+            }
+
+            warn!(
+                "Ignoring out-of-turn {:?} from {} while {:?}",
+                resp, self.name, state
+            );
+        };
+
+        // Keep track of how long the player took to decide.
+        self.decisions.lock().record(sent_at.elapsed());
+
+        trace!(target: "wolf::server::net", "Got {:?} back from {}", resp, self.name);
+        Ok(resp)
+    }
+
+    /// Waits for the next message from the client, giving up if it doesn't arrive in time. If we
+    /// give up, we remember that the client still owes us a reply.
+    ///
+    /// If the client is quiet for too long, we ping it, and if it doesn't answer that either, we
+    /// give up on the connection. `Pong`s are skipped over, since they don't answer anything.
+    fn receive(&self, stream: &mut T, timeout: Option<Duration>) -> Result<CtsMessage, CommError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut pinged = false;
+
+        loop {
+            let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+            // We wake up after a heartbeat's worth of silence if that comes before the deadline.
+            let wait = match (left, self.heartbeat) {
+                (Some(left), Some(heartbeat)) => Some(left.min(heartbeat)),
+                (left, heartbeat) => left.or(heartbeat),
+            };
+
+            stream.set_recv_timeout(wait)?;
+
+            match comm::receive(stream) {
+                Ok(CtsMessage::Pong) => pinged = false,
+
+                // Running out of time only counts if the deadline has passed. Otherwise it's just
+                // been a while since we heard from the client.
+                Err(CommError::TimedOut) if deadline.is_some_and(|d| Instant::now() >= d) => {
+                    self.owed_replies.fetch_add(1, Ordering::Relaxed);
+                    return Err(CommError::TimedOut);
+                }
+
+                Err(CommError::TimedOut) if pinged => {
+                    info!("{} stopped answering pings", self.name);
+
+                    return Err(CommError::Disconnected(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "stopped answering pings",
+                    )));
+                }
+
+                Err(CommError::TimedOut) => {
+                    debug!("Pinging {} after hearing nothing for a while", self.name);
+
+                    comm::send(stream, &StcMessage::Ping)?;
+                    pinged = true;
+                }
+
+                result => {
+                    if let Ok(msg) = &result {
+                        self.transcript.lock().push(Message::Received(msg.clone()));
+                    }
+
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Returns a message that the client sent without being asked, if one is waiting or arrives
+    /// within `wait`. Clients that still owe us replies aren't checked, since anything they send
+    /// is one of those.
+    fn poll(&self, wait: Duration) -> Option<CtsMessage> {
+        if let Some(msg) = self.unsolicited.lock().pop_front() {
+            return Some(msg);
+        }
+
+        if self.is_disconnected() || self.owed_replies.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+
+        let mut stream = self.stream.lock();
+
+        let result: Result<CtsMessage, _> = stream
+            .set_recv_timeout(Some(wait))
+            .map_err(CommError::from)
+            .and_then(|()| comm::receive(stream.deref_mut()));
+
+        self.note_failure(&result);
+
+        match result {
+            Ok(msg) => {
+                self.transcript.lock().push(Message::Received(msg.clone()));
+                Some(msg)
+            }
+
+            Err(CommError::TimedOut) => None,
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", self.name, err);
+                None
+            }
+        }
+    }
+
+    /// Returns `true` if we've lost the connection to the player's client.
+    fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the player's role. Panics if the role has not been assigned yet.
+    fn role(&self) -> Role {
+        self.role.expect("No role given")
+    }
+
+    /// Returns `true` if the player's role has something to do during the night, given whether
+    /// it's the first night of the game.
+    fn acts_at_night(&self, first_night: bool) -> bool {
+        match self.role() {
+            Role::Wolf
+            | Role::WolfCub
+            | Role::Seer
+            | Role::Doctor
+            | Role::Witch
+            | Role::Bodyguard => true,
+            Role::Cupid => first_night,
+            _ => false,
+        }
+    }
+}
+
+struct Game<T: Transport> {
+    /// The players participating in the game. These are kept in ID order (and therefore in the
+    /// order that they joined) so that iterating over them is predictable.
+    players: BTreeMap<PlayerId, Player<T>>,
+
+    /// The people watching the game without playing in it.
+    spectators: Vec<Player<T>>,
+
+    /// Connections made after the game started, which are let in as spectators between nights
+    /// and days. This is `None` until the game starts, since players are let in by the lobby
+    /// before then.
+    late_joins: Option<mpsc::Receiver<Arrival<T>>>,
+
+    /// How long players' clients can be quiet while we're waiting for them before we ping them,
+    /// if at all.
+    heartbeat: Option<Duration>,
+
+    /// The part of the game that is being played, which players who rejoin are told about.
+    phase: Phase,
+
+    /// The next available player ID for this game.
+    next_id: PlayerId,
+
+    /// The rules the game is being played with.
+    rules: GameRules,
+
+    /// The roles that are handed out when the game starts.
+    roles: RoleSetup,
+
+    /// Wolves who have killed a knight and will die at the start of the next day, along with the
+    /// IDs of the knights they killed.
+    pending_revenge: Vec<(PlayerId, PlayerId)>,
+
+    /// Players who were poisoned during the night and will die at the start of the day, along
+    /// with the IDs of the witches who poisoned them.
+    pending_poison: Vec<(PlayerId, PlayerId)>,
+
+    /// Whether the village has lost the right to vote anybody out today, because it voted out a
+    /// saint yesterday.
+    lynch_forbidden: bool,
+
+    /// The two players that cupid made fall in love, if any.
+    lovers: Option<(PlayerId, PlayerId)>,
+
+    /// Whether the wolf cub has died since the last night, so the wolves kill twice tonight.
+    pack_enraged: bool,
+
+    /// The number of investigations that the village has left to use.
+    investigations_left: usize,
+
+    /// The number of times each player has been the first to vote on a day.
+    times_led: HashMap<PlayerId, usize>,
+
+    /// The total time spent playing through nights.
+    night_time: Duration,
+
+    /// The total time spent playing through days.
+    day_time: Duration,
+
+    /// Everything that has happened in the game so far, in order.
+    events: Vec<Event>,
+
+    /// The seed that the game's randomness comes from, which is enough to reproduce the game.
+    seed: u64,
+
+    /// The seed that the current game's randomness comes from. This is drawn from the room's
+    /// randomness when each game starts, so that a game can be replayed without everything that
+    /// happened in the room before it.
+    game_seed: u64,
+
+    /// The source of randomness for the game. This is seeded once when the game is created so
+    /// that a game can be reproduced from its seed.
+    rng: StdRng,
+}
+
+impl<T: Transport> Game<T> {
+    fn new(rules: GameRules, roles: RoleSetup, seed: Option<u64>) -> Game<T> {
+        let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+
+        let investigations_left = rules.village_investigations;
+
+        Game {
+            players: BTreeMap::new(),
+            spectators: vec![],
+            late_joins: None,
+            heartbeat: None,
+            phase: Phase::Lobby,
+            next_id: PlayerId::new(),
+            rules,
+            roles,
+            pending_revenge: vec![],
+            pending_poison: vec![],
+            lynch_forbidden: false,
+            lovers: None,
+            pack_enraged: false,
+            investigations_left,
+            times_led: HashMap::new(),
+            night_time: Duration::ZERO,
+            day_time: Duration::ZERO,
+            events: vec![],
+            seed,
+            game_seed: seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Gives the next game a seed of its own, drawn from the room's randomness.
+    fn reseed(&mut self) {
+        self.game_seed = self.rng.gen();
+        self.rng = StdRng::seed_from_u64(self.game_seed);
+    }
+
+    /// Plays the game from start to finish, and returns the side that won.
+    fn play(&mut self) -> Winner {
+        // Names are settled once the game starts, so any renames that haven't been dealt with
+        // yet are too late. Transcripts start from here too, since that's where a replay starts.
+        for player in self.players.values() {
+            player.unsolicited.lock().clear();
+            player.transcript.lock().clear();
+        }
+
+        self.assign_roles();
+        self.announce_setup();
+
+        // Players are seated in the order they joined, which is the order we keep them in.
+        if self.rules.seating {
+            self.send_all(&StcMessage::Seating(self.players.keys().copied().collect()));
+        }
+
+        // Without a first night, the game opens with a day, before anybody has died.
+        if self.rules.skip_first_night {
+            self.phase = Phase::Day(0);
+
+            let day_start = Instant::now();
+            let winner = self.hold_day();
+            self.day_time += day_start.elapsed();
+
+            if let Some(winning_side) = winner {
+                self.end(winning_side);
+                return winning_side;
+            }
+        }
+
+        let winner = loop {
+            self.admit_late_joins();
+
+            // Anyone who lost their connection during the last day is out of the game, and that
+            // could be enough to decide it.
+            if let Some(winning_side) = self.drop_disconnected() {
+                break winning_side;
+            }
+
+            let night_start = Instant::now();
+            let night = self.play_night();
+            self.night_time += night_start.elapsed();
+
+            let kills = match night {
+                Ok(kills) => kills,
+                Err(winning_side) => {
+                    break winning_side;
+                }
+            };
+
+            self.admit_late_joins();
+
+            if let Some(winning_side) = self.drop_disconnected() {
+                break winning_side;
+            }
+
+            // Play one day, and if either side wins, report that and end the game.
+            let day_start = Instant::now();
+            let winner = self.play_day(kills);
+            self.day_time += day_start.elapsed();
+
+            if let Some(winning_side) = winner {
+                break winning_side;
+            }
+        };
+
+        self.end(winner);
+        winner
+    }
+
+    /// Returns `true` if the roles have been handed out, so it's too late for anyone else to join.
+    fn started(&self) -> bool {
+        self.players.values().any(|p| p.role.is_some())
+    }
+
+    /// Lets in everyone who has connected since we last checked. The game has already started,
+    /// so they can only spectate, unless they're rejoining.
+    fn admit_late_joins(&mut self) {
+        let Some(joins) = &self.late_joins else {
+            return;
+        };
+
+        let streams: Vec<Arrival<T>> = joins.try_iter().collect();
+
+        for (stream, intro) in streams {
+            if let Err(err) = Player::join(self, stream, intro) {
+                warn!("Dropping new connection: {}", err);
+            }
+        }
+    }
+
+    /// Gives the player with the given ID their seat back over a new connection, as long as the
+    /// token matches the one they were given when they joined. They're told enough about the
+    /// game to pick up where they left off.
+    ///
+    /// Anything they were in the middle of over the old connection is lost, so a player who
+    /// rejoins halfway through a vote will be asked for their vote if their turn hasn't come yet,
+    /// but not if it has already passed.
+    fn reconnect(&mut self, id: PlayerId, token: Token, mut stream: T) -> Result<(), CommError> {
+        let Some(player) = self.players.get_mut(&id).filter(|p| p.token == token) else {
+            warn!(
+                "Rejecting attempt to rejoin as {:?} with the wrong token",
+                id
+            );
+
+            // There's no player to talk through, so we send this straight down the stream.
+            comm::send(&mut stream, &StcMessage::RejoinRejected)?;
+
+            return Ok(());
+        };
+
+        info!("{} rejoined the game", player.name);
+
+        // If everyone was told that the player's seat was being held, they're told that it was
+        // worth it.
+        if player.dropped_at.take().is_some() {
+            self.send_all(&StcMessage::PlayerReconnected(id));
+        }
+
+        let player = &self.players[&id];
+
+        // Replace the old connection. Any replies we were still owed would have come over that
+        // connection, so they'll never arrive now.
+        *player.stream.lock() = stream;
+        player.disconnected.store(false, Ordering::Relaxed);
+        player.owed_replies.store(0, Ordering::Relaxed);
+
+        player.post(&StcMessage::Reconnected(
+            player.role,
+            player.dead,
+            self.phase,
+        ))?;
+
+        player.tell(&StcMessage::Players(
+            self.players
+                .iter()
+                .map(|(&id, p)| (id, p.name.clone(), p.colour))
+                .collect(),
+        ));
+
+        player.tell(&StcMessage::GameSnapshot(self.snapshot()));
+
+        // A wolf or minion needs to know who they're working with again.
+        if matches!(player.role, Some(Role::Wolf | Role::WolfCub | Role::Minion)) && !player.dead {
+            let teammates = self
+                .living_wolves()
+                .into_iter()
+                .filter(|&wolf_id| wolf_id != id)
+                .collect();
+
+            player.tell(&StcMessage::WolfTeammates(teammates));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the host, who is the first player to join who is still connected. This is normally
+    /// the player on the machine running the game.
+    fn host(&self) -> Option<&Player<T>> {
+        self.players.values().find(|p| !p.is_disconnected())
+    }
+
+    /// Returns `true` if the given player is ready to start. The host doesn't have to say, since
+    /// they're the one who starts the game.
+    fn is_ready(&self, player: &Player<T>) -> bool {
+        player.ready || self.host().is_some_and(|host| host.id == player.id)
+    }
+
+    /// Returns `true` if every connected player is ready to start.
+    fn everyone_ready(&self) -> bool {
+        self.players
+            .values()
+            .filter(|p| !p.is_disconnected())
+            .all(|p| self.is_ready(p))
+    }
+
+    /// Asks every connected player who isn't ready yet whether they are, and then tells everyone
+    /// who is ready.
+    fn check_ready(&mut self) {
+        let unready: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.is_disconnected() && !self.is_ready(p))
+            .map(|p| p.id)
+            .collect();
+
+        for id in unready {
+            let player = self.players.get_mut(&id).unwrap();
+
+            match player.send(&StcMessage::AskReady) {
+                Ok(CtsMessage::Ready(ready)) => player.ready = ready,
+                Ok(_) => (),
+                Err(err) => warn!("Lost connection to {}: {}", player.name, err),
+            }
+        }
+
+        let lobby = self
+            .players
+            .values()
+            .filter(|p| !p.is_disconnected())
+            .map(|p| (p.id, self.is_ready(p)))
+            .collect();
+
+        self.send_all(&StcMessage::LobbyState(lobby));
+    }
+
+    /// Asks the host whether they want to start the game now, even if not everyone is ready.
+    fn host_wants_to_start(&self) -> bool {
+        let Some(host) = self.host() else {
+            return false;
+        };
+
+        // If the roles were chosen for bigger games, the host has to wait for more players.
+        let needed = self.roles.min_players();
+
+        if self.players.len() < needed {
+            host.tell(&StcMessage::NotEnoughPlayers(needed));
+
+            return false;
+        }
+
+        match host.send(&StcMessage::AskToStart(self.everyone_ready())) {
+            Ok(CtsMessage::StartGame) => true,
+            Ok(_) => false,
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", host.name, err);
+                false
+            }
+        }
+    }
+
+    /// Counts down to the start of the game, telling every player how long is left once per
+    /// second until the given length of time has passed. Returns `false` if the countdown was
+    /// called off because somebody said they weren't ready after all.
+    fn count_down(&mut self, length: Duration) -> bool {
+        let mut remaining = length;
+
+        while !remaining.is_zero() {
+            self.send_all(&StcMessage::StartingIn(remaining));
+
+            // Wait for a second, or for whatever is left if that's less than a second, checking
+            // every so often for anyone backing out.
+            let tick = remaining.min(Duration::from_secs(1));
+            let tick_end = Instant::now() + tick;
+
+            loop {
+                if let Some(id) = self.handle_unprompted() {
+                    let player = self.players.get_mut(&id).unwrap();
+                    player.ready = false;
+
+                    info!(
+                        "{} isn't ready any more, so the countdown is off",
+                        player.name
+                    );
+                    self.send_all(&StcMessage::CountdownCancelled(id));
+
+                    return false;
+                }
+
+                let left = tick_end.saturating_duration_since(Instant::now());
+
+                if left.is_zero() {
+                    break;
+                }
+
+                std::thread::sleep(left.min(COUNTDOWN_POLL_INTERVAL));
+            }
+
+            remaining -= tick;
+        }
+
+        true
+    }
+
+    /// Deals with everything that players have sent without being asked, which before the game
+    /// starts means renames and players backing out. Returns the ID of a player who has told us
+    /// that they aren't ready after all, if anyone has. Anything else is thrown away.
+    fn handle_unprompted(&mut self) -> Option<PlayerId> {
+        let mut backed_out = None;
+        let ids: Vec<PlayerId> = self.players.keys().copied().collect();
+
+        for id in ids {
+            while let Some(msg) = self.players[&id].poll(Duration::from_millis(1)) {
+                match msg {
+                    CtsMessage::Rename(name) => self.rename(id, name),
+
+                    CtsMessage::Ready(false) => {
+                        self.players.get_mut(&id).unwrap().ready = false;
+                        backed_out.get_or_insert(id);
+                    }
+
+                    msg => debug!(
+                        "Ignoring unprompted {:?} from {}",
+                        msg, self.players[&id].name
+                    ),
+                }
+            }
+        }
+
+        backed_out
+    }
+
+    /// Gives a player the name they asked for, as long as it's one that a new player could join
+    /// with. Everybody is told about the change so that they know who's who.
+    fn rename(&mut self, id: PlayerId, name: String) {
+        if let Err(reason) = self.check_name(&name, Some(id)) {
+            self.players[&id].tell(&StcMessage::RenameRejected(reason));
+            return;
+        }
+
+        let player = self.players.get_mut(&id).unwrap();
+        info!("{} is now called {}", player.name, name);
+        player.name = name.clone();
+
+        self.send_all(&StcMessage::PlayerRenamed(id, name));
+    }
+
+    /// Ends the game with a win for the given side. Once the players know which side won, each
+    /// player's role is revealed in turn with a pause before each one, and then the winner is
+    /// formally announced.
+    fn end(&mut self, winner: Winner) {
+        self.admit_late_joins();
+        self.phase = Phase::Over;
+
+        self.events.push(Event::Won(winner));
+        self.send_all(&StcMessage::GameEnding(winner));
+
+        for player in self.players.values() {
+            std::thread::sleep(self.rules.reveal_delay);
+            self.send_all(&StcMessage::RoleReveal(player.id, player.role()));
+        }
+
+        std::thread::sleep(self.rules.reveal_delay);
+        self.send_all(&StcMessage::AnnounceWinner(winner));
+    }
+
+    /// Asks every player whether they want to play again, and gets the game ready for another
+    /// round with those who do. Everyone else, including the spectators, is disconnected. Players
+    /// who don't answer within `timeout` are taken to be leaving, unless it's zero.
+    ///
+    /// Everyone is asked at once, so that one player who is slow to decide doesn't hold up the
+    /// others' answers.
+    fn rematch(&mut self, timeout: Duration) {
+        let timeout = Some(timeout).filter(|t| !t.is_zero());
+
+        let staying: Vec<PlayerId> = std::thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .players
+                .values()
+                .filter(|p| !p.is_disconnected())
+                .map(|player| {
+                    scope.spawn(move || {
+                        let answer = player.send_with_timeout(&StcMessage::GameOver, timeout);
+                        (player, answer)
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .filter_map(
+                    |handle| match handle.join().expect("Rematch thread panicked") {
+                        (player, Ok(CtsMessage::PlayAgain(true))) => Some(player.id),
+                        (player, Ok(_)) => {
+                            info!("{} doesn't want to play again", player.name);
+                            None
+                        }
+                        (player, Err(err)) => {
+                            info!("{} didn't say whether to play again: {}", player.name, err);
+                            None
+                        }
+                    },
+                )
+                .collect()
+        });
+
+        let leaving: Vec<PlayerId> = self
+            .players
+            .keys()
+            .copied()
+            .filter(|id| !staying.contains(id))
+            .collect();
+
+        for id in leaving {
+            let player = self.players.remove(&id).unwrap();
+
+            if !player.is_disconnected() {
+                player.tell(&StcMessage::SessionClosed);
+            }
+        }
+
+        for spectator in self.spectators.drain(..) {
+            spectator.tell(&StcMessage::SessionClosed);
+        }
+
+        for player in self.players.values_mut() {
+            player.reset();
+        }
+
+        self.phase = Phase::Lobby;
+        self.pending_revenge.clear();
+        self.pending_poison.clear();
+        self.lynch_forbidden = false;
+        self.lovers = None;
+        self.pack_enraged = false;
+        self.investigations_left = self.rules.village_investigations;
+        self.times_led.clear();
+        self.night_time = Duration::ZERO;
+        self.day_time = Duration::ZERO;
+        self.events.clear();
+
+        // The players who stayed have been told about everyone who was in the last game, so we
+        // tell them who is left.
+        self.send_all(&StcMessage::Players(
+            self.players
+                .iter()
+                .map(|(&id, p)| (id, p.name.clone(), p.colour))
+                .collect(),
+        ));
+    }
+
+    /// Adds the given player to the game after announcing that they are joining.
+    fn add_player(&mut self, player: Player<T>) {
+        self.send_all(&StcMessage::AnnounceJoin(
+            player.id,
+            player.name.clone(),
+            player.colour,
+        ));
+
+        // Tell the new player about all the players who are already in the game.
+        player.tell(&StcMessage::Players(
+            self.players
+                .iter()
+                .map(|(&id, p)| (id, p.name.clone(), p.colour))
+                .collect(),
+        ));
+
+        let id = player.id;
+        self.players.insert(id, player);
+
+        // The snapshot is taken once the player is in the game, so that they're counted among
+        // the living.
+        self.players[&id].tell(&StcMessage::GameSnapshot(self.snapshot()));
+    }
+
+    /// Adds the given spectator to the game, telling them who's playing and (if the game has
+    /// started and the rules allow it) who has which role.
+    fn add_spectator(&mut self, spectator: Player<T>) {
+        spectator.tell(&StcMessage::Players(
+            self.players
+                .iter()
+                .map(|(&id, p)| (id, p.name.clone(), p.colour))
+                .collect(),
+        ));
+
+        spectator.tell(&StcMessage::GameSnapshot(self.snapshot()));
+
+        if self.started() && self.sees_secrets(&spectator) {
+            spectator.tell(&StcMessage::Secret(Secret::Roles(self.role_list())));
+        }
+
+        self.spectators.push(spectator);
+    }
+
+    /// Returns the state of the game as it stands, with everything that every player has seen
+    /// happen so far, for someone who is joining partway through.
+    fn snapshot(&self) -> GameSnapshot {
+        let mut nights = 0;
+
+        let history = self
+            .events
+            .iter()
+            .filter_map(|event| match *event {
+                Event::NightFell => {
+                    nights += 1;
+                    Some(PublicEvent::NightFell(nights))
+                }
+                Event::Voted(voter, target) => Some(PublicEvent::Voted(voter, target)),
+                Event::Died(id, _) => Some(PublicEvent::Died(id)),
+                Event::DayBroke | Event::Won(_) => None,
+            })
+            .collect();
+
+        GameSnapshot {
+            phase: self.phase,
+            living: self
+                .players
+                .values()
+                .filter(|p| !p.dead)
+                .map(|p| p.id)
+                .collect(),
+            history,
+        }
+    }
+
+    /// Checks whether a new player can join with the given name, and returns the reason why not
+    /// if they can't. When an existing player wants to change their name, `renaming` is their ID,
+    /// so that their current name doesn't count as taken.
+    fn check_name(&self, name: &str, renaming: Option<PlayerId>) -> Result<(), NameRejection> {
+        if name.trim().is_empty() {
+            return Err(NameRejection::Empty);
+        }
+
+        if name.chars().count() > comm::MAX_NAME_LEN {
+            return Err(NameRejection::TooLong);
+        }
+
+        // This catches the escape character that starts ANSI escape codes, as well as newlines
+        // and the like.
+        if name.chars().any(char::is_control) {
+            return Err(NameRejection::ControlCharacters);
+        }
+
+        if self
+            .players
+            .values()
+            .filter(|p| Some(p.id) != renaming)
+            .any(|p| p.name.to_lowercase() == name.to_lowercase())
+        {
+            return Err(NameRejection::Taken);
+        }
+
+        Ok(())
+    }
+
+    /// Returns every player's ID along with their role.
+    fn role_list(&self) -> Vec<(PlayerId, Role)> {
+        self.players.values().map(|p| (p.id, p.role())).collect()
+    }
+
+    /// Tells the spectators about something that the players can't see, if the rules let them
+    /// see it.
+    fn reveal_to_spectators(&self, secret: Secret) {
+        let message = StcMessage::Secret(secret);
+
+        for spectator in &self.spectators {
+            if !spectator.is_disconnected() && self.sees_secrets(spectator) {
+                spectator.tell(&message);
+            }
+        }
+    }
+
+    /// Returns whether the given spectator can be shown what the players can't see. Someone who is
+    /// still playing could open a second connection to spectate from, so we don't show secrets to
+    /// a spectator who connected from the same address as a living player.
+    fn sees_secrets(&self, spectator: &Player<T>) -> bool {
+        self.rules.spectators_see_secrets
+            && !self
+                .players
+                .values()
+                .any(|p| !p.dead && p.address.is_some() && p.address == spectator.address)
+    }
+
+    /// Returns the colour that a new player should have, given the colour they asked for. They get
+    /// it if nobody else has it, and otherwise they get a random colour that nobody else has. If
+    /// every colour has been taken, we have no choice but to hand out one that's already in use.
+    fn pick_colour(&mut self, requested: Option<Colour>) -> Colour {
+        let taken: Vec<Colour> = self
+            .players
+            .values()
+            .filter(|p| !p.is_disconnected())
+            .map(|p| p.colour)
+            .collect();
+
+        if let Some(colour) = requested.filter(|c| !taken.contains(c)) {
+            return colour;
+        }
+
+        let free: Vec<Colour> = Colour::ALL
+            .into_iter()
+            .filter(|c| !taken.contains(c))
+            .collect();
+
+        if let Some(&colour) = free.choose(&mut self.rng) {
+            return colour;
+        }
+
+        *Colour::ALL.choose(&mut self.rng).unwrap()
+    }
+
+    /// Returns a player ID that can be used for a new player joining the game.
+    ///
+    /// An identical player ID will not be generated by this `Game` instance on any subsequent call
+    /// to this method.
+    fn take_next_id(&mut self) -> PlayerId {
+        let id = self.next_id;
+
+        // Get another player ID for the next call to this method, since we've used this one
+        // already.
+        self.next_id = id.next();
+
+        id
+    }
+
+    /// Assigns a random role to each player.
+    fn assign_roles(&mut self) {
+        // Shuffle the players so that we can hand out roles in order.
+        let mut ids: Vec<PlayerId> = self.players.keys().copied().collect();
+        ids.shuffle(&mut self.rng);
+
+        if ids.len() <= self.roles.specials.len() {
+            warn!("Not enough players for every special role, so some won't be given out");
+        }
+
+        for (id, role) in ids.into_iter().zip(self.roles_in_play()) {
+            let player = self.players.get_mut(&id).unwrap();
+
+            debug!("{} is a {:?}", player.name, role);
+
+            player.role = Some(role);
+            player.tell(&StcMessage::RoleAssigned(role));
+        }
+
+        if self.rules.godfather {
+            if let Some(&id) = self.living_wolves().choose(&mut self.rng) {
+                let godfather = self.players.get_mut(&id).unwrap();
+
+                debug!("{} is the godfather", godfather.name);
+                godfather.seer_immune = true;
+            }
+        }
+
+        // A lone wolf needs a pack to turn on.
+        let wolves = self.living_wolves();
+
+        if self.rules.lone_wolf && wolves.len() > 1 {
+            let id = *wolves.choose(&mut self.rng).unwrap();
+            let lone_wolf = self.players.get_mut(&id).unwrap();
+
+            debug!("{} is the lone wolf", lone_wolf.name);
+            lone_wolf.lone_wolf = true;
+            lone_wolf.tell(&StcMessage::LoneWolf);
+        }
+
+        self.reveal_to_spectators(Secret::Roles(self.role_list()));
+    }
+
+    /// Returns the roles that are given out in this game, with one role for each player.
+    ///
+    /// The wolves always come first, then the special roles, and then the villagers.
+    fn roles_in_play(&self) -> Vec<Role> {
+        // If the host gave exact counts for this many players, those decide the roles.
+        if let Some(counts) = self.roles.counts_for(self.players.len()) {
+            return counts
+                .roles
+                .iter()
+                .flat_map(|&(role, count)| std::iter::repeat_n(role, count))
+                .chain(std::iter::repeat(Role::Villager))
+                .take(self.players.len())
+                .collect();
+        }
+
+        // We give out one wolf for every few players, but there has to be at least one or there
+        // would be no game. The special roles go to the next players, and everyone left over is a
+        // villager.
+        let wolves = (self.players.len() / self.roles.players_per_wolf.max(1)).max(1);
+
+        std::iter::repeat_n(Role::Wolf, wolves)
+            .chain(self.roles.specials.iter().copied())
+            .chain(std::iter::repeat(Role::Villager))
+            .take(self.players.len())
+            .collect()
+    }
+
+    /// Tells everyone about the roles in play, revealing as much as the rules allow.
+    fn announce_setup(&self) {
+        let roles = self.roles_in_play();
+
+        let setup = match self.rules.setup_reveal {
+            SetupReveal::Full => Setup::Full(roles),
+            SetupReveal::Partial => Setup::Partial(
+                roles
+                    .iter()
+                    .filter(|role| !matches!(role, Role::Wolf | Role::Villager))
+                    .count(),
+            ),
+            SetupReveal::Hidden => Setup::Hidden,
+        };
+
+        self.send_all(&StcMessage::GameSetup(setup));
+    }
+
+    /// Plays through one night in the game, returning the IDs of the players killed by the
+    /// werewolves, each with the ID of the werewolf that killed them. This is normally one
+    /// player, or two if the wolf cub died since the last night. Anyone who was saved (by a
+    /// doctor, for example) is left out.
+    ///
+    /// The player isn't actually killed until their death is announced at the start of the next
+    /// day.
+    ///
+    /// If there's nobody left for the wolves to kill, the night doesn't happen and the wolves'
+    /// win is returned as an error instead.
+    ///
+    /// Once the wolves have chosen, anyone who can save their victim acts in a fixed order:
+    ///
+    /// 1. A bodyguard guarding the victim blocks the attack, or takes it in the victim's place if
+    ///    the rules say so.
+    /// 2. A doctor protecting whoever is still under attack saves them. Protecting the victim
+    ///    doesn't help a bodyguard who has taken their place.
+    /// 3. A witch is told who is still under attack, and can heal them.
+    ///
+    /// Poison is a separate death, so nothing stops it. The witch can't poison anyone who is
+    /// already under attack.
+    fn play_night(&mut self) -> Result<Vec<(PlayerId, PlayerId)>, Winner> {
+        // Find the non-wolf players. These are the players that can be killed by the wolf.
+        let kill_candidates: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead && !p.role().is_wolf())
+            .map(|p| p.id)
+            .collect();
+
+        // If every non-wolf is already dead, the wolf's client would be given an empty menu that
+        // it can never choose from, and the game would hang. There's nobody left to stand in the
+        // wolves' way, so they've won.
+        if kill_candidates.is_empty() {
+            info!("Nobody left for the wolves to kill, so ending the game");
+            return Err(Winner::Wolf);
+        }
+
+        // The wolves are told who their teammates are when they first wake up.
+        let first_night = !self.events.iter().any(|e| matches!(e, Event::NightFell));
+
+        // Tell all the players that night has fallen.
+        self.events.push(Event::NightFell);
+
+        let nights = self
+            .events
+            .iter()
+            .filter(|e| matches!(e, Event::NightFell))
+            .count();
+
+        self.phase = Phase::Night(nights);
+        self.send_all(&StcMessage::NightFalls);
+
+        // Let the living players who have nothing to do tonight know that they can relax, so they
+        // don't think the game has frozen while the others act.
         for player in self.players.values() {
-            player.send(message);
+            if !player.dead && !player.acts_at_night(first_night) {
+                player.tell(&StcMessage::SleepTight);
+            }
+        }
+
+        // Before anyone wakes, the dead get to talk among themselves.
+        self.discuss(Channel::Graveyard);
+
+        // Before anyone else wakes on the first night, cupid chooses two players to fall in love.
+        // There's only one pair of lovers, so if there's more than one cupid, only the first one
+        // gets to choose.
+        if first_night {
+            if let Some(&cupid_id) = self.living_with_role(Role::Cupid).first() {
+                self.wait_for_host("wake cupid");
+                self.match_lovers(cupid_id);
+            }
+        }
+
+        // Tell all the players that the wolves have woken up.
+        self.wait_for_host("wake the wolves");
+        self.send_all(&StcMessage::WolvesWake);
+
+        let wolves = self.living_wolves();
+
+        if first_night {
+            for &wolf_id in &wolves {
+                let teammates = wolves.iter().copied().filter(|&id| id != wolf_id).collect();
+                self.players[&wolf_id].tell(&StcMessage::WolfTeammates(teammates));
+            }
+
+            // Minions are shown the wolves, but the wolves aren't shown the minions.
+            for minion_id in self.living_with_role(Role::Minion) {
+                self.players[&minion_id].tell(&StcMessage::WolfTeammates(wolves.clone()));
+            }
+        }
+
+        // The wolves get to agree on a plan before they choose, if there's more than one of them.
+        if wolves.len() > 1 {
+            self.discuss(Channel::Wolves);
+        }
+
+        let mut kills: Vec<(PlayerId, PlayerId)> = self
+            .choose_victim(&wolves, &kill_candidates)
+            .into_iter()
+            .collect();
+
+        // If the wolf cub has died since the last night, the pack takes it out on someone else as
+        // well.
+        if std::mem::take(&mut self.pack_enraged) {
+            let others: Vec<PlayerId> = kill_candidates
+                .iter()
+                .copied()
+                .filter(|&id| kills.iter().all(|&(kill_id, _)| kill_id != id))
+                .collect();
+
+            if !others.is_empty() {
+                self.send_all(&StcMessage::PackEnraged);
+
+                let second = self.choose_victim(&wolves, &others);
+                kills.extend(second);
+            }
+        }
+
+        for &(kill_id, _) in &kills {
+            self.reveal_to_spectators(Secret::WolvesChose(kill_id));
+        }
+
+        // The doctors and bodyguards who chose someone to protect, and whether it saved them.
+        let mut protection = vec![];
+
+        // Once the wolves have chosen, any living bodyguards get to guard someone. If one of them
+        // guards one of the wolves' victims, that attack is blocked, and the bodyguard may die
+        // instead.
+        for bodyguard_id in self.living_with_role(Role::Bodyguard) {
+            self.wait_for_host("wake the bodyguard");
+
+            let guarded = self.guard(bodyguard_id);
+
+            if let Some(guarded_id) = guarded {
+                self.reveal_to_spectators(Secret::Guarded(bodyguard_id, guarded_id));
+            }
+
+            let blocked = kills
+                .iter()
+                .position(|&(kill_id, _)| guarded == Some(kill_id));
+
+            if guarded.is_some() {
+                protection.push((bodyguard_id, blocked.is_some()));
+            }
+
+            if let Some(i) = blocked {
+                if self.rules.bodyguard_dies {
+                    info!("The bodyguard took the wolves' attack on themselves");
+                    kills[i].0 = bodyguard_id;
+                } else {
+                    info!("The wolves' victim was guarded, so they survive the night");
+                    kills.remove(i);
+                }
+            }
+        }
+
+        // Any living doctors get to protect someone too. If any of them chose one of the wolves'
+        // victims, that victim survives the night.
+        for doctor_id in self.living_with_role(Role::Doctor) {
+            self.wait_for_host("wake the doctor");
+
+            let protected = self.protect(doctor_id);
+
+            if let Some(protected_id) = protected {
+                self.reveal_to_spectators(Secret::Protected(doctor_id, protected_id));
+
+                let saved = kills.iter().any(|&(kill_id, _)| kill_id == protected_id);
+                protection.push((doctor_id, saved));
+
+                if saved {
+                    info!("The wolves' victim was protected, so they survive the night");
+                    kills.retain(|&(kill_id, _)| kill_id != protected_id);
+                }
+            }
+        }
+
+        // Any living witches are told who is about to die, and can save them or poison someone
+        // else. The poison doesn't take effect until morning, alongside the wolves' kill. A
+        // potion can only save one player, so if the wolves are killing twice tonight, the witch
+        // is only told about the first.
+        for witch_id in self.living_with_role(Role::Witch) {
+            self.wait_for_host("wake the witch");
+
+            match self.brew(witch_id, kills.first().map(|&(kill_id, _)| kill_id)) {
+                WitchAction::Heal => {
+                    info!("The witch saved the wolves' victim, so they survive the night");
+                    kills.remove(0);
+                }
+
+                WitchAction::Poison(id) => self.pending_poison.push((id, witch_id)),
+                WitchAction::Pass => (),
+            }
+        }
+
+        // Any living seers get to find out about someone too.
+        for seer_id in self.living_with_role(Role::Seer) {
+            self.wait_for_host("wake the seer");
+            self.inspect(seer_id);
+        }
+
+        // Now that everyone has acted, the doctors and bodyguards find out whether anyone needed
+        // their protection, but nothing about who attacked.
+        for (id, saved) in protection {
+            self.players[&id].tell(&StcMessage::ProtectionResult(saved));
+        }
+
+        self.wait_for_host("end the night");
+
+        // Return the IDs of the killed players and their killers for use in the day phase.
+        Ok(kills)
+    }
+
+    /// Asks each of the given wolves who they want to kill, and returns the ID of the player that
+    /// the wolves chose along with the ID of a wolf who chose them. If we lost contact with every
+    /// wolf, nobody is chosen and `None` is returned.
+    ///
+    /// The player chosen by the most wolves is killed. If there's a tie, one of the tied players
+    /// is picked at random.
+    fn choose_victim(
+        &mut self,
+        wolves: &[PlayerId],
+        candidates: &[PlayerId],
+    ) -> Option<(PlayerId, PlayerId)> {
+        // Each victim maps to the wolves that chose them. We keep these in a `BTreeMap` so that
+        // the seed alone decides how ties are broken.
+        let mut choices = BTreeMap::<PlayerId, Vec<PlayerId>>::new();
+
+        for &wolf_id in wolves {
+            let wolf = &self.players[&wolf_id];
+
+            // A wolf that picks someone they weren't offered, or answers with something other than
+            // a kill, is asked again, but only a few times, so that a broken client can't hold the
+            // night up forever.
+            let mut attempts = 0;
+
+            let kill_id = loop {
+                // Send the wolf the list of players that they can kill. This should trigger their
+                // client to ask them for and send back their choice of player.
+                match wolf.send(&StcMessage::KillOptions(candidates.to_vec())) {
+                    Ok(CtsMessage::Kill(id)) if candidates.contains(&id) => break Some(id),
+
+                    Ok(CtsMessage::Kill(id)) => {
+                        warn!("{} tried to kill non-candidate {:?}", wolf.name, id)
+                    }
+
+                    Ok(msg) => warn!("Expected kill from {}, got {:?}", wolf.name, msg),
+
+                    Err(err) => {
+                        warn!("Lost connection to {}: {}", wolf.name, err);
+                        break None;
+                    }
+                }
+
+                attempts += 1;
+
+                if attempts == MAX_PICK_ATTEMPTS {
+                    break None;
+                }
+
+                wolf.tell(&StcMessage::InvalidChoice);
+            };
+
+            let Some(kill_id) = kill_id else {
+                continue;
+            };
+
+            choices.entry(kill_id).or_default().push(wolf_id);
+        }
+
+        let most = choices.values().map(Vec::len).max().unwrap_or(0);
+
+        let tied: Vec<(PlayerId, PlayerId)> = choices
+            .into_iter()
+            .filter(|(_, chosen_by)| chosen_by.len() == most)
+            .map(|(kill_id, chosen_by)| (kill_id, chosen_by[0]))
+            .collect();
+
+        tied.choose(&mut self.rng).copied()
+    }
+
+    /// Returns the IDs of the living wolves, including the wolf cub.
+    fn living_wolves(&self) -> Vec<PlayerId> {
+        self.players
+            .values()
+            .filter(|p| !p.dead && p.role().is_wolf())
+            .map(|p| p.id)
+            .collect()
+    }
+
+    /// Returns the IDs of the living players with the given role.
+    fn living_with_role(&self, role: Role) -> Vec<PlayerId> {
+        self.players
+            .values()
+            .filter(|p| !p.dead && p.role() == role)
+            .map(|p| p.id)
+            .collect()
+    }
+
+    /// Asks the given doctor who they want to protect tonight, returning the ID of the player
+    /// they chose, or `None` if they didn't choose anyone valid.
+    fn protect(&self, doctor_id: PlayerId) -> Option<PlayerId> {
+        let doctor = &self.players[&doctor_id];
+
+        // The doctor can protect anyone who is still alive. Whether that includes themselves is
+        // up to the rules.
+        let options: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead && (p.id != doctor_id || self.rules.doctor_self_protect))
+            .map(|p| p.id)
+            .collect();
+
+        match doctor.send(&StcMessage::ProtectOptions(options.clone())) {
+            Ok(CtsMessage::Protect(id)) if options.contains(&id) => Some(id),
+
+            Ok(msg) => {
+                warn!("Expected protection from {}, got {:?}", doctor.name, msg);
+                None
+            }
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", doctor.name, err);
+                None
+            }
+        }
+    }
+
+    /// Asks the given cupid which two players should fall in love, and tells each of the lovers
+    /// who the other is. If cupid doesn't choose two different players, nobody falls in love.
+    fn match_lovers(&mut self, cupid_id: PlayerId) {
+        let cupid = &self.players[&cupid_id];
+
+        // Cupid can choose anyone, including themselves.
+        let options: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead)
+            .map(|p| p.id)
+            .collect();
+
+        if options.len() < 2 {
+            return;
+        }
+
+        let (first, second) = match cupid.send(&StcMessage::ChooseLovers(options.clone())) {
+            Ok(CtsMessage::ChooseLovers(first, second))
+                if first != second && options.contains(&first) && options.contains(&second) =>
+            {
+                (first, second)
+            }
+
+            Ok(msg) => {
+                warn!("Expected two lovers from {}, got {:?}", cupid.name, msg);
+                return;
+            }
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", cupid.name, err);
+                return;
+            }
+        };
+
+        info!(
+            "{} and {} have fallen in love",
+            self.players[&first].name, self.players[&second].name
+        );
+
+        self.lovers = Some((first, second));
+
+        self.players[&first].tell(&StcMessage::LoverIs(second));
+        self.players[&second].tell(&StcMessage::LoverIs(first));
+
+        self.reveal_to_spectators(Secret::Lovers(cupid_id, first, second));
+    }
+
+    /// Returns the ID of the given player's lover, if they have one.
+    fn lover_of(&self, id: PlayerId) -> Option<PlayerId> {
+        match self.lovers {
+            Some((first, second)) if first == id => Some(second),
+            Some((first, second)) if second == id => Some(first),
+            _ => None,
+        }
+    }
+
+    /// Asks the given bodyguard who they want to guard tonight, returning the ID of the player
+    /// they chose, or `None` if they didn't choose anyone valid.
+    ///
+    /// Bodyguards can't guard themselves, or the player they guarded last night. If the client
+    /// picks someone it wasn't offered anyway, we tell it that the choice was rejected and the
+    /// bodyguard guards nobody tonight.
+    fn guard(&mut self, bodyguard_id: PlayerId) -> Option<PlayerId> {
+        let bodyguard = &self.players[&bodyguard_id];
+
+        let options: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead && p.id != bodyguard_id && Some(p.id) != bodyguard.last_guarded)
+            .map(|p| p.id)
+            .collect();
+
+        // If there's nobody left to guard, we don't ask.
+        let guarded = if options.is_empty() {
+            None
+        } else {
+            match bodyguard.send(&StcMessage::GuardOptions(options.clone())) {
+                Ok(CtsMessage::Guard(id)) if options.contains(&id) => Some(id),
+
+                Ok(CtsMessage::Guard(_)) => {
+                    warn!("{} tried to guard someone they can't", bodyguard.name);
+                    bodyguard.tell(&StcMessage::GuardRejected);
+                    None
+                }
+
+                Ok(msg) => {
+                    warn!("Expected guard from {}, got {:?}", bodyguard.name, msg);
+                    None
+                }
+
+                Err(err) => {
+                    warn!("Lost connection to {}: {}", bodyguard.name, err);
+                    None
+                }
+            }
+        };
+
+        self.players.get_mut(&bodyguard_id).unwrap().last_guarded = guarded;
+        guarded
+    }
+
+    /// Asks the given witch what they want to do with their potions tonight, given the ID of the
+    /// player the wolves are about to kill (if anyone). Each potion can only be used once, so
+    /// the witch isn't asked at all once they have nothing left that they can use.
+    ///
+    /// If the witch doesn't make a valid choice, they're treated as having passed.
+    fn brew(&mut self, witch_id: PlayerId, dying: Option<PlayerId>) -> WitchAction {
+        // There's no point poisoning the wolves' victim, or the witch themselves.
+        let targets: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead && p.id != witch_id && Some(p.id) != dying)
+            .map(|p| p.id)
+            .collect();
+
+        let witch = self.players.get_mut(&witch_id).unwrap();
+
+        let can_heal = witch.healing_potion && dying.is_some();
+        let can_poison = witch.poison && !targets.is_empty();
+
+        if !can_heal && !can_poison {
+            return WitchAction::Pass;
+        }
+
+        let prompt = StcMessage::WitchPrompt {
+            dying,
+            can_heal,
+            can_poison,
+            targets: if can_poison { targets.clone() } else { vec![] },
+        };
+
+        let action = match witch.send(&prompt) {
+            Ok(CtsMessage::WitchAction(WitchAction::Heal)) if can_heal => {
+                witch.healing_potion = false;
+                WitchAction::Heal
+            }
+
+            Ok(CtsMessage::WitchAction(WitchAction::Poison(id)))
+                if can_poison && targets.contains(&id) =>
+            {
+                witch.poison = false;
+                WitchAction::Poison(id)
+            }
+
+            Ok(CtsMessage::WitchAction(WitchAction::Pass)) => WitchAction::Pass,
+
+            Ok(msg) => {
+                warn!(
+                    "Expected valid potion use from {}, got {:?}",
+                    witch.name, msg
+                );
+                WitchAction::Pass
+            }
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", witch.name, err);
+                WitchAction::Pass
+            }
+        };
+
+        self.reveal_to_spectators(Secret::Brewed(witch_id, action));
+
+        action
+    }
+
+    /// Asks the given seer who they want to inspect, and tells them (and only them) the role of
+    /// the player they chose.
+    fn inspect(&self, seer_id: PlayerId) {
+        let seer = &self.players[&seer_id];
+
+        // The seer can inspect anyone who is still alive, apart from themselves.
+        let options: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead && p.id != seer_id)
+            .map(|p| p.id)
+            .collect();
+
+        match seer.send(&StcMessage::InspectOptions(options.clone())) {
+            Ok(CtsMessage::Inspect(id)) if options.contains(&id) => {
+                // The godfather passes for a villager, even to the seer.
+                let inspected = &self.players[&id];
+                let role = if inspected.seer_immune {
+                    Role::Villager
+                } else {
+                    inspected.role()
+                };
+
+                seer.tell(&StcMessage::InspectResult(id, role));
+                self.reveal_to_spectators(Secret::Inspected(seer_id, id, role));
+            }
+
+            Ok(msg) => warn!("Expected inspection from {}, got {:?}", seer.name, msg),
+            Err(err) => warn!("Lost connection to {}: {}", seer.name, err),
+        }
+    }
+
+    /// Gives the living players on the given channel a chance to talk, relaying what each of them
+    /// says to everyone who can listen. For the village, that includes the dead, who can listen
+    /// but not speak.
+    ///
+    /// The players take turns to speak in the order they joined. The discussion ends once the
+    /// time for it has run out, or once everyone has had a turn without saying anything.
+    fn discuss(&self, channel: Channel) {
+        let time = match channel {
+            Channel::Village => self.rules.discussion_time,
+            Channel::Wolves => self.rules.wolf_chat_time,
+            Channel::Graveyard => self.rules.dead_chat_time,
+        };
+
+        if time.is_zero() {
+            return;
+        }
+
+        let started = Instant::now();
+
+        loop {
+            let mut anyone_spoke = false;
+
+            for player in self.players.values() {
+                // Only the graveyard is for the dead, and it's only for the dead.
+                if player.dead != (channel == Channel::Graveyard) || player.is_disconnected() {
+                    continue;
+                }
+
+                if channel == Channel::Wolves && !player.role().is_wolf() {
+                    continue;
+                }
+
+                // We can't cut someone off while they're typing, so the time limit is only
+                // checked between turns.
+                if started.elapsed() >= time {
+                    return;
+                }
+
+                let prompt = match channel {
+                    Channel::Village => StcMessage::ChatPrompt,
+                    Channel::Wolves => StcMessage::WolfChatPrompt,
+                    Channel::Graveyard => StcMessage::DeadChatPrompt,
+                };
+
+                let text = match player.send(&prompt) {
+                    Ok(
+                        CtsMessage::Chat(text)
+                        | CtsMessage::WolfChatSend(text)
+                        | CtsMessage::DeadChat(text),
+                    ) => text,
+
+                    Ok(msg) => {
+                        warn!("Expected chat from {}, got {:?}", player.name, msg);
+                        continue;
+                    }
+
+                    Err(err) => {
+                        warn!("Lost connection to {}: {}", player.name, err);
+                        continue;
+                    }
+                };
+
+                let text = clean_chat(&text);
+
+                if text.is_empty() {
+                    continue;
+                }
+
+                anyone_spoke = true;
+
+                self.send_all(&match channel {
+                    Channel::Village => StcMessage::ChatBroadcast(player.id, text),
+                    Channel::Wolves => StcMessage::WolfChat(player.id, text),
+                    Channel::Graveyard => StcMessage::DeadChatRelay(player.id, text),
+                });
+            }
+
+            if !anyone_spoke {
+                return;
+            }
+        }
+    }
+
+    /// Gives the given player, who has just died, a chance to say their last words to everyone.
+    /// They only get as long as the rules allow, so that someone who has wandered off can't hold
+    /// the game up.
+    fn last_words(&self, id: PlayerId) {
+        let time = self.rules.last_words_time;
+        let player = &self.players[&id];
+
+        if time.is_zero() || !player.dead || player.is_disconnected() {
+            return;
+        }
+
+        let text = match player.send_with_timeout(&StcMessage::LastWordsPrompt, Some(time)) {
+            Ok(CtsMessage::LastWords(text)) => clean_chat(&text),
+            Ok(CtsMessage::Received) => return,
+
+            Ok(msg) => {
+                warn!("Expected last words from {}, got {:?}", player.name, msg);
+                return;
+            }
+
+            Err(CommError::TimedOut) => {
+                info!("{} ran out of time for their last words", player.name);
+                return;
+            }
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", player.name, err);
+                return;
+            }
+        };
+
+        if !text.is_empty() {
+            self.send_all(&StcMessage::LastWordsBroadcast(id, text));
+        }
+    }
+
+    /// If the night is being advanced manually, waits for the host to say that they're ready to
+    /// move on to the given step. Otherwise, returns straight away.
+    ///
+    /// This lets a host who is narrating the game in person read each step out at their own pace.
+    /// We ask the host's client rather than reading the terminal ourselves, since in a hosted
+    /// game the client is already reading it.
+    fn wait_for_host(&self, step: &str) {
+        if !self.rules.manual_night {
+            return;
+        }
+
+        let Some(host) = self.host() else {
+            return;
+        };
+
+        match host.send_with_timeout(&StcMessage::AskToContinue(step.to_string()), None) {
+            Ok(CtsMessage::Received) => (),
+            Ok(msg) => warn!("Expected {} to continue, got {:?}", host.name, msg),
+            Err(err) => warn!("Lost connection to {}: {}", host.name, err),
+        }
+    }
+
+    /// Plays through one day in the game, given the IDs of the players that were killed the night
+    /// before, each with the ID of the wolf that killed them.
+    ///
+    /// If this day ends the game, the winning side will be returned. Otherwise, `None` will be
+    /// returned.
+    fn play_day(&mut self, kills: Vec<(PlayerId, PlayerId)>) -> Option<Winner> {
+        // Any wolves who killed a knight on an earlier night have run out of time. We take these
+        // before announcing last night's death, so that a wolf who killed a knight last night
+        // doesn't die until tomorrow.
+        let revenge = std::mem::take(&mut self.pending_revenge);
+
+        if let Phase::Night(night) = self.phase {
+            self.phase = Phase::Day(night);
+        }
+
+        let poisoned = std::mem::take(&mut self.pending_poison);
+
+        // Kill the players the wolves chose, which tells everyone that they died. If the doctor
+        // or the witch saved them and nobody was poisoned, we tell everyone that nobody died
+        // instead.
+        if kills.is_empty() && poisoned.is_empty() {
+            self.send_all(&StcMessage::PeacefulNight);
+        }
+
+        for (killed_id, killer_id) in kills {
+            self.kill(killed_id, DeathCause::Killed(killer_id));
+            self.last_words(killed_id);
+        }
+
+        // The poison can't kill someone who is already dead, which the witch finds out.
+        for (victim_id, witch_id) in poisoned {
+            let killed = !self.players[&victim_id].dead;
+            self.kill(victim_id, DeathCause::Poisoned(witch_id));
+
+            self.players[&witch_id].tell(&StcMessage::PoisonResult(killed));
+        }
+
+        for (wolf_id, knight_id) in revenge {
+            self.kill(wolf_id, DeathCause::Revenge(knight_id));
+        }
+
+        // Last night's death may have set off more deaths (such as a hunter's shot), and those
+        // could have decided the game before anyone gets to vote.
+        if let Some(winning_side) = self.winner() {
+            return Some(winning_side);
+        }
+
+        self.hold_day()
+    }
+
+    /// Plays out the part of the day after the night's deaths have been announced, where the
+    /// village talks things over and votes. Returns the winning side if the day decided the game.
+    fn hold_day(&mut self) -> Option<Winner> {
+        self.events.push(Event::DayBroke);
+
+        // If the village voted out a saint yesterday, there's no vote today.
+        if std::mem::take(&mut self.lynch_forbidden) {
+            self.send_all(&StcMessage::LynchForbidden);
+            return self.winner();
+        }
+
+        // Let the village talk things over before anyone has to decide.
+        self.discuss(Channel::Village);
+
+        // Find all the living players. These are the players who will get a vote, and who can be
+        // voted against by other players.
+        let mut candidates: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead)
+            .map(|p| p.id)
+            .collect();
+
+        // Work out who votes when, and let everyone know so they can see whose turn is coming.
+        let mut order = self.vote_order(&candidates);
+        self.send_all(&StcMessage::VoteOrder(order.clone()));
+
+        // While the village has investigations left, it votes on who to investigate before it
+        // votes on who to vote out.
+        if self.investigations_left > 0 {
+            self.investigate(&order, &candidates);
+
+            // Anyone who left during the investigation can't vote or be voted out.
+            if let Some(winning_side) = self.drop_disconnected() {
+                return Some(winning_side);
+            }
+
+            order.retain(|id| !self.players[id].dead);
+            candidates.retain(|id| !self.players[id].dead);
+        }
+
+        // Vote on who to get rid of, and show everyone how the votes fell. If a majority agreed on
+        // someone, they die.
+        let (voted, tally) = self.hold_vote(&order, &candidates, Ballot::Lynch);
+
+        let mut results: Vec<(PlayerId, usize)> = tally.into_iter().collect();
+        results.sort_by_key(|&(_, votes)| Reverse(votes));
+        self.send_all(&StcMessage::VoteResults(results));
+
+        match voted {
+            Some(voted_id) => {
+                // The first time the village votes out the prince, they show their role and the
+                // village lets them off. After that, they're voted out like anyone else.
+                let voted = self.players.get_mut(&voted_id).unwrap();
+
+                if voted.role() == Role::Prince && !voted.prince_revealed {
+                    voted.prince_revealed = true;
+                    self.send_all(&StcMessage::PrinceRevealed(voted_id));
+
+                    return self.winner();
+                }
+
+                self.kill(voted_id, DeathCause::VotedOut);
+                self.last_words(voted_id);
+
+                // The jester wanted to be voted out all along, so they win, whatever else their
+                // death set off. Dying any other way doesn't count.
+                if self.players[&voted_id].role() == Role::Jester {
+                    return Some(Winner::Jester);
+                }
+
+                // The village is punished for voting out a saint. This only applies to a player
+                // who was voted out, and not to one who died any other way.
+                if self.players[&voted_id].role() == Role::Saint {
+                    self.send_all(&StcMessage::SaintLynched(voted_id));
+
+                    match self.rules.saint_penalty {
+                        SaintPenalty::Lose => return Some(Winner::Wolf),
+                        SaintPenalty::SkipNextLynch => self.lynch_forbidden = true,
+                    }
+                }
+            }
+
+            None => {
+                self.send_all(&StcMessage::NoMajority);
+            }
+        }
+
+        self.winner()
+    }
+
+    /// Has the village vote on who to investigate, and tells everyone which side the chosen
+    /// player is on. This uses up one of the village's investigations, unless no player gets a
+    /// majority.
+    fn investigate(&mut self, order: &[PlayerId], candidates: &[PlayerId]) {
+        let (Some(id), _) = self.hold_vote(order, candidates, Ballot::Investigation) else {
+            self.send_all(&StcMessage::NoMajority);
+            return;
+        };
+
+        self.investigations_left -= 1;
+
+        let alignment = self.players[&id].role().alignment();
+
+        self.send_all(&StcMessage::Investigated(
+            id,
+            alignment,
+            self.investigations_left,
+        ));
+    }
+
+    /// Asks each player in `order` to vote for one of the `candidates` on the given ballot, and
+    /// returns the candidate that more than half of the voters agreed on. If no candidate gets a
+    /// majority, `None` is returned. The number of votes each candidate got in the final round of
+    /// voting is returned alongside.
+    ///
+    /// If two or more candidates tie for the most votes, everyone is told who tied and votes again
+    /// between just those candidates. There's only one runoff, so if that ties as well, nobody
+    /// gets a majority.
+    fn hold_vote(
+        &mut self,
+        order: &[PlayerId],
+        candidates: &[PlayerId],
+        ballot: Ballot,
+    ) -> (Option<PlayerId>, BTreeMap<PlayerId, usize>) {
+        let mut candidates = candidates.to_vec();
+        let mut runoff = false;
+
+        loop {
+            let vote_counts = self.collect_votes(order, &candidates, ballot);
+
+            // Find the players with the most votes. If nobody voted at all, there's no majority.
+            let Some(most) = vote_counts.values().copied().max() else {
+                return (None, vote_counts);
+            };
+
+            let leaders: Vec<PlayerId> = vote_counts
+                .iter()
+                .filter(|&(_, &num)| num == most)
+                .map(|(&id, _)| id)
+                .collect();
+
+            if let [voted_id] = leaders[..] {
+                // Check if the vote has a majority (i.e. whether more than half of the players
+                // agreed).
+                return ((most > order.len() / 2).then_some(voted_id), vote_counts);
+            }
+
+            if runoff {
+                return (None, vote_counts);
+            }
+
+            info!(
+                "The vote is tied between {} players, so holding a runoff",
+                leaders.len()
+            );
+            self.send_all(&StcMessage::VoteTie(leaders.clone()));
+
+            candidates = leaders;
+            runoff = true;
+        }
+    }
+
+    /// Asks each player in `order` to vote for one of the `candidates` on the given ballot, and
+    /// returns the number of votes that each candidate got. Candidates without any votes are left
+    /// out.
+    fn collect_votes(
+        &mut self,
+        order: &[PlayerId],
+        candidates: &[PlayerId],
+        ballot: Ballot,
+    ) -> BTreeMap<PlayerId, usize> {
+        // We don't want to allow a player to vote multiple times, so store votes in a hashmap to
+        // ensure that there is only one vote per player ID.
+        let mut votes = HashMap::<PlayerId, PlayerId>::new();
+
+        'voters: for id in order {
+            // Anyone who lost their connection earlier in the vote might have rejoined in time
+            // for their turn.
+            self.admit_late_joins();
+
+            let player = &self.players[id];
+
+            // There's no point asking someone we've lost contact with.
+            if player.is_disconnected() {
+                continue;
+            }
+
+            // Say who we're waiting for so players can tell others that they need to vote.
+            self.send_all(&StcMessage::WaitingFor(player.id));
+
+            // Unless the rules allow it, players can't vote against themselves.
+            let options: Vec<PlayerId> = candidates
+                .iter()
+                .copied()
+                .filter(|&id| self.rules.allow_self_vote || id != player.id)
+                .collect();
+
+            let ballot_msg = match ballot {
+                Ballot::Lynch => StcMessage::VoteOptions {
+                    candidates: options.clone(),
+                    can_abstain: self.rules.allow_no_lynch,
+                },
+                Ballot::Investigation => StcMessage::InvestigateOptions(options.clone()),
+            };
+
+            let timeout = Some(self.rules.vote_timeout).filter(|t| !t.is_zero());
+
+            // A player who picks someone they weren't offered (a dead player, an ID that doesn't
+            // exist, or themselves when that isn't allowed), or who abstains when that isn't
+            // allowed, is asked again, but only a few times, so that a broken client can't hold
+            // the vote up forever.
+            let mut attempts = 0;
+
+            let vote = loop {
+                let response = player.send_with_timeout(&ballot_msg, timeout);
+
+                let vote = match (ballot, response) {
+                    (Ballot::Lynch, Ok(CtsMessage::Vote(vote)))
+                    | (Ballot::Investigation, Ok(CtsMessage::Investigate(vote))) => Some(vote),
+
+                    // An abstention still counts towards the number of votes a candidate needs
+                    // for a majority, since the majority is out of everyone who was asked.
+                    (Ballot::Lynch, Ok(CtsMessage::Abstain)) if self.rules.allow_no_lynch => {
+                        info!("{} abstained", player.name);
+                        self.send_all(&StcMessage::Abstained(player.id));
+                        continue 'voters;
+                    }
+
+                    (Ballot::Lynch, Ok(CtsMessage::Abstain)) => None,
+
+                    (_, Ok(msg)) => {
+                        warn!("Expected vote message, got {:?} instead", msg);
+                        continue 'voters;
+                    }
+
+                    // Someone who takes too long abstains, so that one absent player can't stop
+                    // the game.
+                    (_, Err(CommError::TimedOut)) => {
+                        info!("{} didn't vote in time", player.name);
+                        self.send_all(&StcMessage::VoteTimedOut(player.id));
+                        continue 'voters;
+                    }
+
+                    (_, Err(err)) => {
+                        warn!("Lost connection to {}: {}", player.name, err);
+                        continue 'voters;
+                    }
+                };
+
+                match vote {
+                    Some(vote) if options.contains(&vote) => break vote,
+                    Some(vote) => warn!("{} voted against non-candidate {:?}", player.name, vote),
+                    None => warn!("{} tried to abstain when it isn't allowed", player.name),
+                }
+
+                attempts += 1;
+
+                if attempts == MAX_PICK_ATTEMPTS {
+                    continue 'voters;
+                }
+
+                player.tell(&StcMessage::InvalidChoice);
+            };
+
+            // Only votes to get rid of someone are announced and recorded as they're cast. An
+            // investigation's result speaks for itself.
+            if ballot == Ballot::Lynch {
+                self.send_all(&StcMessage::AnnounceVote(player.id, vote));
+                self.events.push(Event::Voted(player.id, vote));
+            }
+
+            votes.insert(player.id, vote);
+        }
+
+        // Count the votes by making a new map with the player ID as a key, and the number of
+        // votes they have received as the value. This is ordered so that ties always come out the
+        // same way.
+        let mut vote_counts = BTreeMap::<PlayerId, usize>::new();
+
+        for voted_id in votes.into_values() {
+            *vote_counts.entry(voted_id).or_default() += 1;
+        }
+
+        vote_counts
+    }
+
+    /// Checks whether either side has won the game, returning the winning side if so.
+    fn winner(&self) -> Option<Winner> {
+        // Count the living wolves, minions and villagers to see if the game has ended. Dead
+        // players (and players who have left, who are counted as dead) can't stand in anyone's
+        // way, so they don't count.
+        let (wolves, minions, villagers) =
+            self.players
+                .values()
+                .filter(|p| !p.dead)
+                .fold((0, 0, 0), |(w, m, v), p| match p.role.unwrap() {
+                    Role::Wolf | Role::WolfCub => (w + 1, m, v),
+                    Role::Minion => (w, m + 1, v),
+                    _ => (w, m, v + 1),
+                });
+
+        // A werewolf and a villager who are in love can't both win with their own sides, so if
+        // they're the last two standing, they win together instead.
+        if let Some((first, second)) = self.lovers {
+            let (first, second) = (&self.players[&first], &self.players[&second]);
+
+            if wolves + minions + villagers == 2
+                && !first.dead
+                && !second.dead
+                && first.role().alignment() != second.role().alignment()
+            {
+                return Some(Winner::Lovers);
+            }
+        }
+
+        // Depending on the rules, the wolves win either as soon as their side matches the
+        // villagers in number, or only once it outnumbers them. Minions are on the wolves' side,
+        // but they can't kill anyone, so they can't win once the wolves are gone.
+        let wolves_win = wolves > 0
+            && match self.rules.wolf_win_rule {
+                WolfWinRule::Parity => wolves + minions >= villagers,
+                WolfWinRule::Majority => wolves + minions > villagers,
+            };
+
+        // The lone wolf turns on the pack, so if they're the only wolf left when the wolves win,
+        // the win is theirs alone.
+        let lone_wolf_left = wolves == 1 && self.players.values().any(|p| p.lone_wolf && !p.dead);
+
+        if wolves_win && lone_wolf_left {
+            Some(Winner::LoneWolf)
+        } else if wolves_win {
+            Some(Winner::Wolf)
+        } else if wolves == 0 {
+            // If the villagers have killed all the wolves, the village wins.
+            Some(Winner::Village)
+        } else {
+            None
+        }
+    }
+
+    /// Takes anyone we've lost contact with out of the game, so that they aren't asked to do
+    /// anything again. If the rules give players time to rejoin, they're only taken out once
+    /// that time is up. If anyone left, the winning side is returned if their leaving ended the
+    /// game.
+    fn drop_disconnected(&mut self) -> Option<Winner> {
+        let grace = self.rules.reconnect_grace;
+        let now = Instant::now();
+
+        // Anyone we've only just noticed is gone gets a chance to come back, and everyone is told
+        // that their seat is being held.
+        for player in self.players.values_mut() {
+            if !grace.is_zero()
+                && !player.dead
+                && player.is_disconnected()
+                && player.dropped_at.is_none()
+            {
+                info!(
+                    "{} lost their connection, so holding their seat",
+                    player.name
+                );
+                player.dropped_at = Some(now);
+            }
+        }
+
+        let held: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| p.dropped_at == Some(now))
+            .map(|p| p.id)
+            .collect();
+
+        for id in held {
+            self.send_all(&StcMessage::PlayerDisconnected(id));
+        }
+
+        let gone: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead && p.is_disconnected())
+            .filter(|p| p.dropped_at.is_none_or(|at| now - at >= grace))
+            .map(|p| p.id)
+            .collect();
+
+        if gone.is_empty() {
+            return None;
+        }
+
+        for id in gone {
+            info!("{} left the game", self.players[&id].name);
+            self.kill(id, DeathCause::Left);
+        }
+
+        self.winner()
+    }
+
+    /// Kills the given player and tells everyone how they died.
+    ///
+    /// Every death goes through here so that anything triggered by a player dying is handled in
+    /// one place. A death can cause more deaths, so this keeps going until there are no more
+    /// deaths to deal with.
+    fn kill(&mut self, id: PlayerId, cause: DeathCause) {
+        let mut deaths = VecDeque::from([(id, cause)]);
+
+        while let Some((id, cause)) = deaths.pop_front() {
+            let player = self.players.get_mut(&id).unwrap();
+
+            // A player can't die twice.
+            if player.dead {
+                continue;
+            }
+
+            player.dead = true;
+            let role = player.role();
+
+            self.events.push(Event::Died(id, cause));
+
+            self.send_all(&match cause {
+                DeathCause::Killed(_) => StcMessage::Died(id),
+                DeathCause::VotedOut => StcMessage::VotedOut(id),
+                DeathCause::TakenDown(bomber_id) => StcMessage::TakenDown(bomber_id, id),
+                DeathCause::Shot(hunter_id) => StcMessage::Shot(hunter_id, id),
+                DeathCause::Revenge(_) => StcMessage::KnightRevenge(id),
+                DeathCause::Poisoned(_) => StcMessage::Poisoned(id),
+                DeathCause::Heartbreak(_) => StcMessage::Heartbroken(id),
+                DeathCause::Left => StcMessage::PlayerLeft(id),
+            });
+
+            // A bomber who is voted out (or killed at night, if the rules allow it) gets to take
+            // someone else with them.
+            let bomber_triggered = match cause {
+                DeathCause::VotedOut => true,
+                DeathCause::Killed(_) => self.rules.bomber_at_night,
+                DeathCause::TakenDown(_)
+                | DeathCause::Shot(_)
+                | DeathCause::Revenge(_)
+                | DeathCause::Poisoned(_)
+                | DeathCause::Heartbreak(_)
+                | DeathCause::Left => false,
+            };
+
+            if role == Role::Bomber && bomber_triggered {
+                if let Some(target) = self.ask_take_down(id) {
+                    deaths.push_back((target, DeathCause::TakenDown(id)));
+                }
+            }
+
+            // A hunter gets a shot off however they die, unless they've left and can't take it.
+            // The player they shoot could be a hunter too, so this can chain.
+            if role == Role::Hunter && cause != DeathCause::Left {
+                if let Some(target) = self.ask_hunter_shot(id) {
+                    deaths.push_back((target, DeathCause::Shot(id)));
+                }
+            }
+
+            // A knight killed by a wolf takes their revenge on that wolf, either straight away or
+            // at the start of the next day.
+            if let (Role::Knight, DeathCause::Killed(wolf_id)) = (role, cause) {
+                if self.rules.knight_revenge_immediate {
+                    deaths.push_back((wolf_id, DeathCause::Revenge(id)));
+                } else {
+                    self.pending_revenge.push((wolf_id, id));
+                }
+            }
+
+            // The seer's apprentice carries on where the seer left off.
+            if role == Role::Seer {
+                self.promote_apprentice();
+            }
+
+            // The wolves take the cub's death out on the village the next night, however it died.
+            if role == Role::WolfCub {
+                self.pack_enraged = true;
+            }
+
+            // Whoever this player was in love with can't go on without them.
+            if let Some(lover_id) = self.lover_of(id) {
+                deaths.push_back((lover_id, DeathCause::Heartbreak(id)));
+            }
+        }
+    }
+
+    /// Makes the first living apprentice seer into the seer, if there is one, and tells them so.
+    /// From then on, they wake each night to inspect someone like any other seer.
+    fn promote_apprentice(&mut self) {
+        let Some(&id) = self.living_with_role(Role::ApprenticeSeer).first() else {
+            return;
+        };
+
+        let apprentice = self.players.get_mut(&id).unwrap();
+        apprentice.role = Some(Role::Seer);
+
+        info!("{} is the seer now", apprentice.name);
+        apprentice.tell(&StcMessage::YouAreNowSeer);
+    }
+
+    /// Asks the given bomber who they want to take down with them, returning the ID of the
+    /// player they chose. If the bomber doesn't choose a valid target, `None` is returned.
+    fn ask_take_down(&self, bomber_id: PlayerId) -> Option<PlayerId> {
+        // The bomber can take down anybody who is still alive.
+        let targets: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead)
+            .map(|p| p.id)
+            .collect();
+
+        if targets.is_empty() {
+            return None;
+        }
+
+        let bomber = &self.players[&bomber_id];
+
+        match bomber.send(&StcMessage::BomberPrompt(targets.clone())) {
+            Ok(CtsMessage::TakeDown(id)) if targets.contains(&id) => Some(id),
+
+            Ok(msg) => {
+                warn!("Expected valid take-down message, got {:?} instead", msg);
+                None
+            }
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", bomber.name, err);
+                None
+            }
+        }
+    }
+
+    /// Asks the given hunter who they want to shoot as they die, returning the ID of the player
+    /// they chose. If the hunter doesn't choose a valid target, `None` is returned.
+    fn ask_hunter_shot(&self, hunter_id: PlayerId) -> Option<PlayerId> {
+        // The hunter can shoot anybody who is still alive.
+        let targets: Vec<PlayerId> = self
+            .players
+            .values()
+            .filter(|p| !p.dead)
+            .map(|p| p.id)
+            .collect();
+
+        if targets.is_empty() {
+            return None;
+        }
+
+        let hunter = &self.players[&hunter_id];
+
+        match hunter.send(&StcMessage::HunterShootOptions(targets.clone())) {
+            Ok(CtsMessage::HunterShoot(id)) if targets.contains(&id) => Some(id),
+
+            Ok(msg) => {
+                warn!(
+                    "Expected valid shot from {}, got {:?} instead",
+                    hunter.name, msg
+                );
+                None
+            }
+
+            Err(err) => {
+                warn!("Lost connection to {}: {}", hunter.name, err);
+                None
+            }
+        }
+    }
+
+    /// Works out statistics for the game from the events that have happened so far.
+    fn summarize_stats(&self) -> GameStats {
+        let mut stats = GameStats {
+            nights: 0,
+            winner: None,
+            players: vec![],
+            votes_cast: 0,
+            lynches: 0,
+            wolves_lynched: 0,
+            night_time: self.night_time,
+            day_time: self.day_time,
+        };
+
+        for event in &self.events {
+            match *event {
+                Event::NightFell => stats.nights += 1,
+                Event::DayBroke => {}
+                Event::Voted(..) => stats.votes_cast += 1,
+
+                Event::Died(id, DeathCause::VotedOut) => {
+                    stats.lynches += 1;
+
+                    if self.players[&id].role.is_some_and(Role::is_wolf) {
+                        stats.wolves_lynched += 1;
+                    }
+                }
+
+                Event::Died(..) => {}
+                Event::Won(winner) => stats.winner = Some(winner),
+            }
+        }
+
+        stats.players = self
+            .players
+            .values()
+            .map(|p| PlayerStats {
+                name: p.name.clone(),
+                role: p.role,
+                survived: !p.dead,
+                decisions: *p.decisions.lock(),
+            })
+            .collect();
+
+        stats
+    }
+
+    /// Puts together a record of everything that happened in the game, from the events that have
+    /// happened so far.
+    fn record(&self) -> GameRecord {
+        let mut record = GameRecord {
+            finished_at: record::now(),
+            seed: self.game_seed,
+            players: self
+                .players
+                .values()
+                .map(|p| PlayerRecord {
+                    id: p.id,
+                    name: p.name.clone(),
+                    role: p.role,
+                    survived: !p.dead,
+                    transcript: p.transcript.lock().clone(),
+                })
+                .collect(),
+            rounds: vec![],
+            winner: None,
+        };
+
+        // Deaths belong to the night until the day breaks.
+        let mut daytime = false;
+
+        for event in &self.events {
+            match *event {
+                Event::NightFell => {
+                    record.rounds.push(RoundRecord::default());
+                    daytime = false;
+                }
+
+                Event::DayBroke => {
+                    // If the first night was skipped, the first day has no night before it.
+                    if record.rounds.is_empty() {
+                        record.rounds.push(RoundRecord::default());
+                    }
+
+                    daytime = true;
+                }
+
+                Event::Voted(voter, target) => {
+                    if let Some(round) = record.rounds.last_mut() {
+                        round.votes.push(VoteRecord { voter, target });
+                    }
+                }
+
+                Event::Died(player, cause) => {
+                    // Players can leave before the first night, so there might not be a round to
+                    // put the death in yet.
+                    if record.rounds.is_empty() {
+                        record.rounds.push(RoundRecord::default());
+                    }
+
+                    let round = record.rounds.last_mut().unwrap();
+                    let (cause, by) = cause.describe();
+                    let death = DeathRecord {
+                        player,
+                        cause: cause.to_string(),
+                        by,
+                    };
+
+                    if daytime {
+                        round.day_deaths.push(death);
+                    } else {
+                        round.night_deaths.push(death);
+                    }
+                }
+
+                Event::Won(winner) => record.winner = Some(winner),
+            }
+        }
+
+        record
+    }
+
+    /// Returns the order in which the given players should be asked for their votes.
+    ///
+    /// Unless the rules fix the order, it is shuffled using the game's RNG, so it changes from
+    /// day to day but is the same every time a game is played with the same seed.
+    ///
+    /// Voting first (or last) can make a difference, so to stop the same player from always
+    /// leading the vote, the order is then rotated to start with whichever voter has voted first
+    /// the fewest times so far. If several voters are tied, the one who comes earliest in the
+    /// order leads. Rotating keeps everyone in the same relative positions, so a fixed order
+    /// stays in join order (just starting with a different player each day), and a shuffled order
+    /// stays just as random. Over a game, this means that each player leads about as often as
+    /// any other.
+    fn vote_order(&mut self, voters: &[PlayerId]) -> Vec<PlayerId> {
+        let mut order = voters.to_vec();
+
+        // The voters are already in join order, so we only need to shuffle them if the order
+        // isn't fixed.
+        if !self.rules.fixed_vote_order {
+            order.shuffle(&mut self.rng);
+        }
+
+        // Find the position of the voter who has led the fewest times. `min_by_key` picks the
+        // first of any tied voters.
+        let leader_index = order
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, id)| self.times_led.get(id).copied().unwrap_or(0))
+            .map(|(i, _)| i);
+
+        if let Some(leader_index) = leader_index {
+            order.rotate_left(leader_index);
+            *self.times_led.entry(order[0]).or_default() += 1;
+        }
+
+        order
+    }
+
+    /// Sends the given message to every player who is still connected.
+    ///
+    /// If the message can't be sent to a player, they're marked as disconnected and we carry on
+    /// with everyone else, so that one dropped connection doesn't stop the others from hearing
+    /// about what happened. The IDs of any players whose connections failed are returned.
+    ///
+    /// Messages meant only for the wolves are only sent to the living wolves (and the spectators,
+    /// if they can see secrets), so that they can't reach anyone else by accident.
+    fn send_all(&self, message: &StcMessage) -> Vec<PlayerId> {
+        let recipients: Vec<&Player<T>> = self
+            .players
+            .values()
+            .filter(|p| !p.is_disconnected())
+            .filter(|p| !message.is_wolves_only() || (!p.dead && p.role().is_wolf()))
+            .filter(|p| !message.is_dead_only() || p.dead)
+            .collect();
+
+        // Spectators hear everything that doesn't need a decision, including what only the
+        // wolves or the dead hear if they're allowed to see secrets.
+        let secret = message.is_wolves_only() || message.is_dead_only();
+
+        let spectators: Vec<&Player<T>> = if message.is_prompt() {
+            vec![]
+        } else {
+            self.spectators
+                .iter()
+                .filter(|s| !s.is_disconnected())
+                .filter(|s| !secret || self.sees_secrets(s))
+                .collect()
+        };
+
+        // Everyone is sent the message at the same time, each on their own thread, so that a
+        // client that's slow to answer a prompt (or to read what we send it) only holds up its own
+        // connection. Each connection still gets its messages in order, since we've finished with
+        // every connection before sending anything else.
+        let results: Vec<(&Player<T>, Result<(), CommError>)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = recipients
+                .into_iter()
+                .map(|player| {
+                    scope.spawn(move || {
+                        let result = if message.is_prompt() {
+                            player.send(message).map(|_| ())
+                        } else {
+                            player.post(message)
+                        };
+
+                        (player, result)
+                    })
+                })
+                .collect();
+
+            // Losing a spectator doesn't change the game, so they never count as failures and we
+            // don't need to hear how they got on.
+            for spectator in spectators {
+                scope.spawn(move || spectator.tell(message));
+            }
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("Broadcast thread panicked"))
+                .collect()
+        });
+
+        let mut failed = vec![];
+
+        for (player, result) in results {
+            match result {
+                Ok(_) => (),
+
+                // A player who is slow to answer a prompt is still connected.
+                Err(CommError::TimedOut) => {
+                    debug!("{} didn't answer {:?} in time", player.name, message)
+                }
+
+                Err(err) => {
+                    warn!("Lost connection to {}: {}", player.name, err);
+                    failed.push(player.id);
+                }
+            }
+        }
+
+        failed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comm::{channel_pair, Alignment, ChannelTransport};
+
+    /// Returns a game with the given rules between players with the given roles, in order, along
+    /// with the client end of each player's connection. The game has already started, so nobody
+    /// else can join.
+    ///
+    /// Nobody is on the other end of the connections, so a test answers prompts by sending the
+    /// answers down them before the game asks.
+    fn game_with_rules(
+        roles: &[Role],
+        rules: GameRules,
+    ) -> (Game<ChannelTransport>, Vec<ChannelTransport>) {
+        let rules = GameRules {
+            reveal_delay: Duration::ZERO,
+            ..rules
+        };
+
+        let mut game = Game::new(rules, Preset::Classic.roles(), Some(0));
+        game.phase = Phase::Night(1);
+
+        let clients = roles
+            .iter()
+            .enumerate()
+            .map(|(n, &role)| {
+                let (server_end, client_end) = channel_pair();
+
+                let id = game.take_next_id();
+                let colour = game.pick_colour(None);
+
+                let mut player = Player::new(id, format!("Player {}", n), colour, server_end, None);
+                player.role = Some(role);
+
+                game.players.insert(id, player);
+
+                client_end
+            })
+            .collect();
+
+        (game, clients)
+    }
+
+    /// Returns the IDs of the players in the given game, in the order that they joined.
+    fn ids(game: &Game<ChannelTransport>) -> Vec<PlayerId> {
+        game.players.keys().copied().collect()
+    }
+
+    /// Queues up the given answer from a client, to be read when the game next asks for one.
+    fn answer(client: &mut ChannelTransport, msg: CtsMessage) {
+        comm::send(client, &msg).unwrap();
+    }
+
+    /// Queues up a vote from every client against the player with the given index, except from
+    /// that player, who votes against the next player along.
+    fn vote_out(clients: &mut [ChannelTransport], ids: &[PlayerId], target: usize) {
+        for (n, client) in clients.iter_mut().enumerate() {
+            let vote = if n == target {
+                ids[(target + 1) % ids.len()]
+            } else {
+                ids[target]
+            };
+
+            answer(client, CtsMessage::Vote(vote));
+        }
+    }
+
+    /// Returns every message that the client has been sent so far.
+    fn received(client: &mut ChannelTransport) -> Vec<StcMessage> {
+        client
+            .set_recv_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+
+        std::iter::from_fn(|| comm::receive(client).ok()).collect()
+    }
+
+    #[test]
+    fn presets_parse_from_their_names() {
+        for (name, preset) in [
+            ("classic", Preset::Classic),
+            ("seer-doctor", Preset::SeerDoctor),
+            ("Chaos", Preset::Chaos),
+        ] {
+            assert_eq!(name.parse::<Preset>(), Ok(preset));
+        }
+
+        assert!("anarchy".parse::<Preset>().is_err());
+    }
+
+    #[test]
+    fn chaos_gives_out_each_special_role_once() {
+        let specials = Preset::Chaos.roles().specials;
+
+        for (i, role) in specials.iter().enumerate() {
+            assert!(
+                !specials[i + 1..].contains(role),
+                "{:?} is given out twice",
+                role
+            );
+        }
+    }
+
+    #[test]
+    fn countdown_runs_out_when_nobody_backs_out() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], GameRules::default());
+
+        assert!(game.count_down(Duration::from_millis(50)));
+        assert!(
+            received(&mut clients[0]).contains(&StcMessage::StartingIn(Duration::from_millis(50)))
+        );
+    }
+
+    #[test]
+    fn countdown_is_called_off_when_someone_backs_out() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], GameRules::default());
+        let ids = ids(&game);
+
+        for player in game.players.values_mut() {
+            player.ready = true;
+        }
+
+        answer(&mut clients[2], CtsMessage::Ready(false));
+
+        assert!(!game.count_down(Duration::from_secs(5)));
+        assert!(!game.players[&ids[2]].ready);
+        assert!(received(&mut clients[0]).contains(&StcMessage::CountdownCancelled(ids[2])));
+    }
+
+    #[test]
+    fn renames_are_announced_to_everyone() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 2], GameRules::default());
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Rename("Alice".to_string()));
+        assert_eq!(game.handle_unprompted(), None);
+
+        assert_eq!(game.players[&ids[0]].name, "Alice");
+        let renamed = StcMessage::PlayerRenamed(ids[0], "Alice".to_string());
+        assert!(received(&mut clients[1]).contains(&renamed));
+    }
+
+    #[test]
+    fn renames_are_checked_like_new_names() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 2], GameRules::default());
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Rename("player 1".to_string()));
+        game.handle_unprompted();
+
+        assert_eq!(game.players[&ids[0]].name, "Player 0");
+        let rejected = StcMessage::RenameRejected(NameRejection::Taken);
+        assert!(received(&mut clients[0]).contains(&rejected));
+
+        // A player's own name doesn't count as taken, so they can change how it's written.
+        answer(&mut clients[0], CtsMessage::Rename("PLAYER 0".to_string()));
+        game.handle_unprompted();
+
+        assert_eq!(game.players[&ids[0]].name, "PLAYER 0");
+    }
+
+    #[test]
+    fn renames_sent_while_answering_are_kept_for_later() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 2], GameRules::default());
+        let ids = ids(&game);
+
+        // Nobody has a role in the lobby.
+        for player in game.players.values_mut() {
+            player.role = None;
+        }
+
+        answer(&mut clients[0], CtsMessage::Rename("Alice".to_string()));
+        answer(&mut clients[0], CtsMessage::Vote(ids[1]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+
+        let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+        assert_eq!(counts.get(&ids[1]), Some(&1));
+
+        game.handle_unprompted();
+        assert_eq!(game.players[&ids[0]].name, "Alice");
+    }
+
+    #[test]
+    fn forbidden_abstention_is_refused() {
+        let rules = GameRules {
+            allow_no_lynch: false,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], rules);
+        let ids = ids(&game);
+
+        // The first voter tries to abstain and then votes properly. The others vote too.
+        answer(&mut clients[0], CtsMessage::Abstain);
+        answer(&mut clients[0], CtsMessage::Vote(ids[1]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+        answer(&mut clients[2], CtsMessage::Vote(ids[1]));
+
+        let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+
+        assert_eq!(counts.get(&ids[1]), Some(&2));
+        assert!(received(&mut clients[0]).contains(&StcMessage::InvalidChoice));
+    }
+
+    #[test]
+    fn allowed_abstention_is_counted() {
+        let rules = GameRules {
+            allow_no_lynch: true,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], rules);
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Abstain);
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+        answer(&mut clients[2], CtsMessage::Vote(ids[1]));
+
+        let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+
+        assert_eq!(counts.values().sum::<usize>(), 2);
+        assert!(!received(&mut clients[0]).contains(&StcMessage::InvalidChoice));
+        assert!(received(&mut clients[1]).contains(&StcMessage::Abstained(ids[0])));
+    }
+
+    #[test]
+    fn day_without_any_votes_passes_peacefully() {
+        let rules = GameRules {
+            allow_no_lynch: false,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Wolf, Role::Villager, Role::Villager, Role::Villager],
+            rules,
+        );
+        let ids = ids(&game);
+
+        // Some players answer without voting, one keeps trying to abstain when it isn't allowed,
+        // and one keeps voting against themselves until they run out of attempts.
+        answer(&mut clients[0], CtsMessage::Received);
+        answer(&mut clients[2], CtsMessage::Received);
+
+        for _ in 0..MAX_PICK_ATTEMPTS {
+            answer(&mut clients[1], CtsMessage::Abstain);
+            answer(&mut clients[3], CtsMessage::Vote(ids[3]));
+        }
+
+        assert_eq!(game.hold_day(), None);
+        assert!(game.players.values().all(|p| !p.dead));
+        assert!(received(&mut clients[0]).contains(&StcMessage::NoMajority));
+    }
+
+    #[test]
+    fn vote_order_is_the_same_for_the_same_seed() {
+        let orders = || {
+            let (mut game, _clients) = game_with_rules(&[Role::Villager; 5], GameRules::default());
+            let ids = ids(&game);
+
+            (0..5).map(|_| game.vote_order(&ids)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(orders(), orders());
+    }
+
+    #[test]
+    fn everyone_leads_the_vote_before_anyone_leads_twice() {
+        let (mut game, _clients) = game_with_rules(&[Role::Villager; 5], GameRules::default());
+        let ids = ids(&game);
+
+        let mut leaders: Vec<PlayerId> = (0..5).map(|_| game.vote_order(&ids)[0]).collect();
+        leaders.sort();
+
+        assert_eq!(leaders, ids);
+    }
+
+    #[test]
+    fn fixed_vote_order_keeps_join_order() {
+        let rules = GameRules {
+            fixed_vote_order: true,
+            ..GameRules::default()
+        };
+
+        let (mut game, _clients) = game_with_rules(&[Role::Villager; 3], rules);
+        let ids = ids(&game);
+
+        // The order only rotates to give the next player a turn at leading.
+        assert_eq!(game.vote_order(&ids), ids);
+        assert_eq!(game.vote_order(&ids), [ids[1], ids[2], ids[0]]);
+    }
+
+    #[test]
+    fn voted_out_bomber_takes_their_target_down() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Bomber, Role::Villager, Role::Wolf],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::TakeDown(ids[2]));
+        game.kill(ids[0], DeathCause::VotedOut);
+
+        assert!(game.players[&ids[2]].dead);
+        assert!(!game.players[&ids[1]].dead);
+        assert!(received(&mut clients[1]).contains(&StcMessage::TakenDown(ids[0], ids[2])));
+    }
+
+    #[test]
+    fn bomber_killed_at_night_only_takes_someone_down_if_the_rules_allow_it() {
+        for bomber_at_night in [false, true] {
+            let rules = GameRules {
+                bomber_at_night,
+                ..GameRules::default()
+            };
+
+            let (mut game, mut clients) =
+                game_with_rules(&[Role::Bomber, Role::Villager, Role::Wolf], rules);
+            let ids = ids(&game);
+
+            answer(&mut clients[0], CtsMessage::TakeDown(ids[1]));
+            game.kill(ids[0], DeathCause::Killed(ids[2]));
+
+            assert_eq!(game.players[&ids[1]].dead, bomber_at_night);
+        }
+    }
+
+    #[test]
+    fn vote_for_a_player_who_doesnt_exist_is_refused() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], GameRules::default());
+        let ids = ids(&game);
+        let stranger = ids[2].next();
+
+        answer(&mut clients[0], CtsMessage::Vote(stranger));
+        answer(&mut clients[0], CtsMessage::Vote(ids[1]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+        answer(&mut clients[2], CtsMessage::Vote(ids[1]));
+
+        let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+
+        assert_eq!(counts.get(&stranger), None);
+        assert_eq!(counts.get(&ids[1]), Some(&2));
+        assert!(received(&mut clients[0]).contains(&StcMessage::InvalidChoice));
+    }
+
+    #[test]
+    fn vote_for_a_dead_player_is_not_counted() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 4], GameRules::default());
+        let ids = ids(&game);
+
+        game.players.get_mut(&ids[3]).unwrap().dead = true;
+        let living = &ids[..3];
+
+        // The first voter keeps voting against the dead player until they run out of attempts,
+        // so their vote is thrown away.
+        for _ in 0..MAX_PICK_ATTEMPTS {
+            answer(&mut clients[0], CtsMessage::Vote(ids[3]));
+        }
+
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+        answer(&mut clients[2], CtsMessage::Vote(ids[0]));
+
+        let counts = game.collect_votes(living, living, Ballot::Lynch);
+
+        assert_eq!(counts.get(&ids[3]), None);
+        assert_eq!(counts.values().sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn voters_are_only_offered_themselves_if_the_rules_allow_it() {
+        for allow_self_vote in [false, true] {
+            let rules = GameRules {
+                allow_self_vote,
+                ..GameRules::default()
+            };
+
+            let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], rules);
+            let ids = ids(&game);
+
+            // Everyone votes against the second player, including the second player. If that
+            // isn't allowed, they're asked again and vote against the first player instead.
+            for client in &mut clients {
+                answer(client, CtsMessage::Vote(ids[1]));
+            }
+
+            answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+
+            let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+            let expected = if allow_self_vote { 3 } else { 2 };
+
+            assert_eq!(counts.get(&ids[1]), Some(&expected));
+
+            let offered = received(&mut clients[1])
+                .into_iter()
+                .find_map(|msg| match msg {
+                    StcMessage::VoteOptions { candidates, .. } => Some(candidates),
+                    _ => None,
+                })
+                .unwrap();
+
+            assert_eq!(offered.contains(&ids[1]), allow_self_vote);
+        }
+    }
+
+    #[test]
+    fn wolves_win_at_parity_only_under_the_parity_rule() {
+        for (rule, winner) in [
+            (WolfWinRule::Parity, Some(Winner::Wolf)),
+            (WolfWinRule::Majority, None),
+        ] {
+            let rules = GameRules {
+                wolf_win_rule: rule,
+                ..GameRules::default()
+            };
+
+            let (game, _clients) = game_with_rules(&[Role::Wolf, Role::Villager], rules);
+
+            assert_eq!(game.winner(), winner, "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn wolves_win_by_majority_under_either_rule() {
+        for rule in [WolfWinRule::Parity, WolfWinRule::Majority] {
+            let rules = GameRules {
+                wolf_win_rule: rule,
+                ..GameRules::default()
+            };
+
+            let (game, _clients) =
+                game_with_rules(&[Role::Wolf, Role::Wolf, Role::Villager], rules);
+
+            assert_eq!(game.winner(), Some(Winner::Wolf), "{:?}", rule);
+        }
+    }
+
+    #[test]
+    fn dead_players_dont_count_towards_a_win() {
+        let (mut game, _clients) = game_with_rules(
+            &[Role::Wolf, Role::Villager, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        assert_eq!(game.winner(), None);
+
+        game.players.get_mut(&ids[2]).unwrap().dead = true;
+        game.players.get_mut(&ids[3]).unwrap().dead = true;
+
+        assert_eq!(game.winner(), Some(Winner::Wolf));
+    }
+
+    #[test]
+    fn night_with_nobody_to_kill_ends_in_a_wolf_win() {
+        let (mut game, mut clients) =
+            game_with_rules(&[Role::Wolf, Role::Villager], GameRules::default());
+        let ids = ids(&game);
+
+        game.players.get_mut(&ids[1]).unwrap().dead = true;
+
+        assert_eq!(game.play_night(), Err(Winner::Wolf));
+        assert!(!received(&mut clients[0])
+            .iter()
+            .any(|msg| matches!(msg, StcMessage::KillOptions(_))));
+    }
+
+    #[test]
+    fn setup_is_revealed_as_far_as_the_rules_allow() {
+        for reveal in [SetupReveal::Full, SetupReveal::Partial, SetupReveal::Hidden] {
+            let rules = GameRules {
+                setup_reveal: reveal,
+                ..GameRules::default()
+            };
+
+            // The classic setup for six players has a wolf, a seer and four villagers.
+            let (game, mut clients) = game_with_rules(&[Role::Villager; 6], rules);
+
+            let roles = game.roles_in_play();
+
+            assert_eq!(roles.iter().filter(|&&role| role == Role::Wolf).count(), 1);
+            assert_eq!(roles.iter().filter(|&&role| role == Role::Seer).count(), 1);
+
+            let expected = match reveal {
+                SetupReveal::Full => Setup::Full(roles),
+                SetupReveal::Partial => Setup::Partial(1),
+                SetupReveal::Hidden => Setup::Hidden,
+            };
+
+            game.announce_setup();
+
+            assert_eq!(received(&mut clients[0]), [StcMessage::GameSetup(expected)]);
+        }
+    }
+
+    #[test]
+    fn out_of_turn_action_isnt_taken_as_the_reply() {
+        let (game, mut clients) = game_with_rules(&[Role::Villager; 3], GameRules::default());
+        let ids = ids(&game);
+
+        // Before answering the prompt they're given, the client sends a vote and a kill that
+        // nobody asked them for.
+        answer(&mut clients[0], CtsMessage::Vote(ids[2]));
+        answer(&mut clients[0], CtsMessage::Kill(ids[2]));
+        answer(&mut clients[0], CtsMessage::Protect(ids[1]));
+
+        let reply = game.players[&ids[0]].send(&StcMessage::ProtectOptions(ids[1..].to_vec()));
+
+        assert_eq!(reply.unwrap(), CtsMessage::Protect(ids[1]));
+    }
+
+    /// The roles for a game about the saint, where the village can vote the saint out without
+    /// the game ending on its own.
+    const SAINT_GAME: [Role; 5] = [
+        Role::Saint,
+        Role::Villager,
+        Role::Villager,
+        Role::Villager,
+        Role::Wolf,
+    ];
+
+    #[test]
+    fn village_loses_for_voting_out_the_saint() {
+        let rules = GameRules {
+            saint_penalty: SaintPenalty::Lose,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&SAINT_GAME, rules);
+        let ids = ids(&game);
+
+        vote_out(&mut clients, &ids, 0);
+
+        assert_eq!(game.hold_day(), Some(Winner::Wolf));
+        assert!(received(&mut clients[1]).contains(&StcMessage::SaintLynched(ids[0])));
+    }
+
+    #[test]
+    fn village_cant_vote_the_day_after_voting_out_the_saint() {
+        let rules = GameRules {
+            saint_penalty: SaintPenalty::SkipNextLynch,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&SAINT_GAME, rules);
+        let ids = ids(&game);
+
+        vote_out(&mut clients, &ids, 0);
+
+        assert_eq!(game.hold_day(), None);
+        received(&mut clients[1]);
+
+        // Nobody is asked to vote the next day, and the day after that, the village votes as
+        // normal.
+        assert_eq!(game.hold_day(), None);
+
+        let next_day = received(&mut clients[1]);
+        assert!(next_day.contains(&StcMessage::LynchForbidden));
+        assert!(!next_day
+            .iter()
+            .any(|msg| matches!(msg, StcMessage::VoteOptions { .. })));
+
+        vote_out(&mut clients[1..], &ids[1..], 0);
+        game.hold_day();
+
+        assert!(game.players[&ids[1]].dead);
+    }
+
+    #[test]
+    fn saint_killed_at_night_doesnt_punish_the_village() {
+        for penalty in [SaintPenalty::Lose, SaintPenalty::SkipNextLynch] {
+            let rules = GameRules {
+                saint_penalty: penalty,
+                ..GameRules::default()
+            };
+
+            let (mut game, mut clients) = game_with_rules(&SAINT_GAME, rules);
+            let ids = ids(&game);
+
+            game.kill(ids[0], DeathCause::Killed(ids[4]));
+            vote_out(&mut clients[1..], &ids[1..], 0);
+
+            assert_eq!(game.hold_day(), None);
+            assert!(game.players[&ids[1]].dead);
+            assert!(!game.lynch_forbidden);
+            assert!(!received(&mut clients[2]).contains(&StcMessage::SaintLynched(ids[0])));
+        }
+    }
+
+    #[test]
+    fn broadcast_reaches_everyone_despite_a_dropped_connection() {
+        let (game, mut clients) = game_with_rules(&[Role::Villager; 3], GameRules::default());
+        let ids = ids(&game);
+
+        drop(clients.remove(1));
+
+        assert_eq!(game.send_all(&StcMessage::NightFalls), [ids[1]]);
+        assert!(game.players[&ids[1]].is_disconnected());
+
+        for client in &mut clients {
+            assert_eq!(received(client), [StcMessage::NightFalls]);
+        }
+    }
+
+    #[test]
+    fn village_investigations_run_out() {
+        let rules = GameRules {
+            village_investigations: 1,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 5], rules);
+        let ids = ids(&game);
+
+        // The village investigates the first player and then votes out the last.
+        for (n, client) in clients.iter_mut().enumerate() {
+            let target = if n == 0 { ids[1] } else { ids[0] };
+            answer(client, CtsMessage::Investigate(target));
+        }
+
+        vote_out(&mut clients, &ids, 4);
+        game.hold_day();
+
+        assert_eq!(game.investigations_left, 0);
+        assert!(
+            received(&mut clients[2]).contains(&StcMessage::Investigated(
+                ids[0],
+                Alignment::Village,
+                0
+            ))
+        );
+
+        // With no investigations left, the next day goes straight to the vote.
+        vote_out(&mut clients[..4], &ids[..4], 3);
+        game.hold_day();
+
+        assert!(!received(&mut clients[2])
+            .iter()
+            .any(|msg| matches!(msg, StcMessage::InvestigateOptions(_))));
+    }
+
+    #[test]
+    fn server_listening_everywhere_can_be_reached() {
+        let config = Config {
+            start_countdown: Duration::ZERO,
+            seed: Some(0),
+            rules: GameRules::default(),
+            roles: Preset::Classic.roles(),
+            stats_path: None,
+            log_dir: None,
+            post_game_timeout: Duration::ZERO,
+            heartbeat: Duration::ZERO,
+        };
+
+        let (addr, _rooms) = start(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0, config);
+
+        assert_ne!(addr.port(), 0);
+        assert!(TcpStream::connect(addr).is_ok());
+
+        // Other machines would connect over the LAN, if this machine is on one.
+        if let Some(ip) = lan_address() {
+            assert!(TcpStream::connect((ip, addr.port())).is_ok());
+        }
+    }
+
+    #[test]
+    fn players_with_the_same_name_both_get_a_vote() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 4], GameRules::default());
+        let ids = ids(&game);
+
+        for id in &ids[..2] {
+            game.players.get_mut(id).unwrap().name = "Twin".to_string();
+        }
+
+        answer(&mut clients[0], CtsMessage::Vote(ids[3]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[3]));
+        answer(&mut clients[2], CtsMessage::Vote(ids[0]));
+        answer(&mut clients[3], CtsMessage::Vote(ids[0]));
+
+        let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+
+        assert_eq!(counts.get(&ids[3]), Some(&2));
+        assert_eq!(counts.get(&ids[0]), Some(&2));
+    }
+
+    #[test]
+    fn village_wins_by_voting_out_the_last_wolf() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Wolf, Role::Villager, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        vote_out(&mut clients, &ids, 0);
+
+        assert_eq!(game.hold_day(), Some(Winner::Village));
+    }
+
+    #[test]
+    fn wolves_win_once_the_village_votes_itself_down_to_parity() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Wolf, Role::Villager, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        // After the first vote, the wolf is still outnumbered. Voting out another villager
+        // leaves the wolf level with the village.
+        vote_out(&mut clients, &ids, 3);
+        assert_eq!(game.hold_day(), None);
+
+        vote_out(&mut clients[..3], &ids[..3], 2);
+        assert_eq!(game.hold_day(), Some(Winner::Wolf));
+    }
+
+    #[test]
+    fn tie_is_settled_by_a_runoff() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], GameRules::default());
+        let ids = ids(&game);
+
+        // Two of the players vote, and they split their votes between the first and the last.
+        // In the runoff, they agree on the last.
+        answer(&mut clients[0], CtsMessage::Vote(ids[2]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+        answer(&mut clients[0], CtsMessage::Vote(ids[2]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[2]));
+
+        let (voted, tally) = game.hold_vote(&ids[..2], &ids, Ballot::Lynch);
+
+        assert_eq!(voted, Some(ids[2]));
+        assert_eq!(tally.get(&ids[2]), Some(&2));
+        assert!(received(&mut clients[2]).contains(&StcMessage::VoteTie(vec![ids[0], ids[2]])));
+    }
+
+    #[test]
+    fn tie_in_the_runoff_means_nobody_is_voted_out() {
+        let result = || {
+            let (mut game, mut clients) =
+                game_with_rules(&[Role::Villager; 6], GameRules::default());
+            let ids = ids(&game);
+
+            // The first three players are tied on two votes each, in both rounds.
+            for _ in 0..2 {
+                for (n, client) in clients.iter_mut().enumerate() {
+                    answer(client, CtsMessage::Vote(ids[(n + 1) % 3]));
+                }
+            }
+
+            let (voted, tally) = game.hold_vote(&ids, &ids, Ballot::Lynch);
+            let ties = received(&mut clients[0])
+                .into_iter()
+                .filter(|msg| matches!(msg, StcMessage::VoteTie(_)))
+                .collect::<Vec<_>>();
+
+            assert_eq!(voted, None);
+            assert_eq!(ties, [StcMessage::VoteTie(ids[..3].to_vec())]);
+
+            tally
+        };
+
+        assert_eq!(result(), result());
+    }
+
+    #[test]
+    fn names_are_checked_before_joining() {
+        let (game, _clients) = game_with_rules(&[Role::Villager], GameRules::default());
+        let long = "w".repeat(comm::MAX_NAME_LEN + 1);
+
+        for (name, rejection) in [
+            ("", NameRejection::Empty),
+            ("   ", NameRejection::Empty),
+            (long.as_str(), NameRejection::TooLong),
+            ("\u{1b}[31mRed", NameRejection::ControlCharacters),
+            ("Two\nLines", NameRejection::ControlCharacters),
+            ("Player 0", NameRejection::Taken),
+            ("PLAYER 0", NameRejection::Taken),
+        ] {
+            assert_eq!(game.check_name(name, None), Err(rejection), "{:?}", name);
+        }
+
+        assert_eq!(game.check_name("Player 1", None), Ok(()));
+        assert_eq!(
+            game.check_name(&"w".repeat(comm::MAX_NAME_LEN), None),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn bodyguard_cant_guard_the_same_player_twice_in_a_row() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Bodyguard, Role::Villager, Role::Villager, Role::Wolf],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Guard(ids[1]));
+        assert_eq!(game.guard(ids[0]), Some(ids[1]));
+        received(&mut clients[0]);
+
+        // The second night, the player guarded last night isn't offered, and picking them anyway
+        // is refused.
+        answer(&mut clients[0], CtsMessage::Guard(ids[1]));
+        assert_eq!(game.guard(ids[0]), None);
+
+        assert_eq!(
+            received(&mut clients[0]),
+            [
+                StcMessage::GuardOptions(vec![ids[2], ids[3]]),
+                StcMessage::GuardRejected
+            ]
+        );
+
+        // Having guarded nobody, the bodyguard can guard that player again the third night.
+        answer(&mut clients[0], CtsMessage::Guard(ids[1]));
+        assert_eq!(game.guard(ids[0]), Some(ids[1]));
+    }
+
+    #[test]
+    fn jester_wins_by_being_voted_out() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Jester, Role::Villager, Role::Villager, Role::Wolf],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        vote_out(&mut clients, &ids, 0);
+
+        assert_eq!(game.hold_day(), Some(Winner::Jester));
+    }
+
+    #[test]
+    fn jester_killed_at_night_doesnt_win() {
+        let (mut game, mut clients) = game_with_rules(
+            &[
+                Role::Jester,
+                Role::Villager,
+                Role::Villager,
+                Role::Villager,
+                Role::Wolf,
+            ],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        // The game carries on through the day after the jester's death.
+        vote_out(&mut clients[1..], &ids[1..], 0);
+
+        assert_eq!(game.play_day(vec![(ids[0], ids[4])]), None);
+        assert!(game.players[&ids[0]].dead);
+        assert!(game.players[&ids[1]].dead);
+    }
+
+    #[test]
+    fn prince_survives_only_their_first_lynch() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Prince, Role::Villager, Role::Villager, Role::Wolf],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        vote_out(&mut clients, &ids, 0);
+        assert_eq!(game.hold_day(), None);
+
+        assert!(!game.players[&ids[0]].dead);
+        assert!(received(&mut clients[1]).contains(&StcMessage::PrinceRevealed(ids[0])));
+
+        vote_out(&mut clients, &ids, 0);
+        game.hold_day();
+
+        assert!(game.players[&ids[0]].dead);
+    }
+
+    #[test]
+    fn wolves_kill_twice_the_night_after_the_cub_dies() {
+        let (mut game, mut clients) = game_with_rules(
+            &[
+                Role::WolfCub,
+                Role::Wolf,
+                Role::Villager,
+                Role::Villager,
+                Role::Villager,
+                Role::Villager,
+            ],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        vote_out(&mut clients, &ids, 0);
+        assert_eq!(game.hold_day(), None);
+
+        answer(&mut clients[1], CtsMessage::Kill(ids[2]));
+        answer(&mut clients[1], CtsMessage::Kill(ids[3]));
+
+        assert_eq!(
+            game.play_night(),
+            Ok(vec![(ids[2], ids[1]), (ids[3], ids[1])])
+        );
+        assert!(received(&mut clients[1]).contains(&StcMessage::PackEnraged));
+        assert!(!received(&mut clients[4]).contains(&StcMessage::PackEnraged));
+
+        // The pack only takes it out on the village once.
+        answer(&mut clients[1], CtsMessage::Kill(ids[4]));
+
+        assert_eq!(game.play_night(), Ok(vec![(ids[4], ids[1])]));
+    }
+
+    #[test]
+    fn hunter_shoots_however_they_die() {
+        for cause in [
+            DeathCause::VotedOut,
+            DeathCause::Killed(PlayerId::new().next()),
+        ] {
+            let (mut game, mut clients) = game_with_rules(
+                &[Role::Hunter, Role::Wolf, Role::Villager],
+                GameRules::default(),
+            );
+            let ids = ids(&game);
+
+            answer(&mut clients[0], CtsMessage::HunterShoot(ids[1]));
+            game.kill(ids[0], cause);
+
+            assert!(game.players[&ids[1]].dead, "{:?}", cause);
+            assert!(received(&mut clients[2]).contains(&StcMessage::Shot(ids[0], ids[1])));
+        }
+    }
+
+    #[test]
+    fn hunters_shots_can_chain() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Hunter, Role::Hunter, Role::Wolf, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::HunterShoot(ids[1]));
+        answer(&mut clients[1], CtsMessage::HunterShoot(ids[2]));
+        game.kill(ids[0], DeathCause::VotedOut);
+
+        assert!(game.players[&ids[1]].dead);
+        assert!(game.players[&ids[2]].dead);
+        assert!(!game.players[&ids[3]].dead);
+    }
+
+    #[test]
+    fn hunter_who_left_doesnt_shoot() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Hunter, Role::Wolf, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        // If the hunter were asked, they'd shoot the wolf.
+        answer(&mut clients[0], CtsMessage::HunterShoot(ids[1]));
+        game.kill(ids[0], DeathCause::Left);
+
+        assert!(!game.players[&ids[1]].dead);
+        assert!(!game.players[&ids[2]].dead);
+    }
+
+    #[test]
+    fn knight_takes_revenge_the_next_day() {
+        let (mut game, _clients) = game_with_rules(
+            &[Role::Knight, Role::Wolf, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.kill(ids[0], DeathCause::Killed(ids[1]));
+
+        assert!(!game.players[&ids[1]].dead);
+        assert_eq!(game.pending_revenge, [(ids[1], ids[0])]);
+
+        // The wolf dies as the next day starts, which ends the game before anyone votes.
+        assert_eq!(game.play_day(vec![]), Some(Winner::Village));
+        assert!(game.players[&ids[1]].dead);
+    }
+
+    #[test]
+    fn knight_can_take_revenge_straight_away() {
+        let rules = GameRules {
+            knight_revenge_immediate: true,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Knight, Role::Wolf, Role::Villager, Role::Villager],
+            rules,
+        );
+        let ids = ids(&game);
+
+        game.kill(ids[0], DeathCause::Killed(ids[1]));
+
+        assert!(game.players[&ids[1]].dead);
+        assert!(received(&mut clients[2]).contains(&StcMessage::KnightRevenge(ids[1])));
+    }
+
+    #[test]
+    fn knight_voted_out_takes_no_revenge() {
+        let (mut game, _clients) = game_with_rules(
+            &[Role::Knight, Role::Wolf, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.kill(ids[0], DeathCause::VotedOut);
+
+        assert!(game.pending_revenge.is_empty());
+        assert!(!game.players[&ids[1]].dead);
+    }
+
+    #[test]
+    fn lovers_die_together() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Villager, Role::Villager, Role::Villager, Role::Wolf],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.lovers = Some((ids[0], ids[1]));
+        game.kill(ids[0], DeathCause::Killed(ids[3]));
+
+        assert!(game.players[&ids[1]].dead);
+        assert!(received(&mut clients[2]).contains(&StcMessage::Heartbroken(ids[1])));
+    }
+
+    #[test]
+    fn wolf_and_villager_in_love_win_together_as_the_last_two() {
+        let (mut game, _clients) = game_with_rules(
+            &[Role::Villager, Role::Wolf, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.lovers = Some((ids[0], ids[1]));
+        game.players.get_mut(&ids[2]).unwrap().dead = true;
+
+        assert_eq!(game.winner(), Some(Winner::Lovers));
+    }
+
+    #[test]
+    fn lovers_on_the_same_side_win_with_their_side() {
+        let (mut game, _clients) = game_with_rules(
+            &[Role::Villager, Role::Villager, Role::Wolf],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.lovers = Some((ids[0], ids[1]));
+        game.players.get_mut(&ids[2]).unwrap().dead = true;
+
+        assert_eq!(game.winner(), Some(Winner::Village));
+    }
+
+    #[test]
+    fn minions_count_towards_the_wolves_parity() {
+        let (game, _clients) = game_with_rules(
+            &[Role::Wolf, Role::Minion, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+
+        assert_eq!(game.winner(), Some(Winner::Wolf));
+    }
+
+    #[test]
+    fn minions_cant_win_without_the_wolves() {
+        let (mut game, _clients) = game_with_rules(
+            &[Role::Wolf, Role::Minion, Role::Minion, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.players.get_mut(&ids[0]).unwrap().dead = true;
+
+        assert_eq!(game.winner(), Some(Winner::Village));
+    }
+
+    /// What the witch does in a night effect test, with players given by their index.
+    #[derive(Clone, Copy, Debug)]
+    enum Brew {
+        Pass,
+        Heal,
+        Poison(usize),
+    }
+
+    #[test]
+    fn night_effects_on_the_wolves_victim_resolve_in_order() {
+        const WOLF: usize = 0;
+        const VICTIM: usize = 1;
+        const DOCTOR: usize = 2;
+        const BODYGUARD: usize = 3;
+        const WITCH: usize = 4;
+        const BYSTANDER: usize = 5;
+
+        // Who the doctor protects, who the bodyguard guards, whether the bodyguard dies in their
+        // charge's place, what the witch does, and who should die as a result. Anyone without
+        // a part to play in a case picks the bystander.
+        let cases: &[(usize, usize, bool, Brew, &[usize])] = &[
+            (BYSTANDER, BYSTANDER, false, Brew::Pass, &[VICTIM]),
+            (VICTIM, BYSTANDER, false, Brew::Pass, &[]),
+            (BYSTANDER, VICTIM, false, Brew::Pass, &[]),
+            (BYSTANDER, VICTIM, true, Brew::Pass, &[BODYGUARD]),
+            (VICTIM, VICTIM, true, Brew::Pass, &[BODYGUARD]),
+            (BODYGUARD, VICTIM, true, Brew::Pass, &[]),
+            (BYSTANDER, BYSTANDER, false, Brew::Heal, &[]),
+            (BYSTANDER, VICTIM, true, Brew::Heal, &[]),
+            // With nobody under attack, there's nobody to heal.
+            (VICTIM, BYSTANDER, false, Brew::Heal, &[]),
+            // Protection only stops the wolves.
+            (VICTIM, BYSTANDER, false, Brew::Poison(VICTIM), &[VICTIM]),
+            (BYSTANDER, VICTIM, false, Brew::Poison(VICTIM), &[VICTIM]),
+            (
+                BYSTANDER,
+                BYSTANDER,
+                false,
+                Brew::Poison(BYSTANDER),
+                &[VICTIM, BYSTANDER],
+            ),
+            // The victim is already under attack, so the poison is refused.
+            (BYSTANDER, BYSTANDER, false, Brew::Poison(VICTIM), &[VICTIM]),
+        ];
+
+        for &(protected, guarded, bodyguard_dies, brew, expected) in cases {
+            let rules = GameRules {
+                bodyguard_dies,
+                ..GameRules::default()
+            };
+
+            let (mut game, mut clients) = game_with_rules(
+                &[
+                    Role::Wolf,
+                    Role::Villager,
+                    Role::Doctor,
+                    Role::Bodyguard,
+                    Role::Witch,
+                    Role::Villager,
+                    Role::Villager,
+                ],
+                rules,
+            );
+            let ids = ids(&game);
+
+            let action = match brew {
+                Brew::Pass => WitchAction::Pass,
+                Brew::Heal => WitchAction::Heal,
+                Brew::Poison(n) => WitchAction::Poison(ids[n]),
+            };
+
+            answer(&mut clients[WOLF], CtsMessage::Kill(ids[VICTIM]));
+            answer(&mut clients[DOCTOR], CtsMessage::Protect(ids[protected]));
+            answer(&mut clients[BODYGUARD], CtsMessage::Guard(ids[guarded]));
+            answer(&mut clients[WITCH], CtsMessage::WitchAction(action));
+
+            for (killed_id, killer_id) in game.play_night().unwrap() {
+                game.kill(killed_id, DeathCause::Killed(killer_id));
+            }
+
+            for (victim_id, witch_id) in std::mem::take(&mut game.pending_poison) {
+                game.kill(victim_id, DeathCause::Poisoned(witch_id));
+            }
+
+            let dead: Vec<usize> = (0..ids.len())
+                .filter(|&n| game.players[&ids[n]].dead)
+                .collect();
+
+            assert_eq!(
+                dead, expected,
+                "doctor protects {}, bodyguard guards {} (dies: {}), witch does {:?}",
+                protected, guarded, bodyguard_dies, brew
+            );
+        }
+    }
+
+    #[test]
+    fn the_living_never_hear_the_dead_talk() {
+        let rules = GameRules {
+            dead_chat_time: Duration::from_secs(60),
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], rules);
+        let ids = ids(&game);
+
+        for &id in &ids[..2] {
+            game.players.get_mut(&id).unwrap().dead = true;
+        }
+
+        // The first dead player speaks, so everyone gets another turn, and then the discussion
+        // ends because nobody has anything else to say.
+        answer(
+            &mut clients[0],
+            CtsMessage::DeadChat("It was the baker".to_string()),
+        );
+        answer(&mut clients[1], CtsMessage::DeadChat(String::new()));
+        answer(&mut clients[0], CtsMessage::DeadChat(String::new()));
+        answer(&mut clients[1], CtsMessage::DeadChat(String::new()));
+
+        game.discuss(Channel::Graveyard);
+
+        let relay = StcMessage::DeadChatRelay(ids[0], "It was the baker".to_string());
+        assert!(received(&mut clients[1]).contains(&relay));
+        assert!(!received(&mut clients[2])
+            .iter()
+            .any(StcMessage::is_dead_only));
+
+        // Nothing meant for the dead reaches the living, however it's sent.
+        game.send_all(&relay);
+        assert!(!received(&mut clients[2]).contains(&relay));
+    }
+
+    /// Plays a whole game between random bots with the given seed, and returns its record.
+    fn recorded_game(config: &Config) -> GameRecord {
+        let mut game = Game::new(config.rules.clone(), config.roles.clone(), config.seed);
+
+        let bots: Vec<_> = (0..5)
+            .map(|n| {
+                let (mut ours, theirs) = channel_pair();
+                let strategy = crate::bot::RandomStrategy::new();
+                let bot = std::thread::spawn(move || {
+                    crate::bot::play(theirs, format!("Bot {}", n), strategy)
+                });
+
+                let intro = comm::receive(&mut ours).unwrap();
+                Player::join(&mut game, ours, intro).unwrap();
+
+                bot
+            })
+            .collect();
+
+        game.reseed();
+        game.play();
+        let record = game.record();
+
+        // The bots only stop once they've lost their connections.
+        drop(game);
+
+        for bot in bots {
+            let _ = bot.join();
+        }
+
+        record
+    }
+
+    /// Returns the settings for a game between bots, without any pauses.
+    fn replay_config() -> Config {
+        Config {
+            start_countdown: Duration::ZERO,
+            seed: Some(7),
+            rules: GameRules {
+                reveal_delay: Duration::ZERO,
+                ..GameRules::default()
+            },
+            roles: Preset::SeerDoctor.roles(),
+            stats_path: None,
+            log_dir: None,
+            post_game_timeout: Duration::ZERO,
+            heartbeat: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn recorded_games_replay_the_same_way() {
+        let config = replay_config();
+        let record = recorded_game(&config);
+
+        // Records are saved as JSON, so the replay should work from what's read back.
+        let json = serde_json::to_string(&record).unwrap();
+        let record: GameRecord = serde_json::from_str(&json).unwrap();
+
+        assert!(record.players.iter().all(|p| !p.transcript.is_empty()));
+        assert_eq!(replay(&record, &config), Ok(()));
+    }
+
+    #[test]
+    fn replays_catch_games_that_play_out_differently() {
+        let config = replay_config();
+        let mut record = recorded_game(&config);
+
+        // Pretend that the first player was told something different at the start.
+        let first = record.players[0]
+            .transcript
+            .iter_mut()
+            .find(|msg| matches!(msg, Message::Sent(_)))
+            .unwrap();
+
+        *first = Message::Sent(StcMessage::PeacefulNight);
+
+        assert!(replay(&record, &config).is_err());
+    }
+
+    #[test]
+    fn apprentice_takes_over_when_the_seer_dies() {
+        let (mut game, mut clients) = game_with_rules(
+            &[
+                Role::Wolf,
+                Role::Seer,
+                Role::ApprenticeSeer,
+                Role::Villager,
+                Role::Villager,
+            ],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.kill(ids[1], DeathCause::Killed(ids[0]));
+
+        assert_eq!(game.players[&ids[2]].role(), Role::Seer);
+        assert!(received(&mut clients[2]).contains(&StcMessage::YouAreNowSeer));
+
+        // The next night, the apprentice is the one who gets to inspect someone.
+        answer(&mut clients[0], CtsMessage::Kill(ids[3]));
+        answer(&mut clients[2], CtsMessage::Inspect(ids[0]));
+
+        assert_eq!(game.play_night(), Ok(vec![(ids[3], ids[0])]));
+        assert!(received(&mut clients[2]).contains(&StcMessage::InspectResult(ids[0], Role::Wolf)));
+    }
+
+    #[test]
+    fn doctors_learn_whether_they_saved_anyone() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Wolf, Role::Doctor, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Kill(ids[2]));
+        answer(&mut clients[1], CtsMessage::Protect(ids[2]));
+
+        assert_eq!(game.play_night(), Ok(vec![]));
+        assert!(received(&mut clients[1]).contains(&StcMessage::ProtectionResult(true)));
+
+        answer(&mut clients[0], CtsMessage::Kill(ids[2]));
+        answer(&mut clients[1], CtsMessage::Protect(ids[3]));
+
+        assert_eq!(game.play_night(), Ok(vec![(ids[2], ids[0])]));
+        assert!(received(&mut clients[1]).contains(&StcMessage::ProtectionResult(false)));
+
+        // Nobody else hears how the doctor got on.
+        for i in [0, 2, 3] {
+            let messages = received(&mut clients[i]);
+            assert!(!messages
+                .iter()
+                .any(|m| matches!(m, StcMessage::ProtectionResult(_))));
+        }
+    }
+
+    #[test]
+    fn bodyguards_learn_whether_they_blocked_an_attack() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Wolf, Role::Bodyguard, Role::Villager, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Kill(ids[2]));
+        answer(&mut clients[1], CtsMessage::Guard(ids[2]));
+
+        assert_eq!(game.play_night(), Ok(vec![]));
+        assert!(received(&mut clients[1]).contains(&StcMessage::ProtectionResult(true)));
+
+        answer(&mut clients[0], CtsMessage::Kill(ids[2]));
+        answer(&mut clients[1], CtsMessage::Guard(ids[3]));
+
+        assert_eq!(game.play_night(), Ok(vec![(ids[2], ids[0])]));
+        assert!(received(&mut clients[1]).contains(&StcMessage::ProtectionResult(false)));
+    }
+
+    #[test]
+    fn witches_learn_whether_their_poison_killed() {
+        for already_dead in [false, true] {
+            let (mut game, mut clients) = game_with_rules(
+                &[
+                    Role::Wolf,
+                    Role::Witch,
+                    Role::Villager,
+                    Role::Villager,
+                    Role::Villager,
+                ],
+                GameRules::default(),
+            );
+            let ids = ids(&game);
+
+            // The victim could have been shot or taken down before the poison took effect.
+            game.players.get_mut(&ids[4]).unwrap().dead = already_dead;
+            game.pending_poison.push((ids[4], ids[1]));
+
+            vote_out(&mut clients[..4], &ids[..4], 0);
+            assert_eq!(game.play_day(vec![]), Some(Winner::Village));
+
+            let result = StcMessage::PoisonResult(!already_dead);
+            assert!(received(&mut clients[1]).contains(&result));
         }
     }
+
+    #[test]
+    fn spectators_are_turned_away_once_there_are_enough() {
+        let rules = GameRules {
+            max_spectators: 1,
+            ..GameRules::default()
+        };
+
+        let mut game = Game::new(rules, Preset::Classic.roles(), Some(0));
+        let mut clients: Vec<ChannelTransport> = (0..2)
+            .map(|_| {
+                let (ours, theirs) = channel_pair();
+                Player::join(&mut game, ours, CtsMessage::Spectate).unwrap();
+
+                theirs
+            })
+            .collect();
+
+        assert_eq!(game.spectators.len(), 1);
+        assert!(matches!(
+            received(&mut clients[0])[0],
+            StcMessage::Spectating(_)
+        ));
+        assert_eq!(
+            received(&mut clients[1]),
+            vec![StcMessage::SpectatorLimitReached(1)]
+        );
+
+        // The limit is only on spectators, so players can still join.
+        let (ours, mut theirs) = channel_pair();
+        let intro = CtsMessage::Connect("Player".to_string(), None);
+        Player::join(&mut game, ours, intro).unwrap();
+
+        assert_eq!(game.players.len(), 1);
+        assert!(matches!(
+            received(&mut theirs)[0],
+            StcMessage::IdAssigned(..)
+        ));
+    }
+
+    #[test]
+    fn seer_sees_the_godfather_as_a_villager() {
+        let (mut game, mut clients) = game_with_rules(
+            &[Role::Wolf, Role::Wolf, Role::Seer, Role::Villager],
+            GameRules::default(),
+        );
+        let ids = ids(&game);
+
+        game.players.get_mut(&ids[0]).unwrap().seer_immune = true;
+
+        answer(&mut clients[2], CtsMessage::Inspect(ids[0]));
+        game.inspect(ids[2]);
+
+        answer(&mut clients[2], CtsMessage::Inspect(ids[1]));
+        game.inspect(ids[2]);
+
+        let results = received(&mut clients[2]);
+        assert!(results.contains(&StcMessage::InspectResult(ids[0], Role::Villager)));
+        assert!(results.contains(&StcMessage::InspectResult(ids[1], Role::Wolf)));
+    }
+
+    #[test]
+    fn godfather_is_one_of_the_wolves() {
+        let rules = GameRules {
+            godfather: true,
+            ..GameRules::default()
+        };
+
+        let (mut game, _clients) = game_with_rules(&[Role::Villager; 8], rules);
+        game.assign_roles();
+
+        let godfathers: Vec<&Player<ChannelTransport>> =
+            game.players.values().filter(|p| p.seer_immune).collect();
+
+        assert_eq!(godfathers.len(), 1);
+        assert!(godfathers[0].role().is_wolf());
+    }
+
+    #[test]
+    fn lone_wolf_wins_alone_by_outlasting_the_pack() {
+        let roles = [
+            Role::Wolf,
+            Role::Wolf,
+            Role::Villager,
+            Role::Villager,
+            Role::Villager,
+        ];
+
+        // While the rest of the pack is alive, a win for the wolves is a win for the pack.
+        let (mut game, _clients) = game_with_rules(&roles, GameRules::default());
+        let ids = ids(&game);
+
+        game.players.get_mut(&ids[0]).unwrap().lone_wolf = true;
+        game.kill(ids[2], DeathCause::VotedOut);
+        game.kill(ids[3], DeathCause::VotedOut);
+
+        assert_eq!(game.winner(), Some(Winner::Wolf));
+
+        // Once the lone wolf is the only wolf left, the win is theirs.
+        let (mut game, _clients) = game_with_rules(&roles, GameRules::default());
+
+        game.players.get_mut(&ids[0]).unwrap().lone_wolf = true;
+        game.kill(ids[1], DeathCause::VotedOut);
+        game.kill(ids[2], DeathCause::VotedOut);
+        assert_eq!(game.winner(), None);
+
+        game.kill(ids[3], DeathCause::Killed(ids[0]));
+        assert_eq!(game.winner(), Some(Winner::LoneWolf));
+    }
+
+    #[test]
+    fn lone_wolf_is_only_chosen_from_a_pack() {
+        let rules = GameRules {
+            lone_wolf: true,
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 8], rules.clone());
+        game.assign_roles();
+
+        let lone_wolves: Vec<usize> = (0..8)
+            .filter(|&i| received(&mut clients[i]).contains(&StcMessage::LoneWolf))
+            .collect();
+
+        assert_eq!(lone_wolves.len(), 1);
+        assert!(game.players[&ids(&game)[lone_wolves[0]]].role().is_wolf());
+
+        // With only one wolf, there's no pack for them to turn on.
+        let (mut game, _clients) = game_with_rules(&[Role::Villager; 4], rules);
+        game.assign_roles();
+
+        assert!(game.players.values().all(|p| !p.lone_wolf));
+    }
+
+    #[test]
+    fn seats_are_held_for_players_who_rejoin_in_time() {
+        let rules = GameRules {
+            reconnect_grace: Duration::from_secs(60),
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], rules);
+        let ids = ids(&game);
+
+        game.players[&ids[2]]
+            .disconnected
+            .store(true, Ordering::Relaxed);
+
+        assert_eq!(game.drop_disconnected(), None);
+        assert!(!game.players[&ids[2]].dead);
+        assert!(received(&mut clients[0]).contains(&StcMessage::PlayerDisconnected(ids[2])));
+
+        let (ours, mut theirs) = channel_pair();
+        let token = game.players[&ids[2]].token;
+        game.reconnect(ids[2], token, ours).unwrap();
+
+        assert!(received(&mut clients[0]).contains(&StcMessage::PlayerReconnected(ids[2])));
+        assert!(matches!(
+            received(&mut theirs)[0],
+            StcMessage::Reconnected(..)
+        ));
+
+        // Once they're back, they stay in the game however long it's been.
+        assert_eq!(game.drop_disconnected(), None);
+        assert!(!game.players[&ids[2]].dead);
+        assert!(game.players[&ids[2]].dropped_at.is_none());
+    }
+
+    #[test]
+    fn held_seats_are_given_up_once_the_grace_runs_out() {
+        let rules = GameRules {
+            reconnect_grace: Duration::from_millis(1),
+            ..GameRules::default()
+        };
+
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 3], rules);
+        let ids = ids(&game);
+
+        game.players[&ids[2]]
+            .disconnected
+            .store(true, Ordering::Relaxed);
+
+        game.drop_disconnected();
+        assert!(!game.players[&ids[2]].dead);
+
+        std::thread::sleep(Duration::from_millis(5));
+        game.drop_disconnected();
+
+        assert!(game.players[&ids[2]].dead);
+        assert!(received(&mut clients[0]).contains(&StcMessage::PlayerLeft(ids[2])));
+    }
+
+    #[test]
+    fn spectators_sharing_an_address_with_a_living_player_see_no_secrets() {
+        let rules = GameRules {
+            spectators_see_secrets: true,
+            ..GameRules::default()
+        };
+
+        let (mut game, _clients) = game_with_rules(&[Role::Wolf, Role::Villager], rules);
+        let ids = ids(&game);
+        let home = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        game.players.get_mut(&ids[0]).unwrap().address = Some(home);
+
+        // One spectator is on the same network as the wolf, and the other is somewhere else.
+        let mut watchers: Vec<ChannelTransport> = [home, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))]
+            .into_iter()
+            .map(|address| {
+                let (ours, theirs) = channel_pair();
+                let id = game.take_next_id();
+                let mut spectator =
+                    Player::new(id, "Watcher".to_string(), Colour::White, ours, None);
+                spectator.address = Some(address);
+                game.add_spectator(spectator);
+
+                theirs
+            })
+            .collect();
+
+        let roles = StcMessage::Secret(Secret::Roles(game.role_list()));
+        assert!(!received(&mut watchers[0]).contains(&roles));
+        assert!(received(&mut watchers[1]).contains(&roles));
+
+        // Once the wolf is dead, there's nothing left for them to cheat at.
+        game.players.get_mut(&ids[0]).unwrap().dead = true;
+        game.reveal_to_spectators(Secret::Roles(game.role_list()));
+        assert!(received(&mut watchers[0]).contains(&roles));
+    }
+
+    #[test]
+    fn renames_sent_during_the_game_are_rejected() {
+        let (mut game, mut clients) = game_with_rules(&[Role::Villager; 2], GameRules::default());
+        let ids = ids(&game);
+
+        answer(&mut clients[0], CtsMessage::Rename("Alice".to_string()));
+        answer(&mut clients[0], CtsMessage::Vote(ids[1]));
+        answer(&mut clients[1], CtsMessage::Vote(ids[0]));
+
+        let counts = game.collect_votes(&ids, &ids, Ballot::Lynch);
+        assert_eq!(counts.get(&ids[1]), Some(&1));
+
+        let rejected = StcMessage::RenameRejected(NameRejection::GameStarted);
+        assert!(received(&mut clients[0]).contains(&rejected));
+
+        // Nothing is left over for the lobby after the game.
+        game.handle_unprompted();
+        assert_eq!(game.players[&ids[0]].name, "Player 0");
+    }
 }
@@ -0,0 +1,143 @@
+use std::{fmt, io::Write, path::Path, time::Duration};
+
+use crate::comm::{Role, Winner};
+
+/// A summary of how a game went, for hosts who want to keep track of their games.
+pub struct GameStats {
+    /// The number of nights that the game lasted.
+    pub nights: usize,
+
+    /// The side that won the game, if it was finished.
+    pub winner: Option<Winner>,
+
+    /// How each player fared.
+    pub players: Vec<PlayerStats>,
+
+    /// The total number of votes cast across every day.
+    pub votes_cast: usize,
+
+    /// The number of players that were voted out.
+    pub lynches: usize,
+
+    /// The number of players voted out that were actually wolves.
+    pub wolves_lynched: usize,
+
+    /// The total time spent on nights.
+    pub night_time: Duration,
+
+    /// The total time spent on days.
+    pub day_time: Duration,
+}
+
+/// A summary of how a single player fared in a game.
+pub struct PlayerStats {
+    /// The player's name.
+    pub name: String,
+
+    /// The role the player had, if the game got far enough for them to be given one.
+    pub role: Option<Role>,
+
+    /// Whether the player was still alive at the end of the game.
+    pub survived: bool,
+
+    /// How long the player took to make decisions when prompted.
+    pub decisions: Timing,
+}
+
+/// A running summary of how long a kind of round trip has taken.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Timing {
+    /// The number of round trips timed.
+    pub count: u32,
+
+    /// The total time taken by all of the round trips.
+    pub total: Duration,
+
+    /// The longest that any single round trip took.
+    pub max: Duration,
+}
+
+impl Timing {
+    /// Adds a round trip that took the given amount of time.
+    pub fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    /// Returns the average time taken by a round trip, or `None` if nothing has been timed.
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count != 0).then(|| self.total / self.count)
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mean() {
+            Some(mean) => write!(
+                f,
+                "mean {:.0?}, max {:.0?} over {}",
+                mean, self.max, self.count
+            ),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+impl GameStats {
+    /// Appends the statistics to the file at the given path, creating the file if it doesn't exist
+    /// yet.
+    pub fn append_to(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        writeln!(file, "{}", self)
+    }
+}
+
+impl fmt::Display for GameStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Game statistics")?;
+        writeln!(f, "  Nights: {}", self.nights)?;
+
+        match self.winner {
+            Some(winner) => writeln!(f, "  Winner: {:?}", winner)?,
+            None => writeln!(f, "  Winner: none")?,
+        }
+
+        writeln!(f, "  Votes cast: {}", self.votes_cast)?;
+
+        // How often the village's lynches hit a wolf. There's nothing to work out if nobody was
+        // voted out.
+        if self.lynches == 0 {
+            writeln!(f, "  Lynch accuracy: no lynches")?;
+        } else {
+            writeln!(
+                f,
+                "  Lynch accuracy: {}/{} lynched players were wolves",
+                self.wolves_lynched, self.lynches
+            )?;
+        }
+
+        writeln!(f, "  Players:")?;
+
+        for player in &self.players {
+            let role = match player.role {
+                Some(role) => format!("{:?}", role),
+                None => "no role".to_string(),
+            };
+
+            let fate = if player.survived { "survived" } else { "died" };
+
+            writeln!(f, "    {} ({}): {}", player.name, role, fate)?;
+            writeln!(f, "      Decisions: {}", player.decisions)?;
+        }
+
+        writeln!(f, "  Time spent on nights: {:.0?}", self.night_time)?;
+        writeln!(f, "  Time spent on days: {:.0?}", self.day_time)?;
+
+        Ok(())
+    }
+}
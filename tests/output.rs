@@ -0,0 +1,32 @@
+//! The output of the command-line program, run as a separate process.
+
+use std::{net::TcpListener, process::Command};
+
+#[test]
+fn piped_output_has_no_colour_codes() {
+    // Find a port that nothing is listening on, so that joining fails straight away with a
+    // message that would normally be coloured.
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_wolf"))
+        .args([
+            "join",
+            "127.0.0.1",
+            "--port",
+            &port.to_string(),
+            "--name",
+            "Tester",
+        ])
+        .env_remove("NO_COLOR")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+
+    assert!(stdout.contains("Couldn't connect"), "{}", stdout);
+    assert!(!stdout.contains('\x1b'), "{:?}", stdout);
+}
@@ -0,0 +1,154 @@
+//! Whole games played in-process between bots, over in-memory connections.
+
+use std::{thread::JoinHandle, time::Duration};
+
+use wolf::{
+    bot::{self, Pick, RandomStrategy, ScriptedStrategy, Strategy},
+    comm::{self, ChannelTransport, CommError, PlayerId, Role, Winner},
+    server::{self, Config, Preset, RoleCounts},
+};
+
+/// A bot playing on a thread of its own.
+type Bot = JoinHandle<Result<(), CommError>>;
+
+/// Returns the settings for a quick game of the given preset with the given seed, with none of
+/// the pauses that are there for the benefit of human players.
+fn config(preset: Preset, seed: u64) -> Config {
+    let mut rules = preset.rules();
+    rules.reveal_delay = Duration::ZERO;
+
+    Config {
+        start_countdown: Duration::ZERO,
+        seed: Some(seed),
+        rules,
+        roles: preset.roles(),
+        stats_path: None,
+        log_dir: None,
+        post_game_timeout: Duration::ZERO,
+        heartbeat: Duration::ZERO,
+    }
+}
+
+/// Returns the settings for a game that opens with a day, handing out exactly the given roles to
+/// the given number of players. Every other player is a villager.
+fn scripted_config(seed: u64, players: usize, roles: Vec<(Role, usize)>) -> Config {
+    let mut config = config(Preset::Classic, seed);
+    config.rules.skip_first_night = true;
+    config.roles.counts = vec![RoleCounts {
+        min_players: players,
+        roles,
+    }];
+
+    config
+}
+
+/// Returns the IDs that the given number of players get, in the order that they join.
+fn ids(count: usize) -> Vec<PlayerId> {
+    std::iter::successors(Some(PlayerId::new()), |id| Some(id.next()))
+        .take(count)
+        .collect()
+}
+
+/// Connects a bot to an in-memory connection for each of the given strategies, and returns our
+/// ends of the connections along with the bots.
+fn bots<S: Strategy + 'static>(strategies: Vec<S>) -> (Vec<ChannelTransport>, Vec<Bot>) {
+    strategies
+        .into_iter()
+        .enumerate()
+        .map(|(n, strategy)| {
+            let (ours, theirs) = comm::channel_pair();
+            let bot = std::thread::spawn(move || bot::play(theirs, format!("Bot {}", n), strategy));
+
+            (ours, bot)
+        })
+        .unzip()
+}
+
+/// Connects the given number of random bots to in-memory connections.
+fn random_bots(count: usize) -> (Vec<ChannelTransport>, Vec<Bot>) {
+    bots((0..count).map(|_| RandomStrategy::new()).collect())
+}
+
+/// Plays a game in which every bot votes for the given player whenever it can, and returns the
+/// winner.
+fn everyone_votes_for(target: PlayerId, config: &Config, players: usize) -> Option<Winner> {
+    let scripts = (0..players)
+        .map(|_| ScriptedStrategy::new().then(Pick::Vote, [target; 4]))
+        .collect();
+
+    let (connections, bots) = bots(scripts);
+    let winner = server::play_game(connections, config);
+
+    // Every bot should have been told that the session was over, rather than losing its
+    // connection.
+    for bot in bots {
+        bot.join().unwrap().unwrap();
+    }
+
+    winner
+}
+
+#[test]
+fn game_between_bots_finishes() {
+    let (connections, bots) = random_bots(6);
+
+    let winner = server::play_game(connections, &config(Preset::Classic, 1));
+    assert!(winner.is_some());
+
+    for bot in bots {
+        bot.join().unwrap().unwrap();
+    }
+}
+
+#[test]
+fn too_few_players_for_the_roles() {
+    let mut config = config(Preset::Classic, 1);
+    config.roles.counts = vec![RoleCounts {
+        min_players: 5,
+        roles: vec![(Role::Wolf, 1), (Role::Seer, 1)],
+    }];
+
+    let (connections, _bots) = random_bots(3);
+
+    assert_eq!(server::play_game(connections, &config), None);
+}
+
+// With seed 1, the three-player games below make player 1 the wolf, and the four-player game
+// makes player 2 the jester. The bots' votes decide the rest.
+
+#[test]
+fn voting_out_the_wolf_wins_for_the_village() {
+    let config = scripted_config(1, 3, vec![(Role::Wolf, 1)]);
+    let wolf = ids(3)[1];
+
+    assert_eq!(everyone_votes_for(wolf, &config, 3), Some(Winner::Village));
+}
+
+#[test]
+fn voting_out_a_villager_hands_the_wolf_parity() {
+    let config = scripted_config(1, 3, vec![(Role::Wolf, 1)]);
+    let villager = ids(3)[0];
+
+    assert_eq!(everyone_votes_for(villager, &config, 3), Some(Winner::Wolf));
+}
+
+#[test]
+fn voting_out_the_jester_wins_for_the_jester() {
+    let config = scripted_config(1, 4, vec![(Role::Wolf, 1), (Role::Jester, 1)]);
+    let jester = ids(4)[2];
+
+    assert_eq!(everyone_votes_for(jester, &config, 4), Some(Winner::Jester));
+}
+
+#[test]
+fn same_seed_and_script_give_the_same_game() {
+    let config = scripted_config(3, 5, vec![(Role::Wolf, 1), (Role::Seer, 1)]);
+    let target = ids(5)[2];
+
+    let first = everyone_votes_for(target, &config, 5);
+    assert!(first.is_some());
+
+    for _ in 0..3 {
+        assert_eq!(everyone_votes_for(target, &config, 5), first);
+    }
+}